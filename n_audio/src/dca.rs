@@ -1,12 +1,22 @@
+use crate::opus::vorbis_channels;
 use audiopus::SampleRate;
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
 use serde::{Deserialize, Serialize};
-use std::io::{Seek, SeekFrom};
+use std::fs;
+use std::io::{self, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
 use symphonia::core::{
+    audio::Channels,
     codecs::{CodecParameters, CODEC_TYPE_OPUS},
     errors::{self as symph_err, Error as SymphError, Result as SymphResult, SeekErrorKind},
-    formats::prelude::*,
+    formats::{prelude::*, util},
     io::{MediaSource, MediaSourceStream, ReadBytes, SeekBuffered},
-    meta::{Metadata as SymphMetadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value},
+    meta::{
+        Metadata as SymphMetadata, MetadataBuilder, MetadataLog, StandardTagKey, StandardVisualKey,
+        Tag, Value, Visual,
+    },
     probe::{Descriptor, Instantiate, QueryDescriptor},
     sample::SampleFormat,
     units::TimeStamp,
@@ -14,6 +24,13 @@ use symphonia::core::{
 
 // Original code from the Songbird project
 
+/// Pre-skip used by libopus with the default settings, applied when the file doesn't tell it
+const PRE_SKIP: u32 = 312;
+/// Samples decoded before the seek position, so that the decoder converges (80 ms as suggested by RFC 7845)
+const SEEK_PREROLL: u64 = 3840;
+/// Samples between two points of a `DcaIndex` (one second)
+const INDEX_INTERVAL: u64 = 48000;
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct DcaMetadata {
     pub dca: DcaInfo,
@@ -47,6 +64,19 @@ pub struct Opus {
     pub channels: u8,
 }
 
+/// Gapless info, stored under the `gapless` key of the `extra` block
+///
+/// It's written by the n_audio DCA writer, files made by other tools only get the standard pre-skip
+#[derive(Clone, Copy, Debug, Deserialize, Serialize)]
+pub struct Gapless {
+    /// Samples added by the encoder at the start
+    pub pre_skip: u32,
+    /// Samples added at the end to fill the last frame
+    pub padding: u32,
+    /// Samples per channel of the original track
+    pub frames: u64,
+}
+
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Info {
     pub title: Option<String>,
@@ -82,6 +112,74 @@ impl QueryDescriptor for DcaReader {
     }
 }
 
+/// Whether a DCA file gets indexed when it's opened, see `DcaReader::try_new_indexed`
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DcaIndexMode {
+    /// The frames are found while they are read, so the first seek forward reads all of them up to the position
+    #[default]
+    Off,
+    /// The cached index is used if it's still valid, otherwise it's built without saving it
+    ReadOnly,
+    /// Like `DcaIndexMode::ReadOnly`, but a newly built index gets cached next to the file
+    Cached,
+}
+
+/// Where the frames of a DCA file start, built with a single pass over the file
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct DcaIndex {
+    /// Timestamp (pre-skip included) and byte position of a frame, about every second
+    pub points: Vec<(TimeStamp, u64)>,
+    /// Samples per channel of the whole file, pre-skip and padding included
+    pub duration: TimeStamp,
+}
+
+/// A `DcaIndex` saved next to its file, valid as long as the file keeps the same size and modification time
+#[derive(Deserialize, Serialize)]
+struct IndexCache {
+    size: u64,
+    modified: Duration,
+    index: DcaIndex,
+}
+
+impl DcaIndex {
+    /// Reads the index cached for the file at `path`, if there's one and the file didn't change since
+    pub fn load(path: &Path) -> Option<Self> {
+        let key = cache_key(path).ok()?;
+        let cache = fs::read(index_path(path)).ok()?;
+        let cache: IndexCache = serde_json::from_slice(&cache).ok()?;
+
+        ((cache.size, cache.modified) == key).then_some(cache.index)
+    }
+
+    /// Caches the index of the file at `path` in `<path>.idx`
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let (size, modified) = cache_key(path)?;
+        let cache = IndexCache {
+            size,
+            modified,
+            index: self.clone(),
+        };
+
+        fs::write(index_path(path), serde_json::to_vec(&cache)?)
+    }
+}
+
+fn index_path(path: &Path) -> PathBuf {
+    let mut index = path.as_os_str().to_owned();
+    index.push(".idx");
+    index.into()
+}
+
+fn cache_key(path: &Path) -> io::Result<(u64, Duration)> {
+    let metadata = fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+
+    Ok((metadata.len(), modified))
+}
+
 struct SeekAccel {
     frame_offsets: Vec<(TimeStamp, u64)>,
     seek_index_fill_rate: u16,
@@ -100,6 +198,15 @@ impl SeekAccel {
         }
     }
 
+    /// Uses the points of a complete index, nothing is added while reading anymore
+    fn from_index(index: &DcaIndex) -> Self {
+        Self {
+            frame_offsets: index.points.clone(),
+            seek_index_fill_rate: 0,
+            next_ts: TimeStamp::MAX,
+        }
+    }
+
     fn update(&mut self, ts: TimeStamp, pos: u64) {
         if ts >= self.next_ts {
             self.next_ts += (self.seek_index_fill_rate as u64) * (48000);
@@ -119,9 +226,12 @@ pub struct DcaReader {
     track: Option<Track>,
     metas: MetadataLog,
     seek_accel: SeekAccel,
+    /// Timestamp of the next packet, before trimming the pre-skip
     curr_ts: TimeStamp,
     max_ts: Option<TimeStamp>,
     held_packet: Option<Packet>,
+    /// Samples trimmed from the start and length of the track, only if gapless playback is enabled
+    gapless: Option<(u32, Option<u64>)>,
 }
 
 impl FormatReader for DcaReader {
@@ -129,8 +239,6 @@ impl FormatReader for DcaReader {
         // Read in the magic number to verify it's a DCA file.
         let magic = source.read_quad_bytes()?;
 
-        // FIXME: make use of the new options.enable_gapless to apply the opus coder delay.
-
         let read_meta = match &magic {
             b"DCA1" => true,
             _ if &magic[..3] == b"DCA" => {
@@ -149,9 +257,12 @@ impl FormatReader for DcaReader {
             .with_max_frames_per_packet(1)
             .with_sample_rate(48000)
             .with_time_base(TimeBase::new(1, 48000))
-            .with_sample_format(SampleFormat::F32);
+            .with_sample_format(SampleFormat::F32)
+            // DCA0 files don't carry any metadata, they are always stereo
+            .with_channels(Channels::FRONT_LEFT | Channels::FRONT_RIGHT);
 
         let mut metas = MetadataLog::default();
+        let mut gapless = None;
 
         if read_meta {
             let size = source.read_u32()?;
@@ -168,6 +279,19 @@ impl FormatReader for DcaReader {
             let metadata: DcaMetadata = serde_json::from_slice::<DcaMetadata>(&mut raw_json)
                 .map_err(|_| SymphError::DecodeError("malformed DCA1 metadata block"))?;
 
+            match vorbis_channels(metadata.opus.channels as usize) {
+                Some(channels) if metadata.opus.channels <= 2 => {
+                    codec_params.with_channels(channels);
+                }
+                _ => return symph_err::unsupported_error("unsupported DCA channel count"),
+            }
+
+            gapless = metadata
+                .extra
+                .as_ref()
+                .and_then(|extra| extra.get("gapless"))
+                .and_then(|gapless| serde_json::from_value::<Gapless>(gapless.clone()).ok());
+
             let mut revision = MetadataBuilder::new();
 
             if let Some(info) = metadata.info {
@@ -206,8 +330,8 @@ impl FormatReader for DcaReader {
                         Value::String(t),
                     ));
                 }
-                if let Some(_t) = info.cover {
-                    // TODO: Add visual, figure out MIME types.
+                if let Some(cover) = info.cover.as_deref().and_then(decode_cover) {
+                    revision.add_visual(cover);
                 }
             }
 
@@ -220,6 +344,23 @@ impl FormatReader for DcaReader {
             metas.push(revision.metadata());
         }
 
+        let delay = gapless.map_or(PRE_SKIP, |gapless| gapless.pre_skip);
+        codec_params.with_delay(delay);
+        if let Some(gapless) = gapless {
+            codec_params.with_padding(gapless.padding);
+        }
+        let frames = gapless.map(|gapless| gapless.frames);
+        match frames {
+            Some(frames) if options.enable_gapless => {
+                codec_params.with_n_frames(frames);
+            }
+            Some(frames) => {
+                let padding = gapless.map_or(0, |gapless| gapless.padding);
+                codec_params.with_n_frames(delay as u64 + frames + padding as u64);
+            }
+            None => {}
+        }
+
         let bytes_read = source.pos();
 
         Ok(Self {
@@ -234,6 +375,7 @@ impl FormatReader for DcaReader {
             curr_ts: 0,
             max_ts: None,
             held_packet: None,
+            gapless: options.enable_gapless.then_some((delay, frames)),
         })
     }
 
@@ -267,36 +409,43 @@ impl FormatReader for DcaReader {
             SeekTo::TimeStamp { ts, .. } => ts,
         };
 
+        // Work on the timeline that includes the pre-skip, starting a bit earlier to let the decoder converge
+        let delay = self.gapless.map_or(0, |(delay, _)| delay as u64);
+        let raw_ts = (ts + delay).saturating_sub(SEEK_PREROLL);
+
         if let Some(max_ts) = self.max_ts {
-            if ts > max_ts {
+            if raw_ts > max_ts {
                 return symph_err::seek_error(SeekErrorKind::OutOfRange);
             }
         }
 
-        let backseek_needed = self.curr_ts > ts;
+        let backseek_needed = self.curr_ts > raw_ts;
 
         if backseek_needed && !can_backseek {
             return symph_err::seek_error(SeekErrorKind::ForwardOnly);
         }
 
-        let (accel_seek_ts, accel_seek_pos) = self.seek_accel.get_seek_pos(ts);
+        let (accel_seek_ts, accel_seek_pos) = self.seek_accel.get_seek_pos(raw_ts);
 
         if backseek_needed || accel_seek_pos > self.source.pos() {
             self.source.seek(SeekFrom::Start(accel_seek_pos))?;
             self.curr_ts = accel_seek_ts;
         }
+        self.held_packet = None;
 
-        while let Ok(pkt) = self.next_packet() {
+        while let Ok(pkt) = self.next_raw_packet() {
             let pts = pkt.ts;
             let dur = pkt.dur;
             let track_id = pkt.track_id();
 
-            if (pts..pts + dur).contains(&ts) {
+            if (pts..pts + dur).contains(&raw_ts) {
+                let pkt = self.trim(pkt);
+                let actual_ts = pkt.ts;
                 self.held_packet = Some(pkt);
                 return Ok(SeekedTo {
                     track_id,
                     required_ts: ts,
-                    actual_ts: pts,
+                    actual_ts,
                 });
             }
         }
@@ -324,6 +473,117 @@ impl FormatReader for DcaReader {
             return Ok(pkt);
         }
 
+        let pkt = self.next_raw_packet()?;
+        Ok(self.trim(pkt))
+    }
+
+    fn into_inner(self: Box<Self>) -> MediaSourceStream {
+        self.source
+    }
+}
+
+impl DcaReader {
+    /// Like `try_new`, but reads every frame header first so that seeking is instant and the duration is known
+    ///
+    /// The index of the file at `path` is reused while the file doesn't change, `mode` tells whether a new one gets
+    /// cached next to it
+    /// A file that can't be indexed (e.g. a truncated one) is read like `try_new` would do
+    pub fn try_new_indexed(
+        source: MediaSourceStream,
+        options: &FormatOptions,
+        path: &Path,
+        mode: DcaIndexMode,
+    ) -> SymphResult<Self> {
+        let mut reader = Self::try_new(source, options)?;
+        if mode == DcaIndexMode::Off {
+            return Ok(reader);
+        }
+
+        let index = match DcaIndex::load(path) {
+            Some(index) => index,
+            None => match reader.build_index() {
+                Ok(index) => {
+                    if mode == DcaIndexMode::Cached {
+                        if let Err(e) = index.save(path) {
+                            eprintln!("Can't cache the index of {}: {e}", path.display());
+                        }
+                    }
+                    index
+                }
+                Err(e) => {
+                    eprintln!("Can't index {}: {e}", path.display());
+                    return Ok(reader);
+                }
+            },
+        };
+        reader.use_index(&index);
+
+        Ok(reader)
+    }
+
+    /// Walks all the frames from the current position, going back to it when done (even if it fails)
+    pub fn build_index(&mut self) -> SymphResult<DcaIndex> {
+        if !self.source.is_seekable() {
+            return symph_err::seek_error(SeekErrorKind::Unseekable);
+        }
+
+        let start = self.source.pos();
+        let index = self.read_index();
+        self.source.seek(SeekFrom::Start(start))?;
+
+        index
+    }
+
+    fn read_index(&mut self) -> SymphResult<DcaIndex> {
+        let mut index = DcaIndex {
+            points: vec![],
+            duration: self.curr_ts,
+        };
+        let mut next_point = self.curr_ts;
+
+        loop {
+            let frame_pos = self.source.pos();
+            let p_len = match self.source.read_u16() {
+                Ok(len) => len as i16,
+                Err(_) => break,
+            };
+
+            if p_len < 0 {
+                return symph_err::decode_error("DCA frame header had a negative length.");
+            }
+
+            let buf = self.source.read_boxed_slice_exact(p_len as usize)?;
+
+            if index.duration >= next_point {
+                index.points.push((index.duration, frame_pos));
+                next_point = index.duration + INDEX_INTERVAL;
+            }
+            index.duration += sample_count(&buf)?;
+        }
+
+        Ok(index)
+    }
+
+    /// Seeks using `index` and takes the duration from it
+    pub fn use_index(&mut self, index: &DcaIndex) {
+        if index.points.is_empty() {
+            return;
+        }
+
+        self.seek_accel = SeekAccel::from_index(index);
+        self.max_ts = Some(index.duration);
+
+        if let Some(track) = self.track.as_mut() {
+            let params = &mut track.codec_params;
+            if params.n_frames.is_none() {
+                let delay = self.gapless.map_or(0, |(delay, _)| delay as u64);
+                params.with_n_frames(index.duration.saturating_sub(delay));
+            }
+        }
+    }
+
+    /// Reads the next packet, with its timestamp still including the pre-skip
+    fn next_raw_packet(&mut self) -> SymphResult<Packet> {
         let frame_pos = self.source.pos();
 
         let p_len = match self.source.read_u16() {
@@ -339,17 +599,7 @@ impl FormatReader for DcaReader {
         }
 
         let buf = self.source.read_boxed_slice_exact(p_len as usize)?;
-
-        let checked_buf = buf[..].try_into().or_else(|_| {
-            symph_err::decode_error("Packet was not a valid Opus Packet: too large for audiopus.")
-        })?;
-
-        let sample_ct =
-            audiopus::packet::nb_samples(checked_buf, SampleRate::Hz48000).or_else(|_| {
-                symph_err::decode_error(
-                    "Packet was not a valid Opus packet: couldn't read sample count.",
-                )
-            })? as u64;
+        let sample_ct = sample_count(&buf)?;
 
         let out = Packet::new_from_boxed_slice(0, self.curr_ts, sample_ct, buf);
 
@@ -360,7 +610,267 @@ impl FormatReader for DcaReader {
         Ok(out)
     }
 
-    fn into_inner(self: Box<Self>) -> MediaSourceStream {
-        self.source
+    /// Moves `pkt` to the timeline without the pre-skip, trimming the pre-skip and the padding if gapless playback
+    /// is enabled
+    fn trim(&self, mut pkt: Packet) -> Packet {
+        if let Some((delay, frames)) = self.gapless {
+            util::trim_packet(&mut pkt, delay, frames);
+        }
+        pkt
+    }
+}
+
+/// Reads how many samples per channel are in an Opus packet
+fn sample_count(buf: &[u8]) -> SymphResult<u64> {
+    let checked_buf = buf.try_into().or_else(|_| {
+        symph_err::decode_error("Packet was not a valid Opus Packet: too large for audiopus.")
+    })?;
+
+    let sample_ct =
+        audiopus::packet::nb_samples(checked_buf, SampleRate::Hz48000).or_else(|_| {
+            symph_err::decode_error(
+                "Packet was not a valid Opus packet: couldn't read sample count.",
+            )
+        })?;
+
+    Ok(sample_ct as u64)
+}
+
+/// Decodes the cover of a DCA file, stored as base64 or as a data URI (`data:image/png;base64,...`)
+fn decode_cover(cover: &str) -> Option<Visual> {
+    let (media_type, data) = match cover.strip_prefix("data:") {
+        Some(uri) => {
+            let (header, data) = uri.split_once(',')?;
+            let media_type = header.strip_suffix(";base64")?;
+            (Some(media_type.to_string()), data)
+        }
+        None => (None, cover),
+    };
+    let data = STANDARD.decode(data.trim()).ok()?;
+    let media_type = media_type
+        .filter(|media_type| !media_type.is_empty())
+        .or_else(|| image_type(&data).map(String::from))?;
+
+    Some(Visual {
+        media_type,
+        dimensions: None,
+        bits_per_pixel: None,
+        color_mode: None,
+        usage: Some(StandardVisualKey::FrontCover),
+        tags: vec![],
+        data: data.into_boxed_slice(),
+    })
+}
+
+/// Guesses the MIME type of an image from its magic number
+fn image_type(data: &[u8]) -> Option<&'static str> {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => Some("image/png"),
+        [0xFF, 0xD8, 0xFF, ..] => Some("image/jpeg"),
+        [b'G', b'I', b'F', b'8', ..] => Some("image/gif"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'B', b'M', ..] => Some("image/bmp"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dca_writer::DcaWriter;
+    use crate::CODEC_REGISTRY;
+    use audiopus::coder::Encoder;
+    use audiopus::{Application, Channels as OpusChannels};
+    use std::io::{Cursor, ErrorKind};
+    use symphonia::core::codecs::DecoderOptions;
+
+    const PACKETS: u64 = 10;
+    const FRAME_SIZE: u64 = 960;
+    /// The gapless info written by `dca`, the padding fills the last packet
+    const GAPLESS: Gapless = Gapless {
+        pre_skip: 312,
+        padding: 100,
+        frames: PACKETS * FRAME_SIZE - 312 - 100,
+    };
+
+    const PNG: &[u8] = &[0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A];
+    const JPEG: &[u8] = &[0xFF, 0xD8, 0xFF, 0xE0, 0x00, 0x10];
+    const GIF: &[u8] = b"GIF89a";
+    const WEBP: &[u8] = b"RIFF\x10\x00\x00\x00WEBPVP8 ";
+
+    /// Builds a stereo DCA1 file of `PACKETS` 20 ms packets of silence
+    fn dca(gapless: Option<Gapless>) -> Vec<u8> {
+        let metadata = DcaMetadata {
+            dca: DcaInfo {
+                version: 1,
+                tool: Tool {
+                    name: String::from("test"),
+                    version: String::from("1.0.0"),
+                    url: None,
+                    author: None,
+                },
+            },
+            opus: Opus {
+                mode: String::from("music"),
+                sample_rate: 48000,
+                frame_size: FRAME_SIZE,
+                abr: None,
+                vbr: true,
+                channels: 2,
+            },
+            info: None,
+            origin: None,
+            extra: gapless.map(|gapless| serde_json::json!({ "gapless": gapless })),
+        };
+
+        let encoder = Encoder::new(
+            SampleRate::Hz48000,
+            OpusChannels::Stereo,
+            Application::Audio,
+        )
+        .unwrap();
+        let frame = [0.0; FRAME_SIZE as usize * 2];
+        let mut packet = [0; 4000];
+        let mut writer = DcaWriter::new(vec![], &metadata).unwrap();
+        for _ in 0..PACKETS {
+            let len = encoder.encode_float(&frame, &mut packet).unwrap();
+            writer.write_packet(&packet[..len]).unwrap();
+        }
+        writer.finish().unwrap()
+    }
+
+    fn open(bytes: Vec<u8>, enable_gapless: bool) -> DcaReader {
+        let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        let options = FormatOptions {
+            enable_gapless,
+            ..Default::default()
+        };
+        DcaReader::try_new(source, &options).unwrap()
+    }
+
+    /// Decodes the rest of the file, returning how many frames came out
+    fn decode(reader: &mut DcaReader) -> u64 {
+        let mut decoder = CODEC_REGISTRY
+            .make(
+                &reader.default_track().unwrap().codec_params,
+                &DecoderOptions::default(),
+            )
+            .unwrap();
+        let mut frames = 0;
+        loop {
+            let packet = match reader.next_packet() {
+                Ok(packet) => packet,
+                Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => panic!("{err}"),
+            };
+            frames += decoder.decode(&packet).unwrap().frames() as u64;
+        }
+        frames
+    }
+
+    #[test]
+    fn trims_with_the_gapless_info() {
+        let mut reader = open(dca(Some(GAPLESS)), true);
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.delay, Some(GAPLESS.pre_skip));
+        assert_eq!(params.padding, Some(GAPLESS.padding));
+        assert_eq!(params.n_frames, Some(GAPLESS.frames));
+
+        let first = reader.next_packet().unwrap();
+        assert_eq!(first.ts, 0);
+        assert_eq!(first.trim_start, GAPLESS.pre_skip);
+        assert_eq!(first.dur, FRAME_SIZE - GAPLESS.pre_skip as u64);
+
+        let mut reader = open(dca(Some(GAPLESS)), true);
+        assert_eq!(decode(&mut reader), GAPLESS.frames);
+    }
+
+    #[test]
+    fn keeps_everything_without_gapless_playback() {
+        let mut reader = open(dca(Some(GAPLESS)), false);
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.n_frames, Some(PACKETS * FRAME_SIZE));
+
+        let first = reader.next_packet().unwrap();
+        assert_eq!(first.ts, 0);
+        assert_eq!(first.trim_start, 0);
+        assert_eq!(first.dur, FRAME_SIZE);
+
+        let mut reader = open(dca(Some(GAPLESS)), false);
+        assert_eq!(decode(&mut reader), PACKETS * FRAME_SIZE);
+    }
+
+    #[test]
+    fn trims_the_default_pre_skip() {
+        // Files made by other tools only tell how long they are once they are read
+        let mut reader = open(dca(None), true);
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.delay, Some(PRE_SKIP));
+        assert_eq!(params.n_frames, None);
+
+        assert_eq!(decode(&mut reader), PACKETS * FRAME_SIZE - PRE_SKIP as u64);
+
+        let mut reader = open(dca(None), false);
+        assert_eq!(decode(&mut reader), PACKETS * FRAME_SIZE);
+    }
+
+    #[test]
+    fn seeks_on_the_trimmed_timeline() {
+        let mut reader = open(dca(Some(GAPLESS)), true);
+        for ts in [5000, 100, 0, GAPLESS.frames - 1] {
+            let seeked = reader
+                .seek(SeekMode::Accurate, SeekTo::TimeStamp { ts, track_id: 0 })
+                .unwrap();
+            assert_eq!(seeked.required_ts, ts);
+            assert!(seeked.actual_ts <= ts);
+            // Enough is decoded before the position to let the decoder converge
+            assert!(ts - seeked.actual_ts < SEEK_PREROLL + FRAME_SIZE);
+
+            let packet = reader.next_packet().unwrap();
+            assert_eq!(packet.ts, seeked.actual_ts);
+        }
+    }
+
+    #[test]
+    fn decodes_data_uris() {
+        let cover = format!("data:image/png;base64,{}", STANDARD.encode(PNG));
+        let visual = decode_cover(&cover).unwrap();
+        assert_eq!(visual.media_type, "image/png");
+        assert_eq!(&*visual.data, PNG);
+        assert_eq!(visual.usage, Some(StandardVisualKey::FrontCover));
+
+        // The declared media type wins over the magic number
+        let cover = format!("data:image/x-test;base64,{}", STANDARD.encode(PNG));
+        assert_eq!(decode_cover(&cover).unwrap().media_type, "image/x-test");
+
+        // Without one it gets sniffed
+        let cover = format!("data:;base64,{}", STANDARD.encode(GIF));
+        assert_eq!(decode_cover(&cover).unwrap().media_type, "image/gif");
+
+        // Only base64 is supported
+        assert!(decode_cover("data:image/png,abc").is_none());
+    }
+
+    #[test]
+    fn sniffs_bare_base64() {
+        for (data, media_type) in [
+            (JPEG, "image/jpeg"),
+            (WEBP, "image/webp"),
+            (GIF, "image/gif"),
+        ] {
+            let visual = decode_cover(&STANDARD.encode(data)).unwrap();
+            assert_eq!(visual.media_type, media_type);
+            assert_eq!(&*visual.data, data);
+        }
+
+        // Unknown images have no media type, so they are dropped
+        assert!(decode_cover(&STANDARD.encode(b"not an image")).is_none());
+        assert_eq!(image_type(b"BM\x00\x00"), Some("image/bmp"));
+    }
+
+    #[test]
+    fn rejects_invalid_base64() {
+        assert!(decode_cover("not base64!").is_none());
+        assert!(decode_cover("data:image/png;base64,####").is_none());
     }
 }
@@ -0,0 +1,520 @@
+//! Encoding of any supported track to DCA1 (Opus packets with a JSON metadata block)
+
+pub use crate::dca::{DcaInfo, DcaMetadata, Gapless, Info, Opus, Origin, Tool};
+use crate::engine::map_channels;
+use crate::music_track::MusicTrack;
+use crate::resampler::{Resampler, ResamplerQuality};
+use crate::{NError, CODEC_REGISTRY};
+use audiopus::coder::Encoder;
+use audiopus::{Application, Bitrate, Channels, SampleRate};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use multitag::Tag;
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use symphonia::core::audio::{AudioBuffer, Signal};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphError;
+use symphonia::core::formats::FormatReader;
+use symphonia::core::meta::StandardTagKey;
+
+/// DCA files are always encoded at 48 kHz
+const SAMPLE_RATE: u32 = 48000;
+/// Frame sizes allowed by Opus, in samples per channel at 48 kHz
+const FRAME_SIZES: [u32; 6] = [120, 240, 480, 960, 1920, 2880];
+/// Largest Opus packet that can be produced, as suggested by libopus
+const MAX_PACKET: usize = 4000;
+
+/// What the encoder gets tuned for, it's written as the `mode` of the metadata
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum DcaApplication {
+    Voip,
+    #[default]
+    Audio,
+    LowDelay,
+}
+
+impl DcaApplication {
+    fn mode(&self) -> &'static str {
+        match self {
+            DcaApplication::Voip => "voip",
+            DcaApplication::Audio => "music",
+            DcaApplication::LowDelay => "lowdelay",
+        }
+    }
+}
+
+impl From<DcaApplication> for Application {
+    fn from(value: DcaApplication) -> Self {
+        match value {
+            DcaApplication::Voip => Application::Voip,
+            DcaApplication::Audio => Application::Audio,
+            DcaApplication::LowDelay => Application::LowDelay,
+        }
+    }
+}
+
+/// How the tracks get encoded
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct DcaOptions {
+    /// Bitrate in bits per second, `None` lets the encoder choose it
+    pub bitrate: Option<u32>,
+    pub vbr: bool,
+    /// Samples per channel of every frame, it must be one of 120, 240, 480, 960, 1920 or 2880
+    pub frame_size: u32,
+    /// Number of channels of the output (1 or 2), `None` to keep the ones of the track (downmixing it to stereo)
+    pub channels: Option<u8>,
+    pub application: DcaApplication,
+    pub resampler_quality: ResamplerQuality,
+}
+
+impl Default for DcaOptions {
+    fn default() -> Self {
+        Self {
+            bitrate: Some(128_000),
+            vbr: true,
+            frame_size: 960,
+            channels: None,
+            application: DcaApplication::default(),
+            resampler_quality: ResamplerQuality::High,
+        }
+    }
+}
+
+/// Writes a DCA1 file, packet by packet
+pub struct DcaWriter<W: Write> {
+    writer: W,
+}
+
+impl<W: Write> DcaWriter<W> {
+    /// Writes the header and the metadata block, the Opus packets must follow
+    pub fn new(mut writer: W, metadata: &DcaMetadata) -> io::Result<Self> {
+        let json = serde_json::to_vec(metadata)?;
+        let len = i32::try_from(json.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "DCA metadata too large"))?;
+
+        writer.write_all(b"DCA1")?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&json)?;
+
+        Ok(Self { writer })
+    }
+
+    /// Writes an Opus packet, prefixed by its length
+    pub fn write_packet(&mut self, packet: &[u8]) -> io::Result<()> {
+        let len = i16::try_from(packet.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "Opus packet too large"))?;
+
+        self.writer.write_all(&len.to_le_bytes())?;
+        self.writer.write_all(packet)
+    }
+
+    /// Flushes what's left and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Encodes the track at `input` to a DCA1 file at `output`
+pub fn encode_file<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    options: &DcaOptions,
+) -> Result<(), NError> {
+    let track = MusicTrack::new(input)?;
+    let file = BufWriter::new(File::create(output)?);
+    encode(&track, file, options)?;
+    Ok(())
+}
+
+/// Encodes `track` to DCA1, writing it to `output`, the metadata block gets filled with the tags of the track
+pub fn encode<W: Write>(track: &MusicTrack, output: W, options: &DcaOptions) -> Result<W, NError> {
+    if !FRAME_SIZES.contains(&options.frame_size) {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "invalid Opus frame size").into());
+    }
+
+    let mut format = track.get_format()?;
+    let source = format.default_track().ok_or(NError::NoTrack)?;
+    let track_id = source.id;
+    let source_channels = source
+        .codec_params
+        .channels
+        .map(|channels| channels.count());
+    let encoding = CODEC_REGISTRY
+        .get_codec(source.codec_params.codec)
+        .map(|codec| codec.short_name.to_string());
+    let channels = match options.channels {
+        Some(channels @ 1..=2) => channels,
+        Some(_) => {
+            return Err(
+                io::Error::new(ErrorKind::InvalidInput, "DCA supports 1 or 2 channels").into(),
+            )
+        }
+        None if source_channels == Some(1) => 1,
+        None => 2,
+    };
+
+    let mut decoder = CODEC_REGISTRY
+        .make(&source.codec_params, &DecoderOptions::default())
+        .map_err(|_| NError::UnsupportedCodec)?;
+
+    let mut encoder = Encoder::new(
+        SampleRate::Hz48000,
+        if channels == 1 {
+            Channels::Mono
+        } else {
+            Channels::Stereo
+        },
+        options.application.into(),
+    )
+    .map_err(io::Error::other)?;
+    encoder
+        .set_bitrate(match options.bitrate {
+            Some(bitrate) => Bitrate::BitsPerSecond(bitrate.min(i32::MAX as u32) as i32),
+            None => Bitrate::Auto,
+        })
+        .map_err(io::Error::other)?;
+    encoder.set_vbr(options.vbr).map_err(io::Error::other)?;
+    // The decoder has to skip the samples delayed by the encoder
+    let pre_skip = encoder.lookahead().map_err(io::Error::other)?;
+
+    let mut metadata = DcaMetadata {
+        dca: DcaInfo {
+            version: 1,
+            tool: Tool {
+                name: env!("CARGO_PKG_NAME").to_string(),
+                version: env!("CARGO_PKG_VERSION").to_string(),
+                url: Some(env!("CARGO_PKG_HOMEPAGE").to_string()),
+                author: Some(env!("CARGO_PKG_AUTHORS").to_string()),
+            },
+        },
+        opus: Opus {
+            mode: options.application.mode().to_string(),
+            sample_rate: SAMPLE_RATE,
+            frame_size: options.frame_size as u64,
+            abr: options.bitrate.map(u64::from),
+            vbr: options.vbr,
+            channels,
+        },
+        info: Some(info(track, format.as_mut())),
+        origin: Some(Origin {
+            source: Some(String::from("file")),
+            abr: None,
+            channels: source_channels.map(|channels| channels as u8),
+            encoding,
+            url: None,
+        }),
+        extra: None,
+    };
+
+    let frame_len = options.frame_size as usize * channels as usize;
+    let mut resampler = Resampler::new(options.resampler_quality);
+    let mut buffer: Option<AudioBuffer<f32>> = None;
+    let mut planar = vec![];
+    let mut pending = vec![];
+    let mut encoded = vec![0; MAX_PACKET];
+    // Used to know how many samples the output should have, since the resampler adds some at the end
+    let mut rate = SAMPLE_RATE;
+    let mut input_frames = 0u64;
+    let mut output_frames = 0u64;
+    // The packets are kept until the end, since the gapless info in the metadata block is known only then
+    let mut packets: Vec<Box<[u8]>> = vec![];
+
+    let mut encode_frames = |pending: &mut Vec<f32>, packets: &mut Vec<Box<[u8]>>| {
+        let mut used = 0;
+        for frame in pending.chunks_exact(frame_len) {
+            let len = encoder
+                .encode_float(frame, &mut encoded)
+                .map_err(io::Error::other)?;
+            packets.push(Box::from(&encoded[..len]));
+            used += frame.len();
+        }
+        pending.drain(..used);
+        io::Result::Ok(())
+    };
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphError::DecodeError(err)) => {
+                eprintln!("Decode error: {}", err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let spec = *decoded.spec();
+        let buffer = match &mut buffer {
+            Some(buffer) if *buffer.spec() == spec && buffer.capacity() >= decoded.frames() => {
+                buffer
+            }
+            buffer => buffer.insert(AudioBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        decoded.convert(buffer);
+
+        rate = spec.rate;
+        input_frames += buffer.frames() as u64;
+        map_channels(buffer, channels as usize, &mut planar);
+        let len = pending.len();
+        resampler.process(&planar, rate as f64 / SAMPLE_RATE as f64, &mut pending);
+        output_frames += ((pending.len() - len) / channels as usize) as u64;
+
+        encode_frames(&mut pending, &mut packets)?;
+    }
+
+    // Push out what's left inside the resampler
+    let expected = input_frames * SAMPLE_RATE as u64 / rate as u64;
+    if output_frames < expected {
+        planar.iter_mut().for_each(|ch| {
+            ch.clear();
+            ch.resize(rate as usize / 10, 0.0);
+        });
+        let len = pending.len();
+        resampler.process(&planar, rate as f64 / SAMPLE_RATE as f64, &mut pending);
+        let missing = (expected - output_frames) as usize * channels as usize;
+        pending.truncate(len + missing.min(pending.len() - len));
+        output_frames += ((pending.len() - len) / channels as usize) as u64;
+    }
+    // Flush the samples delayed by the encoder, then pad the last frame with silence
+    pending.resize(pending.len() + pre_skip as usize * channels as usize, 0.0);
+    if pending.len() % frame_len != 0 {
+        pending.resize(pending.len() + frame_len - pending.len() % frame_len, 0.0);
+    }
+    encode_frames(&mut pending, &mut packets)?;
+
+    let encoded_frames = packets.len() as u64 * options.frame_size as u64;
+    metadata.extra = Some(serde_json::json!({
+        "gapless": Gapless {
+            pre_skip,
+            padding: (encoded_frames - pre_skip as u64 - output_frames) as u32,
+            frames: output_frames,
+        }
+    }));
+
+    let mut writer = DcaWriter::new(output, &metadata)?;
+    for packet in &packets {
+        writer.write_packet(packet)?;
+    }
+    Ok(writer.finish()?)
+}
+
+/// Collects the tags and the cover of `track` to be written in the metadata block
+fn info(track: &MusicTrack, format: &mut dyn FormatReader) -> Info {
+    let mut info = Info {
+        title: None,
+        artist: None,
+        album: None,
+        genre: None,
+        cover: None,
+        comments: None,
+    };
+
+    if let Ok(meta) = track.get_meta() {
+        info.title = Some(meta.title);
+        info.artist = (!meta.artist.is_empty()).then_some(meta.artist);
+        info.album = (!meta.album.is_empty()).then_some(meta.album);
+    }
+
+    let mut cover = None;
+    if let Some(rev) = format.metadata().current() {
+        for tag in rev.tags() {
+            match tag.std_key {
+                Some(StandardTagKey::Genre) => info.genre = Some(tag.value.to_string()),
+                Some(StandardTagKey::Comment) => info.comments = Some(tag.value.to_string()),
+                _ => {}
+            }
+        }
+        cover = rev.visuals().first().map(|visual| visual.data.to_vec());
+    }
+    if cover.is_none() {
+        cover = Tag::read_from_path(track.path())
+            .ok()
+            .and_then(|tag| tag.get_album_info())
+            .and_then(|album| album.cover)
+            .map(|cover| cover.data);
+    }
+    info.cover = cover.map(|cover| STANDARD.encode(cover));
+
+    info
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dca::DcaReader;
+    use std::fs;
+    use std::io::Cursor;
+    use std::path::PathBuf;
+    use symphonia::core::formats::FormatOptions;
+    use symphonia::core::io::MediaSourceStream;
+
+    /// 0.3 s of a stereo 440 Hz tone at 44.1 kHz
+    const RATE: u32 = 44100;
+    const FRAMES: u64 = 13230;
+
+    /// Appends a RIFF chunk, padded to an even length
+    fn chunk(out: &mut Vec<u8>, id: &[u8; 4], data: &[u8]) {
+        out.extend_from_slice(id);
+        out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        out.extend_from_slice(data);
+        out.resize(out.len() + data.len() % 2, 0);
+    }
+
+    /// Writes a 16 bit WAV file with a title, an artist and an album in its INFO list
+    fn wav(dir: &Path) -> PathBuf {
+        let mut info = b"INFO".to_vec();
+        chunk(&mut info, b"INAM", b"Title");
+        chunk(&mut info, b"IART", b"Artist");
+        chunk(&mut info, b"IPRD", b"Album");
+
+        let mut fmt = vec![];
+        fmt.extend_from_slice(&1u16.to_le_bytes());
+        fmt.extend_from_slice(&2u16.to_le_bytes());
+        fmt.extend_from_slice(&RATE.to_le_bytes());
+        fmt.extend_from_slice(&(RATE * 4).to_le_bytes());
+        fmt.extend_from_slice(&4u16.to_le_bytes());
+        fmt.extend_from_slice(&16u16.to_le_bytes());
+
+        let data = (0..FRAMES)
+            .map(|i| (i as f32 * 440.0 / RATE as f32 * std::f32::consts::TAU).sin() * 8000.0)
+            .flat_map(|sample| [sample as i16; 2])
+            .flat_map(i16::to_le_bytes)
+            .collect::<Vec<_>>();
+
+        let mut wave = b"WAVE".to_vec();
+        chunk(&mut wave, b"fmt ", &fmt);
+        chunk(&mut wave, b"LIST", &info);
+        chunk(&mut wave, b"data", &data);
+        let mut bytes = vec![];
+        chunk(&mut bytes, b"RIFF", &wave);
+
+        let path = dir.join("track.wav");
+        fs::write(&path, bytes).unwrap();
+        path
+    }
+
+    fn track(path: &Path) -> MusicTrack {
+        MusicTrack::new(path.to_str().unwrap()).unwrap()
+    }
+
+    /// Reads the metadata block of a DCA1 file
+    fn read_metadata(bytes: &[u8]) -> DcaMetadata {
+        assert_eq!(&bytes[..4], b"DCA1");
+        let len = i32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        serde_json::from_slice(&bytes[8..8 + len]).unwrap()
+    }
+
+    fn gapless(metadata: &DcaMetadata) -> Gapless {
+        serde_json::from_value(metadata.extra.as_ref().unwrap()["gapless"].clone()).unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = DcaOptions::default();
+        let bytes = encode(&track(&wav(dir.path())), vec![], &options).unwrap();
+
+        let metadata = read_metadata(&bytes);
+        let info = metadata.info.as_ref().unwrap();
+        assert_eq!(info.title.as_deref(), Some("Title"));
+        assert_eq!(info.artist.as_deref(), Some("Artist"));
+        assert_eq!(info.album.as_deref(), Some("Album"));
+        assert_eq!(metadata.opus.sample_rate, SAMPLE_RATE);
+        assert_eq!(metadata.opus.frame_size, 960);
+        assert_eq!(metadata.opus.channels, 2);
+        let origin = metadata.origin.as_ref().unwrap();
+        assert_eq!(origin.channels, Some(2));
+
+        // The frames are counted at 48 kHz, the pre-skip and the padding fill whole packets
+        let gapless = gapless(&metadata);
+        assert_eq!(gapless.frames, FRAMES * SAMPLE_RATE as u64 / RATE as u64);
+        assert!(gapless.padding < options.frame_size);
+
+        let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        let options = FormatOptions {
+            enable_gapless: true,
+            ..Default::default()
+        };
+        let mut reader = DcaReader::try_new(source, &options).unwrap();
+        let params = &reader.default_track().unwrap().codec_params;
+        assert_eq!(params.delay, Some(gapless.pre_skip));
+        assert_eq!(params.n_frames, Some(gapless.frames));
+
+        let mut packets = 0;
+        let mut frames = 0;
+        while let Ok(packet) = reader.next_packet() {
+            packets += 1;
+            frames += packet.dur;
+        }
+        assert_eq!(frames, gapless.frames);
+        assert_eq!(
+            packets * 960,
+            gapless.pre_skip as u64 + gapless.frames + gapless.padding as u64
+        );
+    }
+
+    #[test]
+    fn keeps_the_cover() {
+        let dir = tempfile::tempdir().unwrap();
+        let cover = STANDARD.encode([0x89, b'P', b'N', b'G', 0x0D, 0x0A, 0x1A, 0x0A]);
+
+        // WAV files can't hold a cover, so it comes from a DCA file that gets encoded again
+        let mut metadata =
+            read_metadata(&encode(&track(&wav(dir.path())), vec![], &Default::default()).unwrap());
+        metadata.info.as_mut().unwrap().cover = Some(cover.clone());
+        let mut writer = DcaWriter::new(vec![], &metadata).unwrap();
+        let encoder =
+            Encoder::new(SampleRate::Hz48000, Channels::Stereo, Application::Audio).unwrap();
+        let mut packet = [0; MAX_PACKET];
+        for _ in 0..10 {
+            let len = encoder.encode_float(&[0.0; 1920], &mut packet).unwrap();
+            writer.write_packet(&packet[..len]).unwrap();
+        }
+        let path = dir.path().join("track.dca");
+        fs::write(&path, writer.finish().unwrap()).unwrap();
+
+        let bytes = encode(&track(&path), vec![], &Default::default()).unwrap();
+        let info = read_metadata(&bytes).info.unwrap();
+        assert_eq!(info.cover, Some(cover));
+        assert_eq!(info.title.as_deref(), Some("Title"));
+    }
+
+    #[test]
+    fn rejects_invalid_options() {
+        let dir = tempfile::tempdir().unwrap();
+        let track = track(&wav(dir.path()));
+
+        for options in [
+            DcaOptions {
+                frame_size: 1000,
+                ..Default::default()
+            },
+            DcaOptions {
+                channels: Some(3),
+                ..Default::default()
+            },
+            DcaOptions {
+                channels: Some(0),
+                ..Default::default()
+            },
+        ] {
+            match encode(&track, vec![], &options) {
+                Err(NError::Io(err)) => assert_eq!(err.kind(), ErrorKind::InvalidInput),
+                _ => panic!("{options:?} should be rejected"),
+            }
+        }
+    }
+}
@@ -0,0 +1,110 @@
+//! Enumeration of the audio hosts and output devices available
+
+use cpal::traits::{DeviceTrait, HostTrait};
+
+/// An output device, identified by its name
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputDevice {
+    pub host: String,
+    pub name: String,
+    pub is_default: bool,
+    pub configs: Vec<DeviceConfig>,
+}
+
+/// A config supported by an output device
+#[derive(Clone, Debug, PartialEq)]
+pub struct DeviceConfig {
+    pub channels: u16,
+    pub min_rate: u32,
+    pub max_rate: u32,
+    pub sample_format: String,
+}
+
+/// Returns the names of the hosts available on this platform
+pub fn hosts() -> Vec<String> {
+    cpal::available_hosts()
+        .into_iter()
+        .map(|host| host.name().to_string())
+        .collect()
+}
+
+/// Returns the output devices of every available host, the ones of the default host first
+pub fn output_devices() -> Vec<OutputDevice> {
+    let mut devices = vec![];
+
+    for host in available_hosts() {
+        let default = host
+            .default_output_device()
+            .and_then(|device| device.name().ok());
+        let Ok(outputs) = host.output_devices() else {
+            continue;
+        };
+
+        for device in outputs {
+            let Ok(name) = device.name() else {
+                continue;
+            };
+            let configs = device
+                .supported_output_configs()
+                .map(|configs| {
+                    configs
+                        .map(|config| DeviceConfig {
+                            channels: config.channels(),
+                            min_rate: config.min_sample_rate().0,
+                            max_rate: config.max_sample_rate().0,
+                            sample_format: config.sample_format().to_string(),
+                        })
+                        .collect()
+                })
+                .unwrap_or_default();
+
+            devices.push(OutputDevice {
+                host: host.id().name().to_string(),
+                is_default: default.as_ref() == Some(&name),
+                name,
+                configs,
+            });
+        }
+    }
+
+    devices
+}
+
+/// Returns the name of the default output device, if any
+pub fn default_output_device() -> Option<String> {
+    cpal::default_host()
+        .default_output_device()
+        .and_then(|device| device.name().ok())
+}
+
+/// Finds the output device with the given name, falling back to the default one if it's `None` or it can't be found
+pub(crate) fn find_output_device(name: Option<&str>) -> Option<cpal::Device> {
+    if let Some(name) = name {
+        for host in available_hosts() {
+            if let Ok(mut outputs) = host.output_devices() {
+                if let Some(device) = outputs.find(|device| device.name().is_ok_and(|n| n == name))
+                {
+                    return Some(device);
+                }
+            }
+        }
+        eprintln!("Output device {name} not found, using the default one");
+    }
+
+    cpal::default_host().default_output_device()
+}
+
+/// Returns the available hosts, the default one first
+fn available_hosts() -> Vec<cpal::Host> {
+    let default = cpal::default_host();
+    let default_id = default.id();
+
+    let mut hosts = vec![default];
+    hosts.extend(
+        cpal::available_hosts()
+            .into_iter()
+            .filter(|id| *id != default_id)
+            .filter_map(|id| cpal::host_from_id(id).ok()),
+    );
+    hosts
+}
@@ -0,0 +1,243 @@
+//! Processing of the audio between the decoder and the output, e.g. equalizers, limiters and effects
+//!
+//! The processors are run in order by the thread of the `Player`, which can add, remove and tune them while playing
+//! (see `Player::insert_dsp`)
+
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use symphonia::core::audio::SignalSpec;
+
+/// A step of the `DspChain`
+///
+/// It gets the audio already converted to the spec of the output (channels and sample rate), right before the
+/// volume is applied
+pub trait Dsp: Send {
+    /// Identifies the processor inside the chain, it must be unique
+    fn name(&self) -> &str;
+
+    /// Processes the interleaved `samples` in place, `spec` is the one of the output and it may change between calls
+    fn process(&mut self, samples: &mut [f32], spec: SignalSpec);
+
+    /// Forgets the audio processed so far (e.g. the history of a filter), called when the playback jumps
+    fn reset(&mut self) {}
+
+    /// Changes the parameter `name`, the ones the processor doesn't know are ignored
+    fn set_parameter(&mut self, _name: &str, _value: f32) {}
+}
+
+struct Entry {
+    dsp: Box<dyn Dsp>,
+    bypassed: bool,
+}
+
+/// Ordered list of `Dsp`s, each one processing the output of the previous one
+#[derive(Default)]
+pub struct DspChain {
+    entries: Vec<Entry>,
+}
+
+impl DspChain {
+    /// Inserts `dsp` at `index`, clamped to the length of the chain
+    ///
+    /// A processor with the same name is replaced, keeping its position if `index` is past the end
+    pub fn insert(&mut self, index: usize, dsp: Box<dyn Dsp>) {
+        let entry = Entry {
+            dsp,
+            bypassed: false,
+        };
+        let mut index = index;
+        if let Some(old) = self.position(entry.dsp.name()) {
+            self.entries.remove(old);
+            if index > self.entries.len() {
+                index = old;
+            }
+        }
+        self.entries.insert(index.min(self.entries.len()), entry);
+    }
+
+    /// Removes the processor called `name`, returning it
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn Dsp>> {
+        let index = self.position(name)?;
+        Some(self.entries.remove(index).dsp)
+    }
+
+    /// Removes every processor
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+
+    /// Changes a parameter of the processor called `dsp`, returns `false` if there isn't any
+    pub fn set_parameter(&mut self, dsp: &str, name: &str, value: f32) -> bool {
+        match self.get_mut(dsp) {
+            Some(entry) => {
+                entry.dsp.set_parameter(name, value);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Skips the processor called `dsp` without removing it, returns `false` if there isn't any
+    pub fn set_bypass(&mut self, dsp: &str, bypassed: bool) -> bool {
+        match self.get_mut(dsp) {
+            Some(entry) => {
+                // It starts again from silence once it's back
+                if entry.bypassed && !bypassed {
+                    entry.dsp.reset();
+                }
+                entry.bypassed = bypassed;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Returns the names of the processors, in the order they are run
+    pub fn names(&self) -> Vec<String> {
+        self.entries
+            .iter()
+            .map(|entry| entry.dsp.name().to_string())
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Runs every processor that isn't bypassed on `samples`
+    pub fn process(&mut self, samples: &mut [f32], spec: SignalSpec) {
+        if samples.is_empty() {
+            return;
+        }
+        for entry in self.entries.iter_mut().filter(|entry| !entry.bypassed) {
+            entry.dsp.process(samples, spec);
+        }
+    }
+
+    /// Resets every processor, see `Dsp::reset`
+    pub fn reset(&mut self) {
+        for entry in &mut self.entries {
+            entry.dsp.reset();
+        }
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        self.entries
+            .iter()
+            .position(|entry| entry.dsp.name() == name)
+    }
+
+    fn get_mut(&mut self, name: &str) -> Option<&mut Entry> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.dsp.name() == name)
+    }
+}
+
+impl Debug for DspChain {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.entries.iter().map(|entry| entry.dsp.name()))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::Channels;
+
+    /// Appends its id to every sample, so the output tells which processors ran and in which order
+    struct Tag {
+        name: &'static str,
+        id: f32,
+    }
+
+    impl Tag {
+        fn boxed(name: &'static str, id: f32) -> Box<dyn Dsp> {
+            Box::new(Tag { name, id })
+        }
+    }
+
+    impl Dsp for Tag {
+        fn name(&self) -> &str {
+            self.name
+        }
+
+        fn process(&mut self, samples: &mut [f32], _spec: SignalSpec) {
+            for sample in samples {
+                *sample = *sample * 10.0 + self.id;
+            }
+        }
+    }
+
+    fn run(chain: &mut DspChain) -> f32 {
+        let mut samples = [0.0];
+        chain.process(&mut samples, SignalSpec::new(44100, Channels::FRONT_LEFT));
+        samples[0]
+    }
+
+    #[test]
+    fn inserts_in_order() {
+        let mut chain = DspChain::default();
+        chain.insert(usize::MAX, Tag::boxed("a", 1.0));
+        chain.insert(usize::MAX, Tag::boxed("b", 2.0));
+        chain.insert(0, Tag::boxed("c", 3.0));
+        chain.insert(1, Tag::boxed("d", 4.0));
+        assert_eq!(chain.names(), ["c", "d", "a", "b"]);
+        assert_eq!(run(&mut chain), 3412.0);
+    }
+
+    #[test]
+    fn replaces_by_name() {
+        let mut chain = DspChain::default();
+        for (name, id) in [("a", 1.0), ("b", 2.0), ("c", 3.0)] {
+            chain.insert(usize::MAX, Tag::boxed(name, id));
+        }
+
+        // Past the end it keeps the old position
+        chain.insert(usize::MAX, Tag::boxed("b", 5.0));
+        assert_eq!(chain.names(), ["a", "b", "c"]);
+        assert_eq!(run(&mut chain), 153.0);
+
+        chain.insert(0, Tag::boxed("c", 6.0));
+        assert_eq!(chain.names(), ["c", "a", "b"]);
+        assert_eq!(run(&mut chain), 615.0);
+    }
+
+    #[test]
+    fn removes_by_name() {
+        let mut chain = DspChain::default();
+        for (name, id) in [("a", 1.0), ("b", 2.0), ("c", 3.0)] {
+            chain.insert(usize::MAX, Tag::boxed(name, id));
+        }
+
+        assert_eq!(
+            chain.remove("b").map(|dsp| dsp.name().to_string()),
+            Some("b".to_string())
+        );
+        assert!(chain.remove("b").is_none());
+        assert_eq!(chain.names(), ["a", "c"]);
+        assert_eq!(run(&mut chain), 13.0);
+
+        chain.clear();
+        assert!(chain.is_empty());
+        assert_eq!(run(&mut chain), 0.0);
+    }
+
+    #[test]
+    fn bypass_keeps_the_position() {
+        let mut chain = DspChain::default();
+        for (name, id) in [("a", 1.0), ("b", 2.0), ("c", 3.0)] {
+            chain.insert(usize::MAX, Tag::boxed(name, id));
+        }
+
+        assert!(chain.set_bypass("b", true));
+        assert!(!chain.set_bypass("d", true));
+        assert_eq!(chain.names(), ["a", "b", "c"]);
+        assert_eq!(run(&mut chain), 13.0);
+
+        assert!(chain.set_bypass("b", false));
+        assert_eq!(run(&mut chain), 123.0);
+    }
+}
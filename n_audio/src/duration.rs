@@ -0,0 +1,95 @@
+//! Estimation of the length of tracks whose headers don't tell it, like VBR MP3s without a Xing/VBRI header
+
+use crate::NError;
+use std::fs::File;
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use symphonia::core::errors::Error as SymphError;
+use symphonia::core::formats::FormatReader;
+
+/// Size of the header of an Ogg page, before its segment table
+const OGG_HEADER: usize = 27;
+/// Bytes read from the end of an Ogg file looking for its last page, doubled until one is found
+const OGG_TAIL: u64 = 64 * 1024;
+/// Ogg pages are at most this big, so the last one must be found within this many bytes from the end
+const OGG_TAIL_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// Estimates the length in seconds of the default track of `format`, read from the file at `path`
+///
+/// Ogg files only need their last page, every other format is read packet by packet, so it can take a while
+pub(crate) fn estimate<P: AsRef<Path>>(
+    path: P,
+    format: &mut dyn FormatReader,
+) -> Result<Option<f64>, NError> {
+    let track = format.default_track().ok_or(NError::NoTrack)?;
+    let track_id = track.id;
+    let params = track.codec_params.clone();
+    let Some(time_base) = params.time_base else {
+        return Ok(None);
+    };
+
+    let end = match ogg_last_granule(&mut File::open(path)?)? {
+        // The granule position counts the samples to be skipped at the start too
+        Some(granule) => Some(granule.saturating_sub(params.delay.unwrap_or(0) as u64)),
+        None => scan(format, track_id)?,
+    };
+
+    Ok(end.map(|end| {
+        let time = time_base.calc_time(end);
+        time.seconds as f64 + time.frac
+    }))
+}
+
+/// Returns the timestamp right after the last packet of the track `track_id`
+fn scan(format: &mut dyn FormatReader, track_id: u32) -> Result<Option<u64>, NError> {
+    let mut end = None;
+
+    loop {
+        match format.next_packet() {
+            Ok(packet) if packet.track_id() == track_id => {
+                end = end.max(Some(packet.ts() + packet.dur()));
+            }
+            Ok(_) => {}
+            Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    Ok(end)
+}
+
+/// Returns the granule position of the last page of the first logical stream, `None` if it isn't an Ogg file
+fn ogg_last_granule<R: Read + Seek>(reader: &mut R) -> io::Result<Option<u64>> {
+    let mut header = [0; OGG_HEADER];
+    match reader.read_exact(&mut header) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err),
+    }
+    if &header[..4] != b"OggS" {
+        return Ok(None);
+    }
+    let serial = &header[14..18];
+
+    let len = reader.seek(SeekFrom::End(0))?;
+    let mut tail = OGG_TAIL;
+    loop {
+        let start = len.saturating_sub(tail);
+        reader.seek(SeekFrom::Start(start))?;
+        let mut data = vec![];
+        reader.read_to_end(&mut data)?;
+
+        // Pages still being written have no granule position, all ones
+        let granule = data
+            .windows(OGG_HEADER)
+            .rev()
+            .filter(|page| &page[..4] == b"OggS" && page[4] == 0 && &page[14..18] == serial)
+            .map(|page| u64::from_le_bytes(page[6..14].try_into().unwrap()))
+            .find(|&granule| granule != u64::MAX);
+        if granule.is_some() || start == 0 || tail >= OGG_TAIL_LIMIT {
+            return Ok(granule);
+        }
+        tail *= 2;
+    }
+}
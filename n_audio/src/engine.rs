@@ -0,0 +1,358 @@
+//! Long-lived audio engine, owning the only output stream used by a `Player`
+
+use crate::dsp::DspChain;
+use crate::output::{AudioOutput, OutputBackend};
+use crate::resampler::{Resampler, ResamplerQuality};
+use crate::time_stretch::{TimeStretch, TimeStretchMode};
+use crate::visualization::VisualizationTap;
+use crate::NError;
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::thread;
+use std::time::Duration;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec};
+
+/// Takes the buffers decoded from any track and writes them to a single output stream, converting them to the
+/// spec of the device (channels and sample rate) as needed
+///
+/// If the device fails, the stream gets reopened on the next write instead of stopping the playback
+#[derive(Default)]
+pub struct AudioEngine {
+    backend: OutputBackend,
+    device: Option<String>,
+    output: Option<Box<dyn AudioOutput>>,
+    /// Whether the output couldn't be opened the last time, so that it gets reported only once
+    unavailable: bool,
+    resampler: Resampler,
+    time_stretch_mode: TimeStretchMode,
+    time_stretch: Option<TimeStretch>,
+    dsp: DspChain,
+    tap: VisualizationTap,
+    buffer: Option<AudioBuffer<f32>>,
+    planar: Vec<Vec<f32>>,
+    stretched: Vec<Vec<f32>>,
+    samples: Vec<f32>,
+}
+
+impl AudioEngine {
+    /// Instance a new `AudioEngine`, playing on `backend`
+    /// Devices are chosen by `device`, the default one is used if it's `None`
+    /// What gets played is also written to `tap`
+    pub fn new(
+        backend: OutputBackend,
+        device: Option<String>,
+        resampler_quality: ResamplerQuality,
+        tap: VisualizationTap,
+    ) -> Self {
+        Self {
+            backend,
+            device,
+            resampler: Resampler::new(resampler_quality),
+            tap,
+            ..Default::default()
+        }
+    }
+
+    /// Changes the quality used to convert the tracks to the rate of the output
+    pub fn set_resampler_quality(&mut self, quality: ResamplerQuality) {
+        if self.resampler.quality() != quality {
+            self.resampler = Resampler::new(quality);
+        }
+    }
+
+    /// Changes how the playback speed gets applied
+    pub fn set_time_stretch_mode(&mut self, mode: TimeStretchMode) {
+        self.time_stretch_mode = mode;
+    }
+
+    /// Switches to another output device, the following writes will go to the new one
+    /// Other backends keep their output, which couldn't be reopened without losing what was written
+    pub fn set_device(&mut self, device: Option<String>) {
+        if self.device != device {
+            self.device = device;
+            if !self.backend.is_device() {
+                return;
+            }
+            self.output = None;
+            self.unavailable = false;
+            self.resampler.reset();
+        }
+    }
+
+    /// Returns the processors run on the audio right before it's written
+    pub fn dsp_mut(&mut self) -> &mut DspChain {
+        &mut self.dsp
+    }
+
+    /// Returns the spec of the output, opening it if needed
+    pub fn spec(&mut self) -> Option<SignalSpec> {
+        self.open().map(|output| output.spec())
+    }
+
+    /// Converts `decoded` to the spec of the output and writes it
+    ///
+    /// `playback_speed` is applied according to the `TimeStretchMode` set
+    /// It errors when the output can't be opened or it fails, but the next writes will try to reopen it anyway
+    pub fn write(
+        &mut self,
+        decoded: AudioBufferRef<'_>,
+        volume: f32,
+        playback_speed: f32,
+    ) -> Result<(), NError> {
+        if decoded.frames() == 0 {
+            return Ok(());
+        }
+
+        let spec = *decoded.spec();
+        let was_unavailable = self.unavailable;
+        let Some(output_spec) = self.spec() else {
+            // There's no device to play on, wait as long as the buffer would have taken to be played
+            let seconds = decoded.frames() as f64 / (spec.rate as f64 * playback_speed as f64);
+            thread::sleep(Duration::from_secs_f64(seconds));
+            return if was_unavailable {
+                Ok(())
+            } else {
+                Err(NError::Device(format!(
+                    "can't open {}",
+                    self.device.as_deref().unwrap_or("the default device")
+                )))
+            };
+        };
+
+        self.process(decoded, output_spec, playback_speed);
+        if let Some(output) = &mut self.output {
+            if let Err(err) = output.write(&self.samples, volume) {
+                self.output = None;
+                self.resampler.reset();
+                return Err(NError::Device(err.to_string()));
+            }
+        }
+        Ok(())
+    }
+
+    /// Converts `decoded` to `output_spec` like `AudioEngine::write`, returning the samples instead of playing them
+    ///
+    /// The volume is left to the caller, as it's applied by the output
+    pub fn render(
+        &mut self,
+        decoded: AudioBufferRef<'_>,
+        output_spec: SignalSpec,
+        playback_speed: f32,
+    ) -> &[f32] {
+        if decoded.frames() == 0 {
+            self.samples.clear();
+        } else {
+            self.process(decoded, output_spec, playback_speed);
+        }
+        &self.samples
+    }
+
+    /// Converts `decoded` to `output_spec` and runs the DSP chain on it, replacing the content of `samples`
+    fn process(
+        &mut self,
+        decoded: AudioBufferRef<'_>,
+        output_spec: SignalSpec,
+        playback_speed: f32,
+    ) {
+        let spec = *decoded.spec();
+        let buffer = match &mut self.buffer {
+            Some(buffer) if *buffer.spec() == spec && buffer.capacity() >= decoded.frames() => {
+                buffer
+            }
+            buffer => buffer.insert(AudioBuffer::new(decoded.capacity() as u64, spec)),
+        };
+        decoded.convert(buffer);
+
+        map_channels(buffer, output_spec.channels.count(), &mut self.planar);
+
+        let (planar, rate) =
+            if self.time_stretch_mode == TimeStretchMode::PreservePitch && playback_speed != 1.0 {
+                let time_stretch = match &mut self.time_stretch {
+                    Some(time_stretch) if time_stretch.rate() == spec.rate => time_stretch,
+                    time_stretch => time_stretch.insert(TimeStretch::new(spec.rate)),
+                };
+                self.stretched.resize(self.planar.len(), vec![]);
+                self.stretched.iter_mut().for_each(Vec::clear);
+                time_stretch.process(&self.planar, playback_speed as f64, &mut self.stretched);
+                (&self.stretched, spec.rate as f64)
+            } else {
+                // Start from a clean state the next time the speed changes
+                if let Some(time_stretch) = &mut self.time_stretch {
+                    time_stretch.reset();
+                }
+                (&self.planar, spec.rate as f64 * playback_speed as f64)
+            };
+
+        self.samples.clear();
+        self.resampler
+            .process(planar, rate / output_spec.rate as f64, &mut self.samples);
+        self.dsp.process(&mut self.samples, output_spec);
+    }
+
+    /// Discards what wasn't played yet, used when the played track changes abruptly (e.g. on seek)
+    pub fn flush(&mut self) {
+        if let Some(output) = &mut self.output {
+            output.flush();
+        }
+        self.resampler.reset();
+        if let Some(time_stretch) = &mut self.time_stretch {
+            time_stretch.reset();
+        }
+        self.dsp.reset();
+    }
+
+    fn open(&mut self) -> Option<&mut Box<dyn AudioOutput>> {
+        if self.output.is_none() {
+            self.output = self
+                .backend
+                .open(self.device.as_deref(), self.tap.clone())
+                .ok();
+            self.unavailable = self.output.is_none();
+        }
+        self.output.as_mut()
+    }
+}
+
+/// Maps the channels of `buffer` to `channels` output channels, replacing the content of `planar`
+pub(crate) fn map_channels(buffer: &AudioBuffer<f32>, channels: usize, planar: &mut Vec<Vec<f32>>) {
+    let in_channels = buffer.spec().channels.count();
+    planar.resize(channels, vec![]);
+
+    if in_channels > 2 && channels == 2 {
+        downmix_stereo(buffer, planar);
+        return;
+    }
+
+    for (ch, out) in planar.iter_mut().enumerate() {
+        out.clear();
+        if in_channels > channels && channels == 1 {
+            // Downmix everything to mono
+            out.extend((0..buffer.frames()).map(|frame| {
+                (0..in_channels)
+                    .map(|ch| buffer.chan(ch)[frame])
+                    .sum::<f32>()
+                    / in_channels as f32
+            }));
+        } else {
+            // Extra input channels get dropped, missing ones are filled repeating the input ones (e.g. mono to stereo)
+            out.extend_from_slice(buffer.chan(ch % in_channels));
+        }
+    }
+}
+
+/// Downmixes a surround `buffer` to stereo, following ITU-R BS.775 (without the LFE)
+fn downmix_stereo(buffer: &AudioBuffer<f32>, planar: &mut [Vec<f32>]) {
+    let layout = buffer.spec().channels;
+    // The gains of every input channel on the left and on the right output
+    let gains = layout
+        .iter()
+        .map(|channel| {
+            if channel == Channels::FRONT_LEFT {
+                (1.0, 0.0)
+            } else if channel == Channels::FRONT_RIGHT {
+                (0.0, 1.0)
+            } else if channel == Channels::FRONT_CENTRE || channel == Channels::REAR_CENTRE {
+                (FRAC_1_SQRT_2, FRAC_1_SQRT_2)
+            } else if channel == Channels::REAR_LEFT || channel == Channels::SIDE_LEFT {
+                (FRAC_1_SQRT_2, 0.0)
+            } else if channel == Channels::REAR_RIGHT || channel == Channels::SIDE_RIGHT {
+                (0.0, FRAC_1_SQRT_2)
+            } else {
+                (0.0, 0.0)
+            }
+        })
+        .collect::<Vec<(f32, f32)>>();
+    let norm = gains.iter().map(|(left, _)| left).sum::<f32>().max(1.0);
+
+    for (side, out) in planar.iter_mut().enumerate() {
+        out.clear();
+        out.extend((0..buffer.frames()).map(|frame| {
+            gains
+                .iter()
+                .enumerate()
+                .map(|(ch, (left, right))| {
+                    buffer.chan(ch)[frame] * if side == 0 { *left } else { *right }
+                })
+                .sum::<f32>()
+                / norm
+        }));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A buffer with a single frame, each channel holding the given sample
+    fn frame(channels: Channels, samples: &[f32]) -> AudioBuffer<f32> {
+        let mut buffer = AudioBuffer::new(1, SignalSpec::new(48000, channels));
+        buffer.render_reserved(Some(1));
+        for (ch, sample) in samples.iter().enumerate() {
+            buffer.chan_mut(ch)[0] = *sample;
+        }
+        buffer
+    }
+
+    fn mapped(buffer: &AudioBuffer<f32>, channels: usize) -> Vec<f32> {
+        let mut planar = vec![];
+        map_channels(buffer, channels, &mut planar);
+        planar.iter().map(|channel| channel[0]).collect()
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-6,
+                "{actual} isn't {expected}"
+            );
+        }
+    }
+
+    #[test]
+    fn maps_mono_and_stereo() {
+        let mono = frame(Channels::FRONT_LEFT, &[0.5]);
+        assert_eq!(mapped(&mono, 1), [0.5]);
+        assert_eq!(mapped(&mono, 2), [0.5, 0.5]);
+
+        let stereo = frame(Channels::FRONT_LEFT | Channels::FRONT_RIGHT, &[0.5, -0.25]);
+        assert_eq!(mapped(&stereo, 2), [0.5, -0.25]);
+        assert_eq!(mapped(&stereo, 1), [0.125]);
+        assert_eq!(mapped(&stereo, 4), [0.5, -0.25, 0.5, -0.25]);
+    }
+
+    #[test]
+    fn downmixes_surround() {
+        // FL, FR, FC, LFE, RL, RR
+        let surround = Channels::FRONT_LEFT
+            | Channels::FRONT_RIGHT
+            | Channels::FRONT_CENTRE
+            | Channels::LFE1
+            | Channels::REAR_LEFT
+            | Channels::REAR_RIGHT;
+        let norm = 1.0 + 2.0 * FRAC_1_SQRT_2;
+
+        assert_close(
+            &mapped(&frame(surround, &[1.0, 0.0, 0.0, 0.0, 0.0, 0.0]), 2),
+            &[1.0 / norm, 0.0],
+        );
+        assert_close(
+            &mapped(&frame(surround, &[0.0, 0.0, 1.0, 0.0, 0.0, 0.0]), 2),
+            &[FRAC_1_SQRT_2 / norm, FRAC_1_SQRT_2 / norm],
+        );
+        assert_close(
+            &mapped(&frame(surround, &[0.0, 0.0, 0.0, 1.0, 0.0, 0.0]), 2),
+            &[0.0, 0.0],
+        );
+        assert_close(
+            &mapped(&frame(surround, &[0.0, 0.0, 0.0, 0.0, 0.0, 1.0]), 2),
+            &[0.0, FRAC_1_SQRT_2 / norm],
+        );
+        // A full scale frame on every channel doesn't clip
+        assert_close(&mapped(&frame(surround, &[1.0; 6]), 2), &[1.0, 1.0]);
+        // Mono gets the average of every channel instead
+        assert_close(
+            &mapped(&frame(surround, &[0.6, 0.0, 0.0, 0.0, 0.0, 0.0]), 1),
+            &[0.1],
+        );
+    }
+}
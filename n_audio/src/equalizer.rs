@@ -0,0 +1,558 @@
+//! Graphic and parametric equalizer, run as a `Dsp` of the player
+//!
+//! The filters are the biquads of the [Audio EQ Cookbook](https://www.w3.org/TR/audio-eq-cookbook/), computed for the
+//! sample rate of the output and applied to every channel on its own
+
+use crate::dsp::Dsp;
+use std::f64::consts::TAU;
+use std::io;
+use std::io::ErrorKind;
+use symphonia::core::audio::SignalSpec;
+
+/// Name of the `Equalizer` inside the DSP chain
+pub const EQUALIZER: &str = "equalizer";
+/// Center frequencies of the bands of the graphic equalizer, one octave apart
+pub const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// How many bands the parametric equalizer can have
+pub const MAX_PARAMETRIC_BANDS: usize = 8;
+/// The gain of every band is clamped between -`MAX_GAIN` and `MAX_GAIN` dB
+pub const MAX_GAIN: f32 = 24.0;
+/// Q of the bands of the graphic equalizer, giving a bandwidth of one octave
+const GRAPHIC_Q: f32 = std::f32::consts::SQRT_2;
+/// Q used when the imported filters don't tell it
+const DEFAULT_Q: f32 = std::f32::consts::FRAC_1_SQRT_2;
+
+/// The shape of a band of the parametric equalizer
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FilterType {
+    /// Boosts or cuts the frequencies around the one of the band
+    #[default]
+    Peaking,
+    /// Boosts or cuts the frequencies below the one of the band
+    LowShelf,
+    /// Boosts or cuts the frequencies above the one of the band
+    HighShelf,
+    /// Removes the frequencies above the one of the band, the gain is ignored
+    LowPass,
+    /// Removes the frequencies below the one of the band, the gain is ignored
+    HighPass,
+}
+
+impl FilterType {
+    /// Index used by `Equalizer::set_parameter`
+    pub fn id(self) -> u8 {
+        match self {
+            FilterType::Peaking => 0,
+            FilterType::LowShelf => 1,
+            FilterType::HighShelf => 2,
+            FilterType::LowPass => 3,
+            FilterType::HighPass => 4,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0 => Some(FilterType::Peaking),
+            1 => Some(FilterType::LowShelf),
+            2 => Some(FilterType::HighShelf),
+            3 => Some(FilterType::LowPass),
+            4 => Some(FilterType::HighPass),
+            _ => None,
+        }
+    }
+}
+
+/// A band of the parametric equalizer
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct Band {
+    pub filter: FilterType,
+    /// Center frequency for peaking bands, corner frequency for the others, in Hz
+    pub frequency: f32,
+    /// Gain in dB
+    pub gain: f32,
+    pub q: f32,
+}
+
+impl Default for Band {
+    fn default() -> Self {
+        Self {
+            filter: FilterType::Peaking,
+            frequency: 1000.0,
+            gain: 0.0,
+            q: DEFAULT_Q,
+        }
+    }
+}
+
+/// Everything the `Equalizer` applies, the graphic bands first and then the parametric ones
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct EqSettings {
+    /// Gain in dB applied before the bands, usually negative to leave room for the boosts
+    pub preamp: f32,
+    /// Gains in dB of the bands at `GRAPHIC_FREQUENCIES`
+    pub graphic: [f32; 10],
+    /// Up to `MAX_PARAMETRIC_BANDS` bands, the others are ignored
+    pub parametric: Vec<Band>,
+}
+
+impl EqSettings {
+    /// Reads the parametric equalizer of the text format of EqualizerAPO, also used by AutoEQ
+    ///
+    /// ```text
+    /// Preamp: -6.4 dB
+    /// Filter 1: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+    /// Filter 2: ON PK Fc 1520 Hz Gain -2.1 dB Q 1.41
+    /// ```
+    ///
+    /// Filters that are `OFF` and lines it doesn't know are skipped, it errors if there are more than
+    /// `MAX_PARAMETRIC_BANDS` filters
+    pub fn from_equalizer_apo(text: &str) -> io::Result<Self> {
+        let mut settings = Self::default();
+
+        for line in text.lines().map(str::trim) {
+            if let Some(preamp) = line.strip_prefix("Preamp:") {
+                settings.preamp += parse_value(preamp.trim().trim_end_matches("dB"))?;
+            } else if let Some((_, filter)) = line
+                .strip_prefix("Filter")
+                .and_then(|filter| filter.split_once(':'))
+            {
+                let Some(band) = parse_filter(filter)? else {
+                    continue;
+                };
+                if settings.parametric.len() == MAX_PARAMETRIC_BANDS {
+                    return Err(io::Error::new(
+                        ErrorKind::InvalidData,
+                        format!("more than {MAX_PARAMETRIC_BANDS} filters"),
+                    ));
+                }
+                settings.parametric.push(band);
+            }
+        }
+
+        Ok(settings)
+    }
+}
+
+/// Parses the part of a filter line after `Filter N:`, `None` if it's off or of a type that isn't supported
+fn parse_filter(filter: &str) -> io::Result<Option<Band>> {
+    let mut words = filter.split_whitespace();
+    if words.next() != Some("ON") {
+        return Ok(None);
+    }
+    let filter = match words.next() {
+        Some("PK" | "PEQ") => FilterType::Peaking,
+        Some("LS" | "LSC") => FilterType::LowShelf,
+        Some("HS" | "HSC") => FilterType::HighShelf,
+        Some("LP" | "LPQ") => FilterType::LowPass,
+        Some("HP" | "HPQ") => FilterType::HighPass,
+        _ => return Ok(None),
+    };
+
+    let mut band = Band {
+        filter,
+        ..Band::default()
+    };
+    while let Some(key) = words.next() {
+        let field = match key {
+            "Fc" => &mut band.frequency,
+            "Gain" => &mut band.gain,
+            "Q" => &mut band.q,
+            // Units following the values
+            _ => continue,
+        };
+        let value = words
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::InvalidData, format!("{key} has no value")))?;
+        *field = parse_value(value)?;
+    }
+
+    Ok(Some(band))
+}
+
+fn parse_value(value: &str) -> io::Result<f32> {
+    value
+        .trim()
+        .parse()
+        .map_err(|_| io::Error::new(ErrorKind::InvalidData, format!("{value} is not a number")))
+}
+
+/// Normalized coefficients of a biquad
+#[derive(Copy, Clone, Debug)]
+struct Coefficients {
+    b0: f64,
+    b1: f64,
+    b2: f64,
+    a1: f64,
+    a2: f64,
+}
+
+impl Coefficients {
+    fn new(band: &Band, rate: u32) -> Self {
+        let rate = rate as f64;
+        let frequency = (band.frequency as f64).clamp(10.0, rate * 0.49);
+        let (sin, cos) = (TAU * frequency / rate).sin_cos();
+        let alpha = sin / (2.0 * (band.q as f64).max(0.01));
+        let a = 10f64.powf(band.gain.clamp(-MAX_GAIN, MAX_GAIN) as f64 / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match band.filter {
+            FilterType::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterType::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            FilterType::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+            FilterType::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterType::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+
+        Self {
+            b0: b0 / a0,
+            b1: b1 / a0,
+            b2: b2 / a0,
+            a1: a1 / a0,
+            a2: a2 / a0,
+        }
+    }
+}
+
+/// A biquad in transposed direct form II, with the state of every channel
+#[derive(Clone, Debug)]
+struct Biquad {
+    /// The band it applies: its index among the graphic and the parametric bands, and its type
+    band: (usize, FilterType),
+    coefficients: Coefficients,
+    state: Vec<[f64; 2]>,
+}
+
+impl Biquad {
+    fn process(&mut self, samples: &mut [f32], channels: usize) {
+        let c = self.coefficients;
+        self.state.resize(channels, [0.0; 2]);
+
+        for frame in samples.chunks_exact_mut(channels) {
+            for (sample, state) in frame.iter_mut().zip(&mut self.state) {
+                let input = *sample as f64;
+                let output = c.b0 * input + state[0];
+                state[0] = c.b1 * input - c.a1 * output + state[1];
+                state[1] = c.b2 * input - c.a2 * output;
+                *sample = output as f32;
+            }
+        }
+    }
+}
+
+/// Graphic and parametric equalizer with a pre-amp
+///
+/// Its parameters (see `Dsp::set_parameter`) are:
+/// - `preamp`: gain in dB
+/// - `graphic.N`: gain in dB of the graphic band `N`, from 0 to 9
+/// - `bands`: how many parametric bands there are, the new ones start flat
+/// - `band.N.type`, `band.N.frequency`, `band.N.gain` and `band.N.q`: a parametric band, the type is a
+///   `FilterType::id`
+#[derive(Debug)]
+pub struct Equalizer {
+    settings: EqSettings,
+    rate: u32,
+    /// Filters of the bands that change the sound, rebuilt when `rate` is 0
+    filters: Vec<Biquad>,
+}
+
+impl Equalizer {
+    pub fn new(mut settings: EqSettings) -> Self {
+        settings.parametric.truncate(MAX_PARAMETRIC_BANDS);
+        Self {
+            settings,
+            rate: 0,
+            filters: vec![],
+        }
+    }
+
+    pub fn settings(&self) -> &EqSettings {
+        &self.settings
+    }
+
+    /// Computes the filters for `rate`, keeping the state of the channels when the same bands stay active
+    fn rebuild(&mut self, rate: u32) {
+        let graphic =
+            GRAPHIC_FREQUENCIES
+                .iter()
+                .zip(self.settings.graphic)
+                .map(|(&frequency, gain)| Band {
+                    filter: FilterType::Peaking,
+                    frequency,
+                    gain,
+                    q: GRAPHIC_Q,
+                });
+        let bands = graphic
+            .chain(self.settings.parametric.iter().copied())
+            .enumerate()
+            // Flat peaking and shelving bands don't change anything
+            .filter(|(_, band)| {
+                matches!(band.filter, FilterType::LowPass | FilterType::HighPass)
+                    || band.gain != 0.0
+            })
+            .map(|(index, band)| ((index, band.filter), Coefficients::new(&band, rate)))
+            .collect::<Vec<((usize, FilterType), Coefficients)>>();
+
+        // The state of a filter would be wrong for another band, even if as many bands are active
+        let same_bands = bands.len() == self.filters.len()
+            && self
+                .filters
+                .iter()
+                .zip(&bands)
+                .all(|(filter, (band, _))| filter.band == *band);
+        if same_bands {
+            for (filter, (_, coefficients)) in self.filters.iter_mut().zip(bands) {
+                filter.coefficients = coefficients;
+            }
+        } else {
+            self.filters = bands
+                .into_iter()
+                .map(|(band, coefficients)| Biquad {
+                    band,
+                    coefficients,
+                    state: vec![],
+                })
+                .collect();
+        }
+        self.rate = rate;
+    }
+}
+
+impl Dsp for Equalizer {
+    fn name(&self) -> &str {
+        EQUALIZER
+    }
+
+    fn process(&mut self, samples: &mut [f32], spec: SignalSpec) {
+        if self.rate != spec.rate {
+            self.rebuild(spec.rate);
+        }
+
+        let preamp = 10f32.powf(self.settings.preamp / 20.0);
+        if preamp != 1.0 {
+            samples.iter_mut().for_each(|sample| *sample *= preamp);
+        }
+        let channels = spec.channels.count().max(1);
+        for filter in &mut self.filters {
+            filter.process(samples, channels);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in &mut self.filters {
+            filter.state.clear();
+        }
+    }
+
+    fn set_parameter(&mut self, name: &str, value: f32) {
+        let settings = &mut self.settings;
+        let mut parts = name.split('.');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some("preamp"), None, None) => settings.preamp = value.clamp(-MAX_GAIN, MAX_GAIN),
+            (Some("graphic"), Some(index), None) => {
+                if let Some(gain) = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| settings.graphic.get_mut(index))
+                {
+                    *gain = value.clamp(-MAX_GAIN, MAX_GAIN);
+                }
+            }
+            (Some("bands"), None, None) => settings.parametric.resize(
+                (value.max(0.0) as usize).min(MAX_PARAMETRIC_BANDS),
+                Band::default(),
+            ),
+            (Some("band"), Some(index), Some(parameter)) => {
+                let Some(band) = index
+                    .parse::<usize>()
+                    .ok()
+                    .and_then(|index| settings.parametric.get_mut(index))
+                else {
+                    return;
+                };
+                match parameter {
+                    "type" => {
+                        if let Some(filter) = FilterType::from_id(value as u8) {
+                            band.filter = filter;
+                        }
+                    }
+                    "frequency" => band.frequency = value.max(1.0),
+                    "gain" => band.gain = value.clamp(-MAX_GAIN, MAX_GAIN),
+                    "q" => band.q = value.max(0.01),
+                    _ => return,
+                }
+            }
+            _ => return,
+        }
+        // The filters are computed again with the next buffer
+        self.rate = 0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+    use symphonia::core::audio::Channels;
+
+    const RATE: u32 = 48000;
+
+    fn mono() -> SignalSpec {
+        SignalSpec::new(RATE, Channels::FRONT_LEFT)
+    }
+
+    /// Runs a mono sine of `frequency` through `equalizer`, returning the peak once the filters have settled
+    fn peak(equalizer: &mut Equalizer, frequency: f32) -> f32 {
+        let mut samples: Vec<f32> = (0..RATE)
+            .map(|i| (2.0 * PI * frequency * i as f32 / RATE as f32).sin())
+            .collect();
+        equalizer.process(&mut samples, mono());
+        samples[RATE as usize / 2..]
+            .iter()
+            .fold(0.0, |peak, sample| sample.abs().max(peak))
+    }
+
+    fn db(amplitude: f32) -> f32 {
+        20.0 * amplitude.log10()
+    }
+
+    #[test]
+    fn parses_equalizer_apo() {
+        let text = "\
+            Preamp: -6.4 dB
+            # a comment
+            Filter 1: ON PK Fc 1520 Hz Gain -2.1 dB Q 1.41
+            Filter 2: ON LSC Fc 105 Hz Gain 5.5 dB Q 0.70
+            Filter 3: ON HSC Fc 10000 Hz Gain 3 dB
+            Filter 4: OFF PK Fc 500 Hz Gain 3 dB Q 1
+            Filter 5: ON LP Fc 18000 Hz
+            Filter 6: ON HP Fc 20 Hz Q 0.5
+            Filter 7: ON BP Fc 500 Hz Q 1
+        ";
+        let settings = EqSettings::from_equalizer_apo(text).unwrap();
+
+        assert_eq!(settings.preamp, -6.4);
+        assert_eq!(settings.graphic, [0.0; 10]);
+        let band = |filter, frequency, gain, q| Band {
+            filter,
+            frequency,
+            gain,
+            q,
+        };
+        assert_eq!(
+            settings.parametric,
+            [
+                band(FilterType::Peaking, 1520.0, -2.1, 1.41),
+                band(FilterType::LowShelf, 105.0, 5.5, 0.7),
+                band(FilterType::HighShelf, 10000.0, 3.0, DEFAULT_Q),
+                band(FilterType::LowPass, 18000.0, 0.0, DEFAULT_Q),
+                band(FilterType::HighPass, 20.0, 0.0, 0.5),
+            ]
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_filters() {
+        for text in [
+            "Preamp: loud",
+            "Filter 1: ON PK Fc 1k Hz Gain 3 dB Q 1",
+            "Filter 1: ON PK Fc 1000 Hz Gain",
+        ] {
+            let err = EqSettings::from_equalizer_apo(text).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{text}");
+        }
+
+        let too_many = (0..=MAX_PARAMETRIC_BANDS)
+            .map(|i| format!("Filter {i}: ON PK Fc 1000 Hz Gain 1 dB Q 1\n"))
+            .collect::<String>();
+        assert!(EqSettings::from_equalizer_apo(&too_many).is_err());
+    }
+
+    #[test]
+    fn flat_is_unchanged() {
+        let mut equalizer = Equalizer::new(EqSettings::default());
+        let input: Vec<f32> = (0..1000).map(|i| (i as f32 * 0.1).sin()).collect();
+        let mut samples = input.clone();
+        equalizer.process(&mut samples, mono());
+        assert_eq!(samples, input);
+    }
+
+    #[test]
+    fn peaking_band_gain() {
+        let mut equalizer = Equalizer::new(EqSettings {
+            parametric: vec![Band {
+                filter: FilterType::Peaking,
+                frequency: 1000.0,
+                gain: 6.0,
+                q: 1.0,
+            }],
+            ..EqSettings::default()
+        });
+        assert!((db(peak(&mut equalizer, 1000.0)) - 6.0).abs() < 0.1);
+        // Far from the band it's left as it is
+        assert!(db(peak(&mut equalizer, 50.0)).abs() < 0.1);
+
+        let mut graphic = EqSettings::default();
+        graphic.graphic[5] = -6.0;
+        graphic.preamp = 2.0;
+        let mut equalizer = Equalizer::new(graphic);
+        assert!((db(peak(&mut equalizer, GRAPHIC_FREQUENCIES[5])) + 4.0).abs() < 0.1);
+    }
+
+    #[test]
+    fn resets_the_state_when_the_bands_change() {
+        let mut settings = EqSettings::default();
+        settings.graphic[2] = 3.0;
+        let mut equalizer = Equalizer::new(settings);
+        peak(&mut equalizer, 1000.0);
+        assert!(!equalizer.filters[0].state.is_empty());
+
+        // The same band with another gain keeps its state
+        equalizer.set_parameter("graphic.2", 4.0);
+        equalizer.rebuild(RATE);
+        assert!(!equalizer.filters[0].state.is_empty());
+
+        // As many bands are active, but it's another one
+        equalizer.set_parameter("graphic.2", 0.0);
+        equalizer.set_parameter("graphic.5", 3.0);
+        equalizer.rebuild(RATE);
+        assert_eq!(equalizer.filters.len(), 1);
+        assert!(equalizer.filters[0].state.is_empty());
+    }
+}
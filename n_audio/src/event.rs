@@ -0,0 +1,58 @@
+//! Events sent by a `Player` to anyone listening, so that there's no need to poll it
+
+use crate::{NError, TrackTime};
+use flume::{Receiver, Sender};
+use std::sync::{Arc, Mutex};
+
+/// Something that happened inside a `Player`
+#[derive(Clone, Debug)]
+pub enum PlayerEvent {
+    /// A new track started playing, either because it was played directly or because the preloaded one took over
+    TrackStarted,
+    Paused,
+    Resumed,
+    /// The current track was moved to the given position
+    Seeked(TrackTime),
+    /// The position of the current track, sent periodically while it's playing
+    Position(TrackTime),
+    /// A track was read to its end: its id (see `Player::play_with_gain`) and its exact length in seconds
+    ///
+    /// A crossfaded track is read to its end after the next one started, so it may not be the current one anymore
+    LengthMeasured(u64, f64),
+    /// The current track ended by itself
+    Ended,
+    /// Something went wrong while playing, see `Player::get_errors`
+    Error(Arc<NError>),
+    /// The output device was changed, `None` for the default one
+    DeviceChanged(Option<String>),
+    /// The stream being played announced what it's playing now, see `http_source::HttpSource`
+    StreamTitle(String),
+}
+
+/// Sends every `PlayerEvent` to all its listeners
+///
+/// Every listener gets its own `Receiver`, which can be used both from sync code (`Receiver::recv`) and from async
+/// code (`Receiver::recv_async` or `Receiver::stream`)
+#[derive(Clone, Debug, Default)]
+pub struct EventBus {
+    listeners: Arc<Mutex<Vec<Sender<PlayerEvent>>>>,
+}
+
+impl EventBus {
+    /// Returns a new `Receiver` that will get all the events sent from now on
+    /// Dropping it is enough to stop listening
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        let (tx, rx) = flume::unbounded();
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.push(tx);
+        }
+        rx
+    }
+
+    /// Sends `event` to every listener, forgetting the ones that stopped listening
+    pub(crate) fn emit(&self, event: PlayerEvent) {
+        if let Ok(mut listeners) = self.listeners.lock() {
+            listeners.retain(|tx| tx.send(event.clone()).is_ok());
+        }
+    }
+}
@@ -0,0 +1,484 @@
+//! Files read while they are decoded, instead of being loaded in memory first
+
+use flume::{Receiver, Sender, TryRecvError};
+use std::fs::File;
+use std::io;
+use std::io::{Cursor, ErrorKind, Read, Seek, SeekFrom};
+use std::path::Path;
+use std::thread;
+use symphonia::core::io::MediaSource;
+
+/// Files up to this size are loaded in memory with `SourceMode::Auto`
+const MEMORY_LIMIT: u64 = 4 * 1024 * 1024;
+
+/// How a `MusicTrack` reads its file
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum SourceMode {
+    /// Small files are loaded in memory, the others are streamed
+    #[default]
+    Auto,
+    /// The whole file is loaded in memory before decoding it
+    Memory,
+    /// The file is read while it's decoded, a few chunks ahead
+    Stream,
+    /// The file is read only when it's needed, without any thread, which suits short reads (e.g. the metadata)
+    Direct,
+}
+
+/// Opens the file at `path` as a Symphonia `MediaSource`
+pub fn open<P: AsRef<Path>>(path: P, mode: SourceMode) -> io::Result<Box<dyn MediaSource>> {
+    let file = File::open(path)?;
+    if mode == SourceMode::Direct {
+        return Ok(Box::new(file));
+    }
+    let len = file.metadata()?.len();
+
+    let in_memory = match mode {
+        SourceMode::Auto => len <= MEMORY_LIMIT,
+        SourceMode::Memory => true,
+        SourceMode::Stream | SourceMode::Direct => false,
+    };
+
+    if in_memory {
+        let mut data = Vec::with_capacity(len as usize);
+        (&file).read_to_end(&mut data)?;
+        Ok(Box::new(Cursor::new(data)))
+    } else {
+        Ok(Box::new(FileSource::new(file)?))
+    }
+}
+
+/// Where a `ReadAhead` gets its bytes from
+pub(crate) trait Fetch: Send + 'static {
+    /// Bytes read at once
+    const CHUNK_SIZE: usize;
+    /// Chunks read before they are needed
+    const READ_AHEAD: usize;
+
+    /// Makes the next reads start from `offset`
+    fn start(&mut self, offset: u64) -> io::Result<()>;
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+impl Fetch for File {
+    const CHUNK_SIZE: usize = 256 * 1024;
+    const READ_AHEAD: usize = 8;
+
+    fn start(&mut self, offset: u64) -> io::Result<()> {
+        self.seek(SeekFrom::Start(offset)).map(|_| ())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        Read::read(self, buf)
+    }
+}
+
+struct Chunk {
+    generation: u64,
+    offset: u64,
+    /// Empty at the end of the file
+    data: io::Result<Vec<u8>>,
+}
+
+/// A `MediaSource` read by a background thread ahead of the current position
+///
+/// Every seek outside the chunks already requested starts a new generation, the chunks of the previous ones are
+/// thrown away
+pub(crate) struct ReadAhead {
+    len: Option<u64>,
+    seekable: bool,
+    /// Bytes that the thread reads ahead of the current chunk
+    ahead: u64,
+    pos: u64,
+    generation: u64,
+    chunk: Vec<u8>,
+    chunk_offset: u64,
+    /// The thread reached the end and waits for another request
+    ended: bool,
+    /// The thread stopped after this error and won't be asked again, as the source can't be seeked
+    error: Option<(ErrorKind, String)>,
+    requests: Sender<(u64, u64)>,
+    chunks: Receiver<Chunk>,
+}
+
+impl ReadAhead {
+    /// Starts reading `fetch` from its beginning, `len` is its size in bytes if it's known
+    pub(crate) fn new<F: Fetch>(fetch: F, len: Option<u64>, seekable: bool) -> io::Result<Self> {
+        let (requests, rx_requests) = flume::unbounded();
+        let (tx_chunks, chunks) = flume::bounded(F::READ_AHEAD);
+
+        thread::Builder::new()
+            .name(String::from("n_audio read-ahead"))
+            .spawn(move || read_ahead(fetch, rx_requests, tx_chunks))?;
+
+        Ok(Self {
+            len,
+            seekable,
+            ahead: (F::CHUNK_SIZE * F::READ_AHEAD) as u64,
+            pos: 0,
+            generation: 0,
+            chunk: vec![],
+            chunk_offset: 0,
+            ended: false,
+            error: None,
+            requests,
+            chunks,
+        })
+    }
+
+    /// Asks the thread to read from `offset`
+    fn restart(&mut self, offset: u64) {
+        self.generation += 1;
+        self.chunk.clear();
+        self.chunk_offset = offset;
+        self.ended = false;
+        self.error = None;
+        let _ = self.requests.send((self.generation, offset));
+    }
+
+    /// Waits for the chunk containing the current position, returns `false` at the end of the file
+    fn fetch(&mut self) -> io::Result<bool> {
+        // Nothing else would be sent by the thread
+        if self.ended {
+            return Ok(false);
+        }
+        if let Some((kind, error)) = &self.error {
+            return Err(io::Error::new(*kind, error.clone()));
+        }
+
+        loop {
+            let chunk = self
+                .chunks
+                .recv()
+                .map_err(|_| io::Error::other("read-ahead thread stopped"))?;
+            if chunk.generation != self.generation {
+                continue;
+            }
+
+            let data = match chunk.data {
+                Ok(data) => data,
+                Err(e) => {
+                    // The thread stops after an error, so it has to be asked again
+                    if self.seekable {
+                        self.restart(self.pos);
+                    } else {
+                        self.error = Some((e.kind(), e.to_string()));
+                    }
+                    return Err(e);
+                }
+            };
+            if data.is_empty() {
+                self.ended = true;
+                return Ok(false);
+            }
+
+            self.chunk_offset = chunk.offset;
+            self.chunk = data;
+            if self.pos < self.chunk_offset + self.chunk.len() as u64 {
+                return Ok(true);
+            }
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.len.is_some_and(|len| self.pos >= len) {
+            return Ok(0);
+        }
+
+        let chunk_end = self.chunk_offset + self.chunk.len() as u64;
+        if !(self.chunk_offset..chunk_end).contains(&self.pos) && !self.fetch()? {
+            return Ok(0);
+        }
+
+        let start = (self.pos - self.chunk_offset) as usize;
+        let len = buf.len().min(self.chunk.len() - start);
+        buf[..len].copy_from_slice(&self.chunk[start..start + len]);
+        self.pos += len as u64;
+
+        Ok(len)
+    }
+}
+
+impl Seek for ReadAhead {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(pos) => pos as i128,
+            SeekFrom::End(delta) => {
+                let len = self.len.ok_or_else(|| {
+                    io::Error::new(ErrorKind::Unsupported, "the length isn't known")
+                })?;
+                len as i128 + delta as i128
+            }
+            SeekFrom::Current(delta) => self.pos as i128 + delta as i128,
+        };
+        let pos = u64::try_from(pos).map_err(|_| {
+            io::Error::new(ErrorKind::InvalidInput, "seek before the start of the file")
+        })?;
+
+        // Going a bit forward is cheaper by skipping the chunks already on their way
+        let chunk_end = self.chunk_offset + self.chunk.len() as u64;
+        let requested_end = chunk_end + self.ahead;
+        if pos < self.chunk_offset || pos >= requested_end {
+            if !self.seekable {
+                return Err(io::Error::new(
+                    ErrorKind::Unsupported,
+                    "the source can't be seeked",
+                ));
+            }
+            self.restart(pos);
+        }
+        self.pos = pos;
+
+        Ok(pos)
+    }
+}
+
+impl MediaSource for ReadAhead {
+    fn is_seekable(&self) -> bool {
+        self.seekable
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.len
+    }
+}
+
+/// A seekable file read ahead of the current position
+pub struct FileSource(ReadAhead);
+
+impl FileSource {
+    pub fn new(file: File) -> io::Result<Self> {
+        let len = file.metadata()?.len();
+        Ok(Self(ReadAhead::new(file, Some(len), true)?))
+    }
+}
+
+impl Read for FileSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Seek for FileSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.seek(pos)
+    }
+}
+
+impl MediaSource for FileSource {
+    fn is_seekable(&self) -> bool {
+        self.0.is_seekable()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.0.byte_len()
+    }
+}
+
+/// Reads `fetch` chunk by chunk, starting again from the offset of every request
+///
+/// It stops when the `ReadAhead` is dropped
+fn read_ahead<F: Fetch>(mut fetch: F, requests: Receiver<(u64, u64)>, chunks: Sender<Chunk>) {
+    let mut generation = 0;
+    let mut offset = 0;
+    let mut done = false;
+
+    loop {
+        let request = if done {
+            match requests.recv() {
+                Ok(request) => Some(request),
+                Err(_) => return,
+            }
+        } else {
+            match requests.try_recv() {
+                Ok(request) => Some(request),
+                Err(TryRecvError::Empty) => None,
+                Err(TryRecvError::Disconnected) => return,
+            }
+        };
+        // Only the latest request matters
+        if let Some(request) = request.map(|request| requests.drain().last().unwrap_or(request)) {
+            (generation, offset) = request;
+            if let Err(e) = fetch.start(offset) {
+                done = true;
+                let chunk = Chunk {
+                    generation,
+                    offset,
+                    data: Err(e),
+                };
+                if chunks.send(chunk).is_err() {
+                    return;
+                }
+                continue;
+            }
+        }
+
+        let data = read_chunk(&mut fetch);
+        done = data.as_ref().map_or(true, Vec::is_empty);
+        let len = data.as_ref().map_or(0, Vec::len);
+        let chunk = Chunk {
+            generation,
+            offset,
+            data,
+        };
+        if chunks.send(chunk).is_err() {
+            return;
+        }
+        offset += len as u64;
+    }
+}
+
+/// Reads up to `F::CHUNK_SIZE` bytes, less only at the end
+fn read_chunk<F: Fetch>(fetch: &mut F) -> io::Result<Vec<u8>> {
+    let mut data = vec![0; F::CHUNK_SIZE];
+    let mut filled = 0;
+
+    while filled < F::CHUNK_SIZE {
+        match fetch.read(&mut data[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    data.truncate(filled);
+
+    Ok(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes in memory, failing once when the read reaches `fail_at`
+    struct Bytes {
+        data: Vec<u8>,
+        pos: usize,
+        fail_at: Option<usize>,
+    }
+
+    impl Fetch for Bytes {
+        const CHUNK_SIZE: usize = 4;
+        const READ_AHEAD: usize = 2;
+
+        fn start(&mut self, offset: u64) -> io::Result<()> {
+            self.pos = offset as usize;
+            Ok(())
+        }
+
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.fail_at == Some(self.pos) {
+                self.fail_at = None;
+                return Err(io::Error::other("broken"));
+            }
+            let end = self
+                .fail_at
+                .filter(|&fail_at| fail_at > self.pos)
+                .unwrap_or(usize::MAX);
+            let len = buf
+                .len()
+                .min(self.data.len().saturating_sub(self.pos))
+                .min(end - self.pos);
+            buf[..len].copy_from_slice(&self.data[self.pos..self.pos + len]);
+            self.pos += len;
+            Ok(len)
+        }
+    }
+
+    fn data() -> Vec<u8> {
+        (0..64).collect()
+    }
+
+    fn read_ahead(fail_at: Option<usize>, len: Option<u64>, seekable: bool) -> ReadAhead {
+        let fetch = Bytes {
+            data: data(),
+            pos: 0,
+            fail_at,
+        };
+        ReadAhead::new(fetch, len, seekable).unwrap()
+    }
+
+    fn read_exact(source: &mut ReadAhead, len: usize) -> Vec<u8> {
+        let mut buf = vec![0; len];
+        source.read_exact(&mut buf).unwrap();
+        buf
+    }
+
+    #[test]
+    fn reads_to_the_end() {
+        for len in [Some(64), None] {
+            let mut source = read_ahead(None, len, false);
+            let mut read = vec![];
+            source.read_to_end(&mut read).unwrap();
+            assert_eq!(read, data());
+            // The thread is done, the end is reported again without waiting for it
+            assert_eq!(source.read(&mut [0; 4]).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn seeks_inside_the_read_ahead() {
+        let mut source = read_ahead(None, Some(64), true);
+        assert_eq!(read_exact(&mut source, 2), [0, 1]);
+
+        // The next chunks were already requested
+        assert_eq!(source.seek(SeekFrom::Current(7)).unwrap(), 9);
+        assert_eq!(source.generation, 0);
+        assert_eq!(read_exact(&mut source, 3), [9, 10, 11]);
+
+        // Back inside the current chunk
+        assert_eq!(source.seek(SeekFrom::Start(8)).unwrap(), 8);
+        assert_eq!(source.generation, 0);
+        assert_eq!(read_exact(&mut source, 2), [8, 9]);
+    }
+
+    #[test]
+    fn seeks_outside_the_read_ahead() {
+        let mut source = read_ahead(None, Some(64), true);
+        assert_eq!(read_exact(&mut source, 2), [0, 1]);
+
+        assert_eq!(source.seek(SeekFrom::Start(40)).unwrap(), 40);
+        assert_eq!(source.generation, 1);
+        assert_eq!(read_exact(&mut source, 3), [40, 41, 42]);
+
+        assert_eq!(source.seek(SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(source.generation, 2);
+        assert_eq!(read_exact(&mut source, 2), [1, 2]);
+
+        assert_eq!(source.seek(SeekFrom::End(-1)).unwrap(), 63);
+        let mut read = vec![];
+        source.read_to_end(&mut read).unwrap();
+        assert_eq!(read, [63]);
+
+        let mut stream = read_ahead(None, None, false);
+        assert!(stream.seek(SeekFrom::Start(40)).is_err());
+        assert!(stream.seek(SeekFrom::End(0)).is_err());
+    }
+
+    #[test]
+    fn reports_errors() {
+        // A stream can't be asked again, the error sticks
+        let mut stream = read_ahead(Some(6), None, false);
+        assert_eq!(read_exact(&mut stream, 4), [0, 1, 2, 3]);
+        let mut buf = [0; 4];
+        for _ in 0..3 {
+            assert_eq!(stream.read(&mut buf).unwrap_err().to_string(), "broken");
+        }
+
+        // A file is read again from where it failed
+        let mut file = read_ahead(Some(6), Some(64), true);
+        let mut read = vec![];
+        let mut buf = [0; 4];
+        loop {
+            match file.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => read.extend_from_slice(&buf[..len]),
+                Err(e) => assert_eq!(e.to_string(), "broken"),
+            }
+        }
+        assert_eq!(read, data());
+    }
+}
@@ -0,0 +1,337 @@
+//! HTTP(S) streams, including Icecast/SHOUTcast radios announcing their titles with ICY metadata
+
+use crate::event::{EventBus, PlayerEvent};
+use crate::file_source::{Fetch, ReadAhead};
+use std::io;
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::time::Duration;
+use symphonia::core::io::MediaSource;
+use ureq::{Agent, AgentBuilder};
+
+/// How long to wait for the server before giving up
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Returns whether `path` is an HTTP(S) URL instead of a local path
+pub fn is_url(path: &str) -> bool {
+    let scheme = path.split_once("://").map(|(scheme, _)| scheme);
+    matches!(scheme, Some(scheme) if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https"))
+}
+
+/// A remote file or a live stream, buffered by a background thread
+///
+/// Files served with `Accept-Ranges: bytes` and a known length are seekable through range requests, live streams
+/// aren't
+pub struct HttpSource {
+    inner: ReadAhead,
+    name: Option<String>,
+    content_type: Option<String>,
+}
+
+impl HttpSource {
+    /// Connects to `url`, the titles announced by the stream are sent to `events` as `PlayerEvent::StreamTitle`
+    pub fn open(url: &str, events: Option<EventBus>) -> io::Result<Self> {
+        let agent = AgentBuilder::new()
+            .timeout_connect(TIMEOUT)
+            .timeout_read(TIMEOUT)
+            .user_agent(concat!(
+                env!("CARGO_PKG_NAME"),
+                "/",
+                env!("CARGO_PKG_VERSION")
+            ))
+            .build();
+        let response = agent
+            .get(url)
+            .set("Icy-MetaData", "1")
+            .call()
+            .map_err(io::Error::other)?;
+
+        let metaint = response
+            .header("icy-metaint")
+            .and_then(|metaint| metaint.trim().parse::<usize>().ok())
+            .filter(|&metaint| metaint > 0);
+        let len = response
+            .header("content-length")
+            .and_then(|len| len.trim().parse::<u64>().ok());
+        let seekable = metaint.is_none()
+            && len.is_some()
+            && response
+                .header("accept-ranges")
+                .is_some_and(|ranges| ranges.trim().eq_ignore_ascii_case("bytes"));
+        let name = response
+            .header("icy-name")
+            .map(str::trim)
+            .filter(|name| !name.is_empty())
+            .map(String::from);
+        let content_type = Some(response.content_type())
+            .filter(|content_type| !content_type.is_empty())
+            .map(String::from);
+
+        let fetch = HttpFetch {
+            agent,
+            url: url.to_string(),
+            reader: Some(response.into_reader()),
+            position: 0,
+            metaint,
+            until_meta: metaint.unwrap_or(0),
+            title: None,
+            events,
+        };
+
+        Ok(Self {
+            inner: ReadAhead::new(fetch, if seekable { len } else { None }, seekable)?,
+            name,
+            content_type,
+        })
+    }
+
+    /// Name of the station, sent by Icecast/SHOUTcast servers
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
+    /// MIME type of the stream, useful as a hint for the probe
+    pub fn content_type(&self) -> Option<&str> {
+        self.content_type.as_deref()
+    }
+}
+
+impl Read for HttpSource {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+impl Seek for HttpSource {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl MediaSource for HttpSource {
+    fn is_seekable(&self) -> bool {
+        self.inner.is_seekable()
+    }
+
+    fn byte_len(&self) -> Option<u64> {
+        self.inner.byte_len()
+    }
+}
+
+struct HttpFetch {
+    agent: Agent,
+    url: String,
+    /// Body of the last response
+    reader: Option<Box<dyn Read + Send + Sync>>,
+    /// Offset of the next byte of `reader`, not counting the ICY metadata
+    position: u64,
+    /// Audio bytes between two ICY metadata blocks
+    metaint: Option<usize>,
+    /// Audio bytes left before the next ICY metadata block
+    until_meta: usize,
+    title: Option<String>,
+    events: Option<EventBus>,
+}
+
+impl HttpFetch {
+    /// Reads an ICY metadata block, announcing the title if it changed
+    ///
+    /// Returns `false` if the stream ended right before it
+    fn read_metadata(&mut self) -> io::Result<bool> {
+        let reader = self
+            .reader
+            .as_mut()
+            .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+
+        let mut len = [0];
+        if reader.read(&mut len)? == 0 {
+            return Ok(false);
+        }
+        let mut block = vec![0; len[0] as usize * 16];
+        reader.read_exact(&mut block)?;
+
+        let block = String::from_utf8_lossy(&block);
+        let title = stream_title(block.trim_end_matches('\0'));
+        if let Some(title) = title.filter(|title| self.title.as_deref() != Some(title)) {
+            self.title = Some(title.to_string());
+            if let Some(events) = &self.events {
+                events.emit(PlayerEvent::StreamTitle(title.to_string()));
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Fetch for HttpFetch {
+    // Small chunks so that live streams start quickly, with enough of them to ride out network hiccups
+    const CHUNK_SIZE: usize = 16 * 1024;
+    const READ_AHEAD: usize = 64;
+
+    fn start(&mut self, offset: u64) -> io::Result<()> {
+        if offset == self.position && self.reader.is_some() {
+            return Ok(());
+        }
+
+        let response = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={offset}-"))
+            .call()
+            .map_err(io::Error::other)?;
+        let ignored_range = response.status() == 200;
+        let mut reader = response.into_reader();
+        // Servers ignoring the range send the whole file again
+        if offset > 0 && ignored_range {
+            io::copy(&mut (&mut reader).take(offset), &mut io::sink())?;
+        }
+        self.reader = Some(reader);
+        self.position = offset;
+        self.until_meta = self.metaint.unwrap_or(0);
+
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let Some(metaint) = self.metaint else {
+            let reader = self
+                .reader
+                .as_mut()
+                .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+            let read = reader.read(buf)?;
+            self.position += read as u64;
+            return Ok(read);
+        };
+
+        if self.until_meta == 0 {
+            if !self.read_metadata()? {
+                return Ok(0);
+            }
+            self.until_meta = metaint;
+        }
+
+        let len = buf.len().min(self.until_meta);
+        let reader = self
+            .reader
+            .as_mut()
+            .ok_or_else(|| io::Error::from(ErrorKind::NotConnected))?;
+        let read = reader.read(&mut buf[..len])?;
+        self.until_meta -= read;
+        self.position += read as u64;
+
+        Ok(read)
+    }
+}
+
+/// Returns the `StreamTitle` of an ICY metadata block, e.g. `StreamTitle='Artist - Title';StreamUrl='';`
+fn stream_title(block: &str) -> Option<&str> {
+    const KEY: &str = "StreamTitle='";
+
+    let start = block.find(KEY)? + KEY.len();
+    let title = &block[start..];
+    // Titles may contain `';` too, the one closing the title is followed by the next field or by nothing
+    let end = title.match_indices("';").map(|(end, _)| end).find(|&end| {
+        let rest = &title[end + 2..];
+        rest.is_empty()
+            || rest.split_once("='").is_some_and(|(key, _)| {
+                !key.is_empty() && key.chars().all(|c| c.is_ascii_alphanumeric())
+            })
+    });
+    let title = match end {
+        Some(end) => &title[..end],
+        None => title.trim_end_matches(';').trim_end_matches('\''),
+    };
+
+    Some(title.trim()).filter(|title| !title.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn parses_the_stream_title() {
+        assert_eq!(
+            stream_title("StreamTitle='Artist - Title';StreamUrl='';"),
+            Some("Artist - Title")
+        );
+        assert_eq!(
+            stream_title("StreamTitle='Rock';n'Roll';StreamUrl='http://example.com';"),
+            Some("Rock';n'Roll")
+        );
+        assert_eq!(
+            stream_title("StreamTitle='Guns';n'Roses';"),
+            Some("Guns';n'Roses")
+        );
+        assert_eq!(
+            stream_title("StreamTitle='Unterminated"),
+            Some("Unterminated")
+        );
+        assert_eq!(stream_title("StreamTitle='Quoted'"), Some("Quoted"));
+        assert_eq!(stream_title("StreamTitle='';StreamUrl='';"), None);
+        assert_eq!(stream_title("StreamTitle=' ';"), None);
+        assert_eq!(stream_title("StreamUrl='http://example.com';"), None);
+    }
+
+    /// An ICY metadata block announcing `title`, or an empty one
+    fn metadata(title: Option<&str>) -> Vec<u8> {
+        let Some(title) = title else {
+            return vec![0];
+        };
+        let mut block = format!("StreamTitle='{title}';").into_bytes();
+        block.resize(block.len().div_ceil(16) * 16, 0);
+        let mut metadata = vec![(block.len() / 16) as u8];
+        metadata.extend(block);
+        metadata
+    }
+
+    #[test]
+    fn strips_the_metadata() {
+        const METAINT: usize = 8;
+        let audio = (0..40).collect::<Vec<u8>>();
+        let titles = [Some("A"), None, Some("A"), Some("B")];
+
+        let mut stream = vec![];
+        for (i, chunk) in audio.chunks(METAINT).enumerate() {
+            stream.extend_from_slice(chunk);
+            if let Some(title) = titles.get(i) {
+                stream.extend(metadata(*title));
+            }
+        }
+
+        let events = EventBus::default();
+        let rx = events.subscribe();
+        let mut fetch = HttpFetch {
+            agent: AgentBuilder::new().build(),
+            url: String::new(),
+            reader: Some(Box::new(Cursor::new(stream))),
+            position: 0,
+            metaint: Some(METAINT),
+            until_meta: METAINT,
+            title: None,
+            events: Some(events),
+        };
+
+        // Reads that don't line up with the metadata blocks
+        let mut read = vec![];
+        let mut buf = [0; 5];
+        loop {
+            let len = fetch.read(&mut buf).unwrap();
+            if len == 0 {
+                break;
+            }
+            read.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(read, audio);
+        assert_eq!(fetch.position, audio.len() as u64);
+
+        let titles = rx
+            .try_iter()
+            .filter_map(|event| match event {
+                PlayerEvent::StreamTitle(title) => Some(title),
+                _ => None,
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(titles, ["A", "B"]);
+    }
+}
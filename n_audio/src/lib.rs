@@ -1,21 +1,49 @@
+use flume::{SendError, Sender};
+use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use std::{fmt, io};
 use symphonia::core::codecs::CodecRegistry;
+use symphonia::core::errors::Error as SymphoniaError;
+use symphonia::core::formats::FormatReader;
 
 use crate::dca::DcaReader;
+use crate::dsp::Dsp;
 use crate::opus::OpusDecoder;
 use crate::raw::RawReader;
+use crate::replay_gain::{ReplayGain, ReplayGainMode};
+use crate::resampler::ResamplerQuality;
+use crate::time_stretch::TimeStretchMode;
 use once_cell::sync::Lazy;
 use symphonia::core::units::Time;
 use symphonia::default::{register_enabled_codecs, register_enabled_formats};
 use symphonia_core::probe::Probe;
 
 mod dca;
+pub mod dca_writer;
+pub mod device;
+pub mod dsp;
+mod duration;
+mod engine;
+pub mod equalizer;
+pub mod event;
+pub mod file_source;
+pub mod http_source;
+pub mod loudness;
 pub mod music_track;
 mod opus;
-mod output;
+pub mod output;
 pub mod player;
 pub mod queue;
 mod raw;
+pub mod raw_writer;
+pub mod render;
+pub mod replay_gain;
+pub mod resampler;
+pub mod time_stretch;
+pub mod visualization;
 
 /// Default Symphonia [`CodecRegistry`], including the (audiopus-backed) Opus codec.
 pub static CODEC_REGISTRY: Lazy<CodecRegistry> = Lazy::new(|| {
@@ -33,9 +61,68 @@ pub static PROBE: Lazy<Probe> = Lazy::new(|| {
     probe
 });
 
+/// Errors returned by `n_audio`
 #[derive(Debug)]
 pub enum NError {
+    /// The file isn't in any of the supported formats
+    UnsupportedFormat,
+    /// The format doesn't contain any playable track
     NoTrack,
+    /// The codec of the track isn't supported
+    UnsupportedCodec,
+    /// The track is corrupted or it couldn't be decoded
+    Decode(SymphoniaError),
+    /// The output device can't be opened or it stopped working
+    Device(String),
+    Io(io::Error),
+    /// The playback thread isn't running anymore
+    Disconnected,
+}
+
+impl Display for NError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            NError::UnsupportedFormat => write!(f, "unsupported format"),
+            NError::NoTrack => write!(f, "no playable track found"),
+            NError::UnsupportedCodec => write!(f, "unsupported codec"),
+            NError::Decode(err) => write!(f, "decode error: {err}"),
+            NError::Device(err) => write!(f, "audio device error: {err}"),
+            NError::Io(err) => write!(f, "I/O error: {err}"),
+            NError::Disconnected => write!(f, "the playback thread is not running"),
+        }
+    }
+}
+
+impl Error for NError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            NError::Decode(err) => Some(err),
+            NError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for NError {
+    fn from(value: io::Error) -> Self {
+        NError::Io(value)
+    }
+}
+
+impl From<SymphoniaError> for NError {
+    fn from(value: SymphoniaError) -> Self {
+        match value {
+            SymphoniaError::IoError(err) => NError::Io(err),
+            SymphoniaError::Unsupported(_) => NError::UnsupportedFormat,
+            err => NError::Decode(err),
+        }
+    }
+}
+
+impl<T> From<SendError<T>> for NError {
+    fn from(_: SendError<T>) -> Self {
+        NError::Disconnected
+    }
 }
 
 /// Messages sent inside the `Player`
@@ -48,6 +135,43 @@ pub enum Message {
     Time(TrackTime),
     Volume(f32),
     PlaybackSpeed(f32),
+    Crossfade(Crossfade),
+    ReplayGainMode(ReplayGainMode),
+    /// Switches the output device, `None` for the default one
+    Device(Option<String>),
+    ResamplerQuality(ResamplerQuality),
+    TimeStretchMode(TimeStretchMode),
+    /// How often `PlayerEvent::Position` gets sent while playing
+    PositionInterval(Duration),
+    /// Inserts a processor in the DSP chain at the given position, see `DspChain::insert`
+    DspInsert(usize, Box<dyn Dsp>),
+    /// Removes the processor of the DSP chain with the given name
+    DspRemove(String),
+    /// Sets a parameter of a processor of the DSP chain: the name of the processor, of the parameter and its value
+    DspParameter(String, String, f32),
+    /// Skips a processor of the DSP chain, or runs it again
+    DspBypass(String, bool),
+    /// Asks for the names of the processors of the DSP chain, in the order they are run
+    DspNames(Sender<Vec<String>>),
+    /// Feeds a new track to the audio engine thread, replacing the current one
+    /// The id is the one returned by `Player::play_with_gain`, the senders are used to report `Time`, `End` and
+    /// `Advanced` about this track
+    Load(
+        u64,
+        Box<dyn FormatReader>,
+        ReplayGain,
+        Sender<Message>,
+        Sender<Message>,
+        Sender<Message>,
+    ),
+    /// Queues a track to be played right after the current one, without closing the output
+    /// The flag tells whether the two tracks may be crossfaded
+    Preload(u64, Box<dyn FormatReader>, ReplayGain, bool),
+    ClearPreload,
+    /// Sent by the track thread when it switched to the preloaded track
+    Advanced,
+    /// Sent by the track thread when something went wrong, the playback carries on with the next track if possible
+    Error(Arc<NError>),
 }
 
 /// Returns the file name without its extension
@@ -87,10 +211,13 @@ pub fn strip_absolute_path(path: String) -> String {
 ///
 /// pos_* is used to represent the *current* timestamp (as in where is currently the player playing inside the track)
 /// len_* is used to represent the *entire* timestamp (as is how long is the track)
+///
+/// The length is `None` when the track doesn't tell how long it is, like live streams, pipes and raw PCM without
+/// metadata
 #[derive(Copy, Clone, Debug, Default, PartialEq)]
 pub struct TrackTime {
     pub position: f64,
-    pub length: f64,
+    pub length: Option<f64>,
 }
 
 impl TrackTime {
@@ -102,12 +229,21 @@ impl TrackTime {
         )
     }
 
+    /// Returns `--:--` if the length isn't known
     pub fn format_len(&self) -> String {
-        format!(
-            "{:02}:{:02}",
-            (self.length / 60.0).floor() as u64,
-            self.length.floor() as u64 % 60
-        )
+        match self.length {
+            Some(length) => format!(
+                "{:02}:{:02}",
+                (length / 60.0).floor() as u64,
+                length.floor() as u64 % 60
+            ),
+            None => String::from("--:--"),
+        }
+    }
+
+    /// Returns whether the track can be seeked, which needs its length to be known
+    pub fn is_seekable(&self) -> bool {
+        self.length.is_some()
     }
 }
 
@@ -116,4 +252,69 @@ pub struct Metadata {
     pub time: TrackTime,
     pub artist: String,
     pub title: String,
+    pub album: String,
+}
+
+/// The curve used to fade out the outgoing track and fade in the next one
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum CrossfadeCurve {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl CrossfadeCurve {
+    /// Returns the gains of the outgoing and of the incoming track at the given progress (from 0.0 to 1.0)
+    pub fn gains(&self, progress: f32) -> (f32, f32) {
+        let progress = progress.clamp(0.0, 1.0);
+        match self {
+            CrossfadeCurve::Linear => (1.0 - progress, progress),
+            CrossfadeCurve::EqualPower => (
+                (progress * std::f32::consts::FRAC_PI_2).cos(),
+                (progress * std::f32::consts::FRAC_PI_2).sin(),
+            ),
+        }
+    }
+}
+
+/// How the last seconds of a track are mixed with the first seconds of the next one
+///
+/// A `duration` of 0 seconds disables crossfading
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Crossfade {
+    pub duration: f32,
+    pub curve: CrossfadeCurve,
+}
+
+impl Crossfade {
+    pub fn is_enabled(&self) -> bool {
+        self.duration > 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn crossfade_gains() {
+        assert_eq!(CrossfadeCurve::Linear.gains(0.0), (1.0, 0.0));
+        assert_eq!(CrossfadeCurve::Linear.gains(0.25), (0.75, 0.25));
+        assert_eq!(CrossfadeCurve::Linear.gains(1.0), (0.0, 1.0));
+
+        let (outgoing, incoming) = CrossfadeCurve::EqualPower.gains(0.0);
+        assert!((outgoing - 1.0).abs() < 1e-6 && incoming.abs() < 1e-6);
+        let (outgoing, incoming) = CrossfadeCurve::EqualPower.gains(1.0);
+        assert!(outgoing.abs() < 1e-6 && (incoming - 1.0).abs() < 1e-6);
+        for progress in [0.1, 0.5, 0.9] {
+            let (outgoing, incoming) = CrossfadeCurve::EqualPower.gains(progress);
+            assert!((outgoing * outgoing + incoming * incoming - 1.0).abs() < 1e-6);
+        }
+
+        // The progress is clamped
+        for curve in [CrossfadeCurve::Linear, CrossfadeCurve::EqualPower] {
+            assert_eq!(curve.gains(-1.0), curve.gains(0.0));
+            assert_eq!(curve.gains(2.0), curve.gains(1.0));
+        }
+    }
 }
@@ -0,0 +1,529 @@
+//! EBU R128 loudness analysis and ReplayGain tagging
+
+use crate::music_track::MusicTrack;
+use crate::{NError, CODEC_REGISTRY};
+use id3::TagLike;
+use multitag::Tag;
+use std::f64::consts::PI;
+use std::ffi::OsStr;
+use std::io;
+use std::io::ErrorKind;
+use std::path::Path;
+use symphonia::core::audio::{AudioBuffer, AudioBufferRef, Channels, Signal};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphError;
+
+/// ReplayGain 2.0 reference level, in LUFS
+pub const REPLAY_GAIN_REFERENCE: f64 = -18.0;
+/// EBU R128 reference level (used by Opus R128 tags), in LUFS
+pub const R128_REFERENCE: f64 = -23.0;
+
+const ABSOLUTE_GATE: f64 = -70.0;
+const INTEGRATED_RELATIVE_GATE: f64 = -10.0;
+const RANGE_RELATIVE_GATE: f64 = -20.0;
+/// Momentary blocks are 400ms long, made of 4 steps of 100ms
+const MOMENTARY_STEPS: usize = 4;
+/// Short-term blocks are 3s long, made of 30 steps of 100ms
+const SHORT_TERM_STEPS: usize = 30;
+
+const OVERSAMPLING: usize = 4;
+const TAPS_PER_PHASE: usize = 12;
+
+/// Result of the analysis of a track (or of a whole album)
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    pub integrated: f64,
+    /// Loudness range, in LU
+    pub range: f64,
+    /// True peak, linear (1.0 is full scale)
+    pub true_peak: f64,
+}
+
+impl Loudness {
+    /// Computes the loudness of a whole album, gating all the tracks together as required by EBU R128
+    pub fn album(meters: &[LoudnessMeter]) -> Self {
+        let mut momentary = vec![];
+        let mut short_term = vec![];
+        let mut true_peak = 0.0f64;
+
+        for meter in meters {
+            momentary.append(&mut meter.block_powers(MOMENTARY_STEPS));
+            short_term.append(&mut meter.block_powers(SHORT_TERM_STEPS));
+            true_peak = true_peak.max(meter.true_peak);
+        }
+
+        Self {
+            integrated: integrated_loudness(&momentary),
+            range: loudness_range(&short_term),
+            true_peak,
+        }
+    }
+
+    /// Gain (in dB) needed to reach the ReplayGain reference level
+    pub fn replay_gain(&self) -> f64 {
+        REPLAY_GAIN_REFERENCE - self.integrated
+    }
+
+    /// Gain (in dB) needed to reach the EBU R128 reference level
+    pub fn r128_gain(&self) -> f64 {
+        R128_REFERENCE - self.integrated
+    }
+}
+
+/// Biquad filter in direct form II transposed
+#[derive(Copy, Clone, Debug, Default)]
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 3],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[1] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[2] * y;
+        y
+    }
+}
+
+/// Per channel state of the meter
+#[derive(Clone, Debug)]
+struct ChannelState {
+    weight: f64,
+    shelf: Biquad,
+    high_pass: Biquad,
+    history: Vec<f64>,
+}
+
+/// Measures the loudness of a stream of audio buffers as described in ITU-R BS.1770-4 and EBU Tech 3342
+#[derive(Clone, Debug)]
+pub struct LoudnessMeter {
+    channels: Vec<ChannelState>,
+    interpolation: Vec<f64>,
+    step_len: usize,
+    step_pos: usize,
+    step_sum: f64,
+    steps: Vec<f64>,
+    true_peak: f64,
+}
+
+impl LoudnessMeter {
+    pub fn new(rate: u32, channels: Channels) -> Self {
+        let (shelf, high_pass) = k_weighting(rate as f64);
+        let channels = channels
+            .iter()
+            .map(|channel| ChannelState {
+                weight: channel_weight(channel),
+                shelf,
+                high_pass,
+                history: vec![0.0; TAPS_PER_PHASE],
+            })
+            .collect();
+
+        Self {
+            channels,
+            interpolation: interpolation_filter(),
+            step_len: (rate as usize / 10).max(1),
+            step_pos: 0,
+            step_sum: 0.0,
+            steps: vec![],
+            true_peak: 0.0,
+        }
+    }
+
+    /// Feeds a decoded buffer to the meter
+    pub fn process(&mut self, decoded: AudioBufferRef<'_>) {
+        let mut buffer = AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+        decoded.convert(&mut buffer);
+        self.process_buffer(&buffer);
+    }
+
+    pub fn process_buffer(&mut self, buffer: &AudioBuffer<f32>) {
+        let n_channels = self.channels.len().min(buffer.spec().channels.count());
+
+        for frame in 0..buffer.frames() {
+            for ch in 0..n_channels {
+                let sample = buffer.chan(ch)[frame] as f64;
+                let state = &mut self.channels[ch];
+
+                // True peak on the oversampled signal
+                state.history.rotate_right(1);
+                state.history[0] = sample;
+                for phase in 0..OVERSAMPLING {
+                    let interpolated = state
+                        .history
+                        .iter()
+                        .enumerate()
+                        .map(|(i, x)| x * self.interpolation[i * OVERSAMPLING + phase])
+                        .sum::<f64>();
+                    self.true_peak = self.true_peak.max(interpolated.abs());
+                }
+                self.true_peak = self.true_peak.max(sample.abs());
+
+                let filtered = state.high_pass.process(state.shelf.process(sample));
+                self.step_sum += state.weight * filtered * filtered;
+            }
+
+            self.step_pos += 1;
+            if self.step_pos == self.step_len {
+                self.steps.push(self.step_sum / self.step_len as f64);
+                self.step_pos = 0;
+                self.step_sum = 0.0;
+            }
+        }
+    }
+
+    pub fn loudness(&self) -> Loudness {
+        Loudness {
+            integrated: integrated_loudness(&self.block_powers(MOMENTARY_STEPS)),
+            range: loudness_range(&self.block_powers(SHORT_TERM_STEPS)),
+            true_peak: self.true_peak,
+        }
+    }
+
+    /// Returns the mean power of every block made of `steps` consecutive 100ms steps
+    fn block_powers(&self, steps: usize) -> Vec<f64> {
+        if self.steps.len() < steps {
+            // Tracks shorter than a block are measured as a single block
+            if self.steps.is_empty() {
+                return vec![];
+            }
+            return vec![self.steps.iter().sum::<f64>() / self.steps.len() as f64];
+        }
+
+        self.steps
+            .windows(steps)
+            .map(|window| window.iter().sum::<f64>() / steps as f64)
+            .collect()
+    }
+}
+
+fn power_to_loudness(power: f64) -> f64 {
+    -0.691 + 10.0 * power.log10()
+}
+
+fn mean(powers: &[f64]) -> f64 {
+    powers.iter().sum::<f64>() / powers.len() as f64
+}
+
+fn integrated_loudness(powers: &[f64]) -> f64 {
+    let gated = powers
+        .iter()
+        .copied()
+        .filter(|p| power_to_loudness(*p) > ABSOLUTE_GATE)
+        .collect::<Vec<f64>>();
+    if gated.is_empty() {
+        return ABSOLUTE_GATE;
+    }
+
+    let threshold = power_to_loudness(mean(&gated)) + INTEGRATED_RELATIVE_GATE;
+    let gated = gated
+        .into_iter()
+        .filter(|p| power_to_loudness(*p) > threshold)
+        .collect::<Vec<f64>>();
+    if gated.is_empty() {
+        return ABSOLUTE_GATE;
+    }
+
+    power_to_loudness(mean(&gated))
+}
+
+fn loudness_range(powers: &[f64]) -> f64 {
+    let gated = powers
+        .iter()
+        .copied()
+        .filter(|p| power_to_loudness(*p) > ABSOLUTE_GATE)
+        .collect::<Vec<f64>>();
+    if gated.is_empty() {
+        return 0.0;
+    }
+
+    let threshold = power_to_loudness(mean(&gated)) + RANGE_RELATIVE_GATE;
+    let mut loudness = gated
+        .into_iter()
+        .map(power_to_loudness)
+        .filter(|l| *l > threshold)
+        .collect::<Vec<f64>>();
+    if loudness.is_empty() {
+        return 0.0;
+    }
+    loudness.sort_by(f64::total_cmp);
+
+    let percentile = |p: f64| loudness[((loudness.len() - 1) as f64 * p).round() as usize];
+    percentile(0.95) - percentile(0.10)
+}
+
+/// Computes the two stages of the K-weighting filter for the given sample rate (same as libebur128)
+fn k_weighting(rate: f64) -> (Biquad, Biquad) {
+    let f0 = 1681.974450955533;
+    let g = 3.999843853973347;
+    let q = 0.7071752369554196;
+
+    let k = (PI * f0 / rate).tan();
+    let vh = 10.0f64.powf(g / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+
+    let shelf = Biquad {
+        b: [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    let f0 = 38.13547087602444;
+    let q = 0.5003270373238773;
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [1.0, 2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+
+    (shelf, high_pass)
+}
+
+/// Windowed sinc lowpass used to oversample the signal when looking for the true peak
+fn interpolation_filter() -> Vec<f64> {
+    let len = OVERSAMPLING * TAPS_PER_PHASE;
+    let center = (len - 1) as f64 / 2.0;
+    (0..len)
+        .map(|i| {
+            let t = (i as f64 - center) / OVERSAMPLING as f64;
+            let sinc = if t == 0.0 {
+                1.0
+            } else {
+                (PI * t).sin() / (PI * t)
+            };
+            // Blackman window
+            let w = 0.42 - 0.5 * (2.0 * PI * i as f64 / (len - 1) as f64).cos()
+                + 0.08 * (4.0 * PI * i as f64 / (len - 1) as f64).cos();
+            sinc * w
+        })
+        .collect()
+}
+
+/// Channel weights from ITU-R BS.1770-4 (LFE channels are excluded from the measurement)
+fn channel_weight(channel: Channels) -> f64 {
+    if channel == Channels::LFE1 || channel == Channels::LFE2 {
+        0.0
+    } else if channel == Channels::SIDE_LEFT
+        || channel == Channels::SIDE_RIGHT
+        || channel == Channels::REAR_LEFT
+        || channel == Channels::REAR_RIGHT
+    {
+        1.41
+    } else {
+        1.0
+    }
+}
+
+/// Decodes the whole track and measures its loudness
+///
+/// Streams are rejected, as they may never end
+pub fn analyze_track<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(
+    path: P,
+) -> Result<LoudnessMeter, NError> {
+    let track = MusicTrack::new(path)?;
+    if track.is_stream() {
+        return Err(NError::Io(io::Error::new(
+            ErrorKind::Unsupported,
+            "streams can't be analyzed",
+        )));
+    }
+    let (mut format, replay_gain) = track.get_format_with_gain()?;
+    let track = format.default_track().ok_or(NError::NoTrack)?;
+    let track_id = track.id;
+    // The Opus output gain is always applied during playback, so it's part of what gets measured
+    let output_gain = replay_gain
+        .with_codec_params(&track.codec_params)
+        .factor(Default::default());
+
+    let mut decoder = CODEC_REGISTRY
+        .make(&track.codec_params, &DecoderOptions::default())
+        .map_err(|_| NError::UnsupportedCodec)?;
+    let mut meter: Option<LoudnessMeter> = None;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        match decoder.decode(&packet) {
+            Ok(decoded) => {
+                let mut buffer =
+                    AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                decoded.convert(&mut buffer);
+                if output_gain != 1.0 {
+                    buffer.transform(|sample| sample * output_gain);
+                }
+
+                let spec = *buffer.spec();
+                meter
+                    .get_or_insert_with(|| LoudnessMeter::new(spec.rate, spec.channels))
+                    .process_buffer(&buffer);
+            }
+            Err(SymphError::DecodeError(err)) => eprintln!("Decode error: {}", err),
+            Err(err) => return Err(err.into()),
+        }
+    }
+
+    meter.ok_or(NError::NoTrack)
+}
+
+/// Measures the loudness of every track of an album, returning the loudness of each track and of the whole album
+pub fn analyze_album<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(
+    paths: &[P],
+) -> Result<(Vec<Loudness>, Loudness), NError> {
+    let meters = paths
+        .iter()
+        .cloned()
+        .map(analyze_track)
+        .collect::<Result<Vec<LoudnessMeter>, NError>>()?;
+
+    Ok((
+        meters.iter().map(LoudnessMeter::loudness).collect(),
+        Loudness::album(&meters),
+    ))
+}
+
+/// Writes the results of the analysis as ReplayGain tags (or as R128 tags for Opus files)
+pub fn write_tags<P: AsRef<Path>>(
+    path: P,
+    track: &Loudness,
+    album: Option<&Loudness>,
+) -> Result<(), NError> {
+    let mut tag = Tag::read_from_path(path.as_ref()).map_err(|_| NError::UnsupportedFormat)?;
+
+    let mut values = vec![
+        (
+            "REPLAYGAIN_TRACK_GAIN",
+            format!("{:.2} dB", track.replay_gain()),
+        ),
+        ("REPLAYGAIN_TRACK_PEAK", format!("{:.6}", track.true_peak)),
+        (
+            "REPLAYGAIN_REFERENCE_LOUDNESS",
+            format!("{:.2} LUFS", REPLAY_GAIN_REFERENCE),
+        ),
+    ];
+    if let Some(album) = album {
+        values.push((
+            "REPLAYGAIN_ALBUM_GAIN",
+            format!("{:.2} dB", album.replay_gain()),
+        ));
+        values.push(("REPLAYGAIN_ALBUM_PEAK", format!("{:.6}", album.true_peak)));
+    }
+
+    match &mut tag {
+        Tag::Id3Tag { inner } => {
+            for (key, value) in values {
+                inner.remove_extended_text(Some(key), None);
+                inner.add_frame(id3::frame::ExtendedText {
+                    description: key.to_string(),
+                    value,
+                });
+            }
+        }
+        Tag::VorbisFlacTag { inner } => {
+            for (key, value) in values {
+                inner.set_vorbis(key, vec![value]);
+            }
+        }
+        Tag::OpusTag { inner } => {
+            // Opus uses R128 gains in Q7.8 format instead of ReplayGain ones
+            let mut values = vec![("r128_track_gain", r128_to_q78(track.r128_gain()))];
+            if let Some(album) = album {
+                values.push(("r128_album_gain", r128_to_q78(album.r128_gain())));
+            }
+            for (key, value) in values {
+                inner.remove_entries(key);
+                inner.add_one(key.to_string(), value.to_string());
+            }
+        }
+        _ => return Err(NError::UnsupportedFormat),
+    }
+
+    tag.write_to_path(path.as_ref())
+        .map_err(|err| NError::Io(io::Error::other(format!("{err:?}"))))
+}
+
+fn r128_to_q78(gain: f64) -> i16 {
+    (gain * 256.0)
+        .round()
+        .clamp(i16::MIN as f64, i16::MAX as f64) as i16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use symphonia::core::audio::SignalSpec;
+
+    const RATE: u32 = 48000;
+
+    /// Measures `seconds` of a sine of the given frequency and amplitude, the same on every channel
+    fn measure_sine(
+        channels: Channels,
+        frequency: f64,
+        amplitude: f64,
+        seconds: usize,
+    ) -> Loudness {
+        let frames = RATE as usize * seconds;
+        let mut buffer = AudioBuffer::<f32>::new(frames as u64, SignalSpec::new(RATE, channels));
+        buffer.render_reserved(Some(frames));
+        for ch in 0..channels.count() {
+            for (i, sample) in buffer.chan_mut(ch).iter_mut().enumerate() {
+                *sample =
+                    (amplitude * (2.0 * PI * frequency * i as f64 / RATE as f64).sin()) as f32;
+            }
+        }
+
+        let mut meter = LoudnessMeter::new(RATE, channels);
+        meter.process_buffer(&buffer);
+        meter.loudness()
+    }
+
+    #[test]
+    fn full_scale_sine() {
+        // ITU-R BS.1770-4: a 0 dBFS 997 Hz sine on a single channel reads -3.01 LKFS
+        let loudness = measure_sine(Channels::FRONT_LEFT, 997.0, 1.0, 5);
+        assert!((loudness.integrated + 3.01).abs() < 0.05, "{loudness:?}");
+        assert!(loudness.range.abs() < 0.1, "{loudness:?}");
+        assert!((loudness.true_peak - 1.0).abs() < 0.01, "{loudness:?}");
+    }
+
+    #[test]
+    fn reference_sine() {
+        // EBU Tech 3341, case 1: a -23 dBFS 1 kHz stereo sine reads -23 LUFS
+        let amplitude = 10.0f64.powf(-23.0 / 20.0);
+        let loudness = measure_sine(
+            Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+            1000.0,
+            amplitude,
+            20,
+        );
+        assert!((loudness.integrated + 23.0).abs() < 0.1, "{loudness:?}");
+        assert!((loudness.r128_gain()).abs() < 0.1, "{loudness:?}");
+        assert!((loudness.replay_gain() - 5.0).abs() < 0.1, "{loudness:?}");
+    }
+
+    #[test]
+    fn silence() {
+        let loudness = measure_sine(Channels::FRONT_LEFT, 1000.0, 0.0, 1);
+        assert_eq!(loudness.integrated, ABSOLUTE_GATE);
+        assert_eq!(loudness.range, 0.0);
+        assert_eq!(loudness.true_peak, 0.0);
+    }
+}
@@ -1,79 +1,175 @@
-use crate::{remove_ext, Metadata, TrackTime, PROBE};
+use crate::dca::{DcaIndexMode, DcaReader};
+use crate::duration;
+use crate::event::EventBus;
+use crate::file_source::SourceMode;
+use crate::http_source::{is_url, HttpSource};
+use crate::replay_gain::ReplayGain;
+use crate::{remove_ext, Metadata, NError, TrackTime, PROBE};
 use multitag::Tag;
 use std::ffi::OsStr;
-use std::io::Cursor;
 use std::path::Path;
-use std::{fs, io};
-use symphonia::core::formats::{FormatOptions, FormatReader};
-use symphonia::core::io::MediaSourceStream;
+use symphonia::core::formats::{FormatOptions, FormatReader, Track};
+use symphonia::core::io::{MediaSource, MediaSourceStream};
 use symphonia::core::meta::MetadataOptions;
 use symphonia::core::probe::Hint;
-use symphonia_core::meta::StandardTagKey;
+use symphonia_core::meta::{StandardTagKey, StandardVisualKey};
 
 /// The basics where everything is built upon
 pub struct MusicTrack {
     path: String,
     ext: String,
+    source_mode: SourceMode,
+    dca_index: DcaIndexMode,
+    events: Option<EventBus>,
 }
 
 impl MusicTrack {
-    pub fn new<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(path: P) -> io::Result<Self> {
-        let p = path.clone();
-        let p = Path::new(&p);
-        Ok(MusicTrack {
-            path: path.into(),
-            ext: p
+    pub fn new<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(
+        path: P,
+    ) -> Result<Self, NError> {
+        let path: String = path.into();
+
+        // Streams often have no extension at all, their content type is used instead
+        let ext = if is_url(&path) {
+            let url = path.split(['?', '#']).next().unwrap_or_default();
+            Path::new(url)
+                .extension()
+                .and_then(OsStr::to_str)
+                .unwrap_or_default()
+                .to_string()
+        } else {
+            Path::new(&path)
                 .extension()
-                .ok_or_else(|| io::Error::from(io::ErrorKind::Unsupported))?
-                .to_str()
-                .unwrap()
-                .to_string(),
+                .and_then(OsStr::to_str)
+                .ok_or(NError::UnsupportedFormat)?
+                .to_string()
+        };
+
+        Ok(MusicTrack {
+            path,
+            ext,
+            source_mode: SourceMode::default(),
+            dca_index: DcaIndexMode::default(),
+            events: None,
         })
     }
 
+    /// Chooses whether the file is loaded in memory or read while decoding it
+    ///
+    /// It's used when the track is decoded, reading only its metadata or its length always uses `SourceMode::Direct`
+    pub fn with_source_mode(mut self, source_mode: SourceMode) -> Self {
+        self.source_mode = source_mode;
+        self
+    }
+
+    /// Chooses whether a DCA file gets indexed when it's opened, see `DcaReader::try_new_indexed`
+    ///
+    /// It's meant for playing the track, reading its metadata doesn't need it
+    pub fn with_dca_index(mut self, dca_index: DcaIndexMode) -> Self {
+        self.dca_index = dca_index;
+        self
+    }
+
+    /// Sends the titles announced by a stream to `events`
+    pub fn with_events(mut self, events: EventBus) -> Self {
+        self.events = Some(events);
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Returns whether the track is an HTTP(S) URL
+    pub fn is_stream(&self) -> bool {
+        is_url(&self.path)
+    }
+
     /// Returns the `FormatReader` provided by Symphonia
-    pub fn get_format(&self) -> Result<Box<dyn FormatReader>, io::Error> {
-        let file = fs::read(&self.path)?;
-        let media_stream = MediaSourceStream::new(
-            Box::new(Cursor::new(file)),
-            std::default::Default::default(),
-        );
+    pub fn get_format(&self) -> Result<Box<dyn FormatReader>, NError> {
+        Ok(self.get_format_with_gain()?.0)
+    }
+
+    /// Returns the `FormatReader` provided by Symphonia together with the ReplayGain info found in the tags
+    pub fn get_format_with_gain(&self) -> Result<(Box<dyn FormatReader>, ReplayGain), NError> {
+        self.open(self.source_mode)
+    }
+
+    fn open(&self, source_mode: SourceMode) -> Result<(Box<dyn FormatReader>, ReplayGain), NError> {
         let mut hint = Hint::new();
         hint.with_extension(self.ext.as_ref());
+        let source: Box<dyn MediaSource> = if self.is_stream() {
+            let source = HttpSource::open(&self.path, self.events.clone())?;
+            if let Some(content_type) = source.content_type() {
+                hint.mime_type(content_type);
+            }
+            Box::new(source)
+        } else {
+            crate::file_source::open(&self.path, source_mode)?
+        };
+        let media_stream = MediaSourceStream::new(source, std::default::Default::default());
         let meta_ops = MetadataOptions::default();
         let fmt_ops = FormatOptions {
             enable_gapless: true,
             ..Default::default()
         };
-        let probed = PROBE
-            .format(&hint, media_stream, &fmt_ops, &meta_ops)
-            .expect("Format not supported");
-        Ok(probed.format)
+
+        // DCA files can be indexed up front, so that seeking is instant and their duration is known
+        if self.dca_index != DcaIndexMode::Off
+            && self.ext.eq_ignore_ascii_case("dca")
+            && !self.is_stream()
+        {
+            let mut format = DcaReader::try_new_indexed(
+                media_stream,
+                &fmt_ops,
+                Path::new(&self.path),
+                self.dca_index,
+            )?;
+            let replay_gain = format
+                .metadata()
+                .current()
+                .map(|rev| ReplayGain::from_tags(rev.tags()))
+                .unwrap_or_default();
+
+            return Ok((Box::new(format), replay_gain));
+        }
+
+        let mut probed = PROBE.format(&hint, media_stream, &fmt_ops, &meta_ops)?;
+
+        // Tags placed before the container (e.g. ID3v2) are read by the probe, not by the format
+        let mut replay_gain = probed
+            .metadata
+            .get()
+            .and_then(|metadata| {
+                metadata
+                    .current()
+                    .map(|rev| ReplayGain::from_tags(rev.tags()))
+            })
+            .unwrap_or_default();
+        if let Some(rev) = probed.format.metadata().current() {
+            replay_gain.merge(ReplayGain::from_tags(rev.tags()));
+        }
+
+        Ok((probed.format, replay_gain))
     }
 
-    pub fn get_meta(&self) -> Result<Metadata, io::Error> {
-        let mut format = self.get_format()?;
-        let track = format.default_track().expect("Can't load tracks");
-        let time_base = track
-            .codec_params
-            .time_base
-            // TODO: add better error
-            .ok_or(io::Error::from(io::ErrorKind::Unsupported))?;
-
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
-        let time = time_base.calc_time(duration);
-
-        let time = TrackTime {
-            position: 0.0,
-            length: time.seconds as f64 + time.frac,
-        };
+    pub fn get_meta(&self) -> Result<Metadata, NError> {
+        // Streams aren't opened just for their metadata, the titles come while they play
+        if self.is_stream() {
+            return Ok(Metadata {
+                time: TrackTime::default(),
+                artist: String::new(),
+                title: self.path.clone(),
+                album: String::new(),
+            });
+        }
+
+        let mut format = self.open(SourceMode::Direct)?.0;
+        let time = length(format.default_track().ok_or(NError::NoTrack)?);
 
         let mut artist = String::new();
         let mut title = String::new();
+        let mut album = String::new();
 
         if let Some(metadata) = format.metadata().skip_to_latest() {
             for tag in metadata.tags() {
@@ -81,6 +177,8 @@ impl MusicTrack {
                     artist = tag.value.to_string();
                 } else if let Some(StandardTagKey::TrackTitle) = tag.std_key {
                     title = tag.value.to_string();
+                } else if let Some(StandardTagKey::Album) = tag.std_key {
+                    album = tag.value.to_string();
                 }
             }
         } else if let Ok(tag) = Tag::read_from_path(&self.path) {
@@ -90,6 +188,9 @@ impl MusicTrack {
             if let Some(a) = tag.artist() {
                 artist = a;
             }
+            if let Some(a) = tag.get_album_info().and_then(|a| a.title) {
+                album = a;
+            }
         }
 
         if title.is_empty() {
@@ -98,29 +199,59 @@ impl MusicTrack {
 
         title.shrink_to_fit();
         artist.shrink_to_fit();
+        album.shrink_to_fit();
 
         Ok(Metadata {
             time,
             artist,
             title,
+            album,
         })
     }
 
-    pub fn get_length(&self) -> Result<TrackTime, io::Error> {
-        let format = self.get_format()?;
-        let track = format.default_track().expect("Can't load tracks");
-        let time_base = track.codec_params.time_base.unwrap();
-
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
-        let time = time_base.calc_time(duration);
-
-        Ok(TrackTime {
-            position: 0.0,
-            length: time.seconds as f64 + time.frac,
-        })
+    /// Returns the cover read by Symphonia, the front cover if there are more pictures
+    pub fn get_cover(&self) -> Option<Vec<u8>> {
+        if self.is_stream() {
+            return None;
+        }
+
+        let mut format = self.open(SourceMode::Direct).ok()?.0;
+        let metadata = format.metadata();
+        let visuals = metadata.current()?.visuals();
+
+        visuals
+            .iter()
+            .find(|visual| visual.usage == Some(StandardVisualKey::FrontCover))
+            .or(visuals.first())
+            .map(|visual| visual.data.to_vec())
+    }
+
+    /// Returns the length of the track, estimating it from the file if its header doesn't tell it
+    ///
+    /// Unlike `MusicTrack::get_meta` it can read the whole file, see `duration::estimate`
+    pub fn get_length(&self) -> Result<TrackTime, NError> {
+        let mut format = self.open(SourceMode::Direct)?.0;
+        let mut time = length(format.default_track().ok_or(NError::NoTrack)?);
+        if time.length.is_none() && !self.is_stream() {
+            time.length = duration::estimate(&self.path, format.as_mut())?;
+        }
+        Ok(time)
+    }
+}
+
+/// Returns the length of `track`, `None` if the track doesn't tell how long it is
+fn length(track: &Track) -> TrackTime {
+    let params = &track.codec_params;
+    let length = params
+        .time_base
+        .zip(params.n_frames)
+        .map(|(time_base, frames)| {
+            let time = time_base.calc_time(params.start_ts + frames);
+            time.seconds as f64 + time.frac
+        });
+
+    TrackTime {
+        position: 0.0,
+        length,
     }
 }
@@ -1,24 +1,34 @@
 use audiopus::{
     coder::{Decoder as AudiopusDecoder, GenericCtl},
-    Channels, Error as OpusError, ErrorCode, SampleRate,
+    ffi, Channels as OpusChannels, Error as OpusError, ErrorCode, SampleRate,
 };
+use std::os::raw::c_int;
+use std::ptr::NonNull;
 use symphonia_core::{
-    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Layout, Signal, SignalSpec},
+    audio::{AsAudioBufferRef, AudioBuffer, AudioBufferRef, Channels, Signal, SignalSpec},
     codecs::{
         CodecDescriptor, CodecParameters, Decoder, DecoderOptions, FinalizeResult, CODEC_TYPE_OPUS,
     },
-    errors::{decode_error, Result as SymphResult},
+    errors::{decode_error, unsupported_error, Result as SymphResult},
     formats::Packet,
 };
 
 // Original code from the Songbird project
 
+/// How many channels Symphonia can represent, one for every bit of `Channels`
+const MAX_CHANNELS: usize = Channels::all().bits().count_ones() as usize;
+
 /// Opus decoder for symphonia, based on libopus v1.3 (via [`audiopus`]).
+///
+/// Mono and stereo streams are decoded natively, while streams with more channels are decoded as multistream
+/// following their channel mapping family (e.g. 5.1 Ogg Opus files).
 pub struct OpusDecoder {
-    inner: AudiopusDecoder,
+    inner: InnerDecoder,
     params: CodecParameters,
     buf: AudioBuffer<f32>,
     rawbuf: Vec<f32>,
+    /// Index in `buf` of every channel decoded, in the order they are decoded
+    order: Vec<usize>,
 }
 
 /// # SAFETY
@@ -32,19 +42,11 @@ unsafe impl Sync for OpusDecoder {}
 
 impl OpusDecoder {
     fn decode_inner(&mut self, packet: &Packet) -> SymphResult<()> {
+        let channels = self.order.len();
         let s_ct = loop {
-            let pkt = if packet.buf().is_empty() {
-                None
-            } else if let Ok(checked_pkt) = packet.buf().try_into() {
-                Some(checked_pkt)
-            } else {
-                return decode_error("Opus packet was too large (greater than i32::MAX bytes).");
-            };
-            let out_space = (&mut self.rawbuf[..]).try_into().expect("The following logic expands this buffer safely below i32::MAX, and we throw our own error.");
-
-            match self.inner.decode_float(pkt, out_space, false) {
-                Ok(v) => break v,
-                Err(OpusError::Opus(ErrorCode::BufferTooSmall)) => {
+            match self.inner.decode_float(packet.buf(), &mut self.rawbuf)? {
+                Some(v) => break v,
+                None => {
                     // double the buffer size
                     // correct behav would be to mirror the decoder logic in the udp_rx set.
                     let new_size = (self.rawbuf.len() * 2).min(i32::MAX as usize);
@@ -53,13 +55,8 @@ impl OpusDecoder {
                     }
 
                     self.rawbuf.resize(new_size, 0.0);
-                    self.buf = AudioBuffer::new(
-                        self.rawbuf.len() as u64 / 2,
-                        SignalSpec::new_with_layout(48000, Layout::Stereo),
-                    );
-                }
-                Err(_) => {
-                    return decode_error("Opus decode error: see 'tracing' logs.");
+                    self.buf =
+                        AudioBuffer::new((self.rawbuf.len() / channels) as u64, *self.buf.spec());
                 }
             }
         };
@@ -67,33 +64,68 @@ impl OpusDecoder {
         self.buf.clear();
         self.buf.render_reserved(Some(s_ct));
 
-        // Forcibly assuming stereo, for now.
-        for ch in 0..2 {
-            let iter = self.rawbuf.chunks_exact(2).map(|chunk| chunk[ch]);
+        for (i, &ch) in self.order.iter().enumerate() {
+            let iter = self.rawbuf.chunks_exact(channels).map(|chunk| chunk[i]);
             for (tgt, src) in self.buf.chan_mut(ch).iter_mut().zip(iter) {
                 *tgt = src;
             }
         }
 
+        // Remove the pre-skip and the padding, if the format asked for gapless playback
+        self.buf
+            .trim(packet.trim_start as usize, packet.trim_end as usize);
+
         Ok(())
     }
 }
 
 impl Decoder for OpusDecoder {
     fn try_new(params: &CodecParameters, _options: &DecoderOptions) -> SymphResult<Self> {
-        let inner = AudiopusDecoder::new(SampleRate::Hz48000, Channels::Stereo).unwrap();
+        let head = match &params.extra_data {
+            Some(extra_data) => OpusHead::parse(extra_data)?,
+            // Without an identification header (e.g. DCA) there can only be a single mono or stereo stream
+            None => OpusHead::single(params.channels.map_or(2, |channels| channels.count())),
+        };
+
+        let inner = match &head.mapping {
+            None => {
+                let channels = match head.channels {
+                    1 => OpusChannels::Mono,
+                    2 => OpusChannels::Stereo,
+                    _ => return unsupported_error("opus: invalid channel count"),
+                };
+                InnerDecoder::Single(
+                    AudiopusDecoder::new(SampleRate::Hz48000, channels)
+                        .or_else(|_| unsupported_error("opus: can't create the decoder"))?,
+                )
+            }
+            Some(mapping) => {
+                InnerDecoder::Multistream(MultistreamDecoder::new(head.channels, mapping)?)
+            }
+        };
+
+        // Without a known layout, the channels are kept in the order they are decoded
+        let layout = (head.family <= 1)
+            .then(|| vorbis_channels(head.channels))
+            .flatten();
+        let channels = layout.unwrap_or_else(|| discrete_channels(head.channels));
+        let order = match layout {
+            Some(layout) => vorbis_order(head.channels)
+                .iter()
+                .map(|channel| (layout.bits() & (channel.bits() - 1)).count_ones() as usize)
+                .collect(),
+            None => (0..head.channels).collect(),
+        };
 
         let mut params = params.clone();
-        params.with_sample_rate(48000);
+        params.with_sample_rate(48000).with_channels(channels);
 
         Ok(Self {
             inner,
             params,
-            buf: AudioBuffer::new(
-                48000 / 50,
-                SignalSpec::new_with_layout(48000, Layout::Stereo),
-            ),
-            rawbuf: vec![0.0f32; 2 * (48000 / 50)],
+            buf: AudioBuffer::new(48000 / 50, SignalSpec::new(48000, channels)),
+            rawbuf: vec![0.0f32; head.channels * (48000 / 50)],
+            order,
         })
     }
 
@@ -106,7 +138,7 @@ impl Decoder for OpusDecoder {
     }
 
     fn reset(&mut self) {
-        _ = self.inner.reset_state();
+        self.inner.reset();
     }
 
     fn codec_params(&self) -> &CodecParameters {
@@ -130,3 +162,280 @@ impl Decoder for OpusDecoder {
         self.buf.as_audio_buffer_ref()
     }
 }
+
+/// The decoder of a single mono or stereo stream, or of multiple streams
+enum InnerDecoder {
+    Single(AudiopusDecoder),
+    Multistream(MultistreamDecoder),
+}
+
+impl InnerDecoder {
+    /// Decodes `packet` into `out` as interleaved samples, returning how many samples per channel were decoded
+    /// It returns `None` if `out` is too small
+    fn decode_float(&mut self, packet: &[u8], out: &mut [f32]) -> SymphResult<Option<usize>> {
+        match self {
+            InnerDecoder::Single(decoder) => {
+                let pkt = if packet.is_empty() {
+                    None
+                } else if let Ok(checked_pkt) = packet.try_into() {
+                    Some(checked_pkt)
+                } else {
+                    return decode_error(
+                        "Opus packet was too large (greater than i32::MAX bytes).",
+                    );
+                };
+                let out_space = out.try_into().expect("The following logic expands this buffer safely below i32::MAX, and we throw our own error.");
+
+                match decoder.decode_float(pkt, out_space, false) {
+                    Ok(v) => Ok(Some(v)),
+                    Err(OpusError::Opus(ErrorCode::BufferTooSmall)) => Ok(None),
+                    Err(_) => decode_error("Opus decode error: see 'tracing' logs."),
+                }
+            }
+            InnerDecoder::Multistream(decoder) => decoder.decode_float(packet, out),
+        }
+    }
+
+    fn reset(&mut self) {
+        match self {
+            InnerDecoder::Single(decoder) => _ = decoder.reset_state(),
+            InnerDecoder::Multistream(decoder) => decoder.reset(),
+        }
+    }
+}
+
+/// Safe wrapper around the libopus multistream decoder, which isn't exposed by [`audiopus`]
+struct MultistreamDecoder {
+    ptr: NonNull<ffi::OpusMSDecoder>,
+    channels: usize,
+}
+
+/// # SAFETY
+/// The decoder state is owned by this struct and only ever touched through `&mut self`.
+unsafe impl Send for MultistreamDecoder {}
+
+impl MultistreamDecoder {
+    fn new(channels: usize, mapping: &StreamMapping) -> SymphResult<Self> {
+        let mut error = 0;
+        // SAFETY: `mapping.mapping` holds exactly `channels` entries, as checked while parsing the header
+        let ptr = unsafe {
+            ffi::opus_multistream_decoder_create(
+                48000,
+                channels as c_int,
+                mapping.streams as c_int,
+                mapping.coupled as c_int,
+                mapping.mapping.as_ptr(),
+                &mut error,
+            )
+        };
+
+        match NonNull::new(ptr) {
+            Some(ptr) if error == ffi::OPUS_OK as c_int => Ok(Self { ptr, channels }),
+            _ => unsupported_error("opus: invalid multistream channel mapping"),
+        }
+    }
+
+    fn decode_float(&mut self, packet: &[u8], out: &mut [f32]) -> SymphResult<Option<usize>> {
+        let Ok(len) = i32::try_from(packet.len()) else {
+            return decode_error("Opus packet was too large (greater than i32::MAX bytes).");
+        };
+        let data = if packet.is_empty() {
+            std::ptr::null()
+        } else {
+            packet.as_ptr()
+        };
+        let frame_size = (out.len() / self.channels).min(i32::MAX as usize) as c_int;
+
+        // SAFETY: `out` has room for `frame_size` samples for each channel
+        let result = unsafe {
+            ffi::opus_multistream_decode_float(
+                self.ptr.as_ptr(),
+                data,
+                len,
+                out.as_mut_ptr(),
+                frame_size,
+                0,
+            )
+        };
+
+        match result {
+            frames if frames >= 0 => Ok(Some(frames as usize)),
+            error if error == ffi::OPUS_BUFFER_TOO_SMALL as c_int => Ok(None),
+            _ => decode_error("Opus multistream decode error."),
+        }
+    }
+
+    fn reset(&mut self) {
+        // SAFETY: OPUS_RESET_STATE doesn't take any argument
+        unsafe {
+            ffi::opus_multistream_decoder_ctl(self.ptr.as_ptr(), ffi::OPUS_RESET_STATE as c_int);
+        }
+    }
+}
+
+impl Drop for MultistreamDecoder {
+    fn drop(&mut self) {
+        // SAFETY: the pointer was returned by `opus_multistream_decoder_create` and it's never used again
+        unsafe { ffi::opus_multistream_decoder_destroy(self.ptr.as_ptr()) }
+    }
+}
+
+/// How the channels of a multistream packet map to the streams it's made of
+struct StreamMapping {
+    streams: u8,
+    coupled: u8,
+    mapping: Vec<u8>,
+}
+
+/// The fields of the Opus identification header needed to set up the decoder
+struct OpusHead {
+    channels: usize,
+    family: u8,
+    /// `None` if there's a single stream
+    mapping: Option<StreamMapping>,
+}
+
+impl OpusHead {
+    fn single(channels: usize) -> Self {
+        Self {
+            channels,
+            family: 0,
+            mapping: None,
+        }
+    }
+
+    /// Parses the "OpusHead" packet, see RFC 7845 section 5.1
+    fn parse(buf: &[u8]) -> SymphResult<Self> {
+        if buf.len() < 19 || &buf[..8] != b"OpusHead" {
+            return decode_error("opus: invalid identification header");
+        }
+
+        let channels = buf[9] as usize;
+        let family = buf[18];
+        if channels == 0 {
+            return unsupported_error("opus: invalid channel count");
+        }
+        // The decoded channels must all fit in the spec of the buffer
+        if channels > MAX_CHANNELS {
+            return decode_error("opus: too many channels");
+        }
+
+        if family == 0 {
+            if channels > 2 {
+                return decode_error("opus: invalid channel count for mapping family 0");
+            }
+            return Ok(Self::single(channels));
+        }
+        if family == 1 && channels > 8 {
+            return decode_error("opus: invalid channel count for mapping family 1");
+        }
+
+        let Some(table) = buf.get(19..21 + channels) else {
+            return decode_error("opus: missing channel mapping table");
+        };
+        let (streams, coupled) = (table[0], table[1]);
+        if streams == 0 || coupled > streams {
+            return decode_error("opus: invalid stream count");
+        }
+
+        Ok(Self {
+            channels,
+            family,
+            mapping: Some(StreamMapping {
+                streams,
+                coupled,
+                mapping: table[2..].to_vec(),
+            }),
+        })
+    }
+}
+
+/// Returns the channels of the Vorbis channel order used by mapping families 0 and 1, in the order they are decoded
+fn vorbis_order(count: usize) -> &'static [Channels] {
+    const FL: Channels = Channels::FRONT_LEFT;
+    const FR: Channels = Channels::FRONT_RIGHT;
+    const FC: Channels = Channels::FRONT_CENTRE;
+    const LFE: Channels = Channels::LFE1;
+    const RL: Channels = Channels::REAR_LEFT;
+    const RR: Channels = Channels::REAR_RIGHT;
+    const RC: Channels = Channels::REAR_CENTRE;
+    const SL: Channels = Channels::SIDE_LEFT;
+    const SR: Channels = Channels::SIDE_RIGHT;
+
+    match count {
+        1 => &[FL],
+        2 => &[FL, FR],
+        3 => &[FL, FC, FR],
+        4 => &[FL, FR, RL, RR],
+        5 => &[FL, FC, FR, RL, RR],
+        6 => &[FL, FC, FR, RL, RR, LFE],
+        7 => &[FL, FC, FR, SL, SR, RC, LFE],
+        8 => &[FL, FC, FR, SL, SR, RL, RR, LFE],
+        _ => &[],
+    }
+}
+
+/// Returns the layout of `count` channels in the Vorbis channel order, if it's defined
+pub(crate) fn vorbis_channels(count: usize) -> Option<Channels> {
+    let order = vorbis_order(count);
+    (!order.is_empty()).then(|| order.iter().fold(Channels::empty(), |acc, &ch| acc | ch))
+}
+
+/// Returns `count` channels without any particular meaning, for the mapping families that don't define a layout
+pub(crate) fn discrete_channels(count: usize) -> Channels {
+    Channels::from_bits_truncate(((1u64 << count.min(MAX_CHANNELS)) - 1) as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds an "OpusHead" packet of `channels` channels, with a mapping table if `family` isn't 0
+    fn head(channels: u8, family: u8) -> Vec<u8> {
+        let mut buf = b"OpusHead".to_vec();
+        buf.extend([1, channels, 0x38, 0x01, 0x80, 0xbb, 0, 0, 0, 0, family]);
+        if family != 0 {
+            buf.extend([channels, 0]);
+            buf.extend(0..channels);
+        }
+        buf
+    }
+
+    #[test]
+    fn parses_the_channel_count() {
+        let mono = OpusHead::parse(&head(1, 0)).unwrap();
+        assert_eq!((mono.channels, mono.family), (1, 0));
+        assert!(mono.mapping.is_none());
+
+        let surround = OpusHead::parse(&head(6, 1)).unwrap();
+        let mapping = surround.mapping.unwrap();
+        assert_eq!((mapping.streams, mapping.coupled), (6, 0));
+        assert_eq!(mapping.mapping, [0, 1, 2, 3, 4, 5]);
+
+        let discrete = OpusHead::parse(&head(MAX_CHANNELS as u8, 255)).unwrap();
+        assert_eq!(discrete_channels(discrete.channels).count(), MAX_CHANNELS);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        assert!(OpusHead::parse(&head(0, 0)).is_err());
+        assert!(OpusHead::parse(&head(3, 0)).is_err());
+        assert!(OpusHead::parse(&head(9, 1)).is_err());
+        // More channels than the ones a spec can have
+        assert!(OpusHead::parse(&head(MAX_CHANNELS as u8 + 1, 255)).is_err());
+        assert!(OpusHead::parse(&head(32, 255)).is_err());
+        // The mapping table is cut short
+        assert!(OpusHead::parse(&head(4, 1)[..22]).is_err());
+        assert!(OpusHead::parse(b"OpusTags").is_err());
+    }
+
+    #[test]
+    fn maps_the_vorbis_layouts() {
+        assert_eq!(
+            vorbis_channels(2),
+            Some(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+        );
+        assert_eq!(vorbis_channels(6).map(|layout| layout.count()), Some(6));
+        assert_eq!(vorbis_channels(9), None);
+    }
+}
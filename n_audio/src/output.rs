@@ -1,22 +1,46 @@
-//! Platform-dependant Audio Outputs
+//! Audio outputs: the sound card, a null sink and files
+//!
+//! A `Player` opens the one chosen with `OutputBackend` (see `Player::with_output`), any other `AudioOutput` can be
+//! used through `OutputBackend::Custom`
 
 /// This is a modified version of [symphonia-play's `output.rs`](https://github.com/pdeljanov/Symphonia/blob/master/symphonia-play/src/output.rs)
 /// It was originally made by [Philip Deljanov](https://github.com/pdeljanov)
 /// Modifications: support for volume (for all platforms)
 /// Modifications: support for custom name app (only for PulseAudio)
 /// Modifications: completely removed pulseaudio in 1.3.0
-use std::result;
-
-use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter};
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use std::{fmt, io, result, thread};
+
+use crate::device;
+use crate::raw_writer::{RawHeader, RawSampleFormat, RawWriter};
+use crate::visualization::VisualizationTap;
+use cpal::traits::{DeviceTrait, StreamTrait};
+use dasp::sample::{FromSample, ToSample};
 use dasp::Sample;
 use rb::*;
-use symphonia::core::audio::{AudioBufferRef, RawSample, SampleBuffer, SignalSpec};
-use symphonia::core::conv::ConvertibleSample;
-use symphonia::core::units::Duration;
+use symphonia::core::audio::{Channels, SignalSpec};
+
+/// How much audio the null sink keeps ahead of what it pretends to play, like the ring buffer of the sound card
+const NULL_BUFFER: Duration = Duration::from_millis(250);
+/// Size of the header written by `FileAudioOutput` for WAV files
+const WAV_HEADER: usize = 44;
+/// The WAV header gets updated every time this much audio is written, so that an interrupted file is still readable
+const WAV_HEADER_INTERVAL_SECS: u64 = 1;
 
+/// Where the audio of a `Player` goes, to play without a sound card too
 pub trait AudioOutput {
-    fn write(&mut self, decoded: AudioBufferRef<'_>, volume: f32) -> Result<()>;
-    #[allow(dead_code)]
+    /// Writes interleaved samples, that must already match the spec of the output
+    fn write(&mut self, samples: &[f32], volume: f32) -> Result<()>;
+    /// Returns the spec the output was opened with
+    fn spec(&self) -> SignalSpec;
+    /// Discards the samples that weren't played yet
     fn flush(&mut self);
 }
 
@@ -27,13 +51,27 @@ pub enum AudioOutputError {
     OpenStreamError,
     PlayStreamError,
     StreamClosedError,
+    WriteError,
+}
+
+impl Display for AudioOutputError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            AudioOutputError::OpenStreamError => write!(f, "can't open the audio stream"),
+            AudioOutputError::PlayStreamError => write!(f, "can't play the audio stream"),
+            AudioOutputError::StreamClosedError => write!(f, "the audio stream was closed"),
+            AudioOutputError::WriteError => write!(f, "can't write the audio"),
+        }
+    }
 }
 
+impl Error for AudioOutputError {}
+
 pub type Result<T> = result::Result<T, AudioOutputError>;
 
 pub struct CpalAudioOutput;
 
-trait AudioOutputSample: Sample + ConvertibleSample + RawSample + Send + 'static {}
+trait AudioOutputSample: Sample + FromSample<f32> + ToSample<f32> + Default + Send + 'static {}
 
 impl AudioOutputSample for f32 {}
 
@@ -42,12 +80,11 @@ impl AudioOutputSample for i16 {}
 impl AudioOutputSample for u16 {}
 
 impl CpalAudioOutput {
-    pub fn try_open(spec: SignalSpec, duration: Duration) -> Result<Box<dyn AudioOutput>> {
-        // Get default host.
-        let host = cpal::default_host();
-
-        // Get the default audio output device.
-        let device = match host.default_output_device() {
+    /// Opens the device with its own config, so that the stream never needs to be reopened when the played track changes
+    /// The default device is used if `device` is `None` or if it can't be found
+    /// Everything sent to the device is also written to `tap`
+    pub fn try_open(device: Option<&str>, tap: VisualizationTap) -> Result<Box<dyn AudioOutput>> {
+        let device = match device::find_output_device(device) {
             Some(device) => device,
             _ => {
                 eprintln!("Failed to get default audio output device");
@@ -66,38 +103,48 @@ impl CpalAudioOutput {
             }
         };
 
+        let spec = SignalSpec::new(config.sample_rate().0, channels(config.channels()));
+
         // Select proper playback routine based on sample format.
         match config.sample_format() {
-            cpal::SampleFormat::F32 => {
-                CpalAudioOutputImpl::<f32>::try_open(spec, duration, &device)
-            }
-            cpal::SampleFormat::I16 => {
-                CpalAudioOutputImpl::<i16>::try_open(spec, duration, &device)
-            }
-            cpal::SampleFormat::U16 => {
-                CpalAudioOutputImpl::<u16>::try_open(spec, duration, &device)
-            }
-            _ => {
-                unimplemented!("sample format not yet implemented")
+            cpal::SampleFormat::F32 => CpalAudioOutputImpl::<f32>::try_open(spec, &device, tap),
+            cpal::SampleFormat::I16 => CpalAudioOutputImpl::<i16>::try_open(spec, &device, tap),
+            cpal::SampleFormat::U16 => CpalAudioOutputImpl::<u16>::try_open(spec, &device, tap),
+            format => {
+                eprintln!("sample format {format} not yet implemented");
+                Err(AudioOutputError::OpenStreamError)
             }
         }
     }
 }
 
+/// Returns the channel layout used by a device with `count` channels
+fn channels(count: u16) -> Channels {
+    match count {
+        1 => Channels::FRONT_LEFT,
+        2 => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+        count => Channels::from_bits_truncate((1u32 << count.min(26)) - 1),
+    }
+}
+
 struct CpalAudioOutputImpl<T: AudioOutputSample>
 where
     T: AudioOutputSample,
 {
+    ring_buf: SpscRb<T>,
     ring_buf_producer: Producer<T>,
-    sample_buf: SampleBuffer<T>,
+    samples: Vec<T>,
+    spec: SignalSpec,
+    failed: Arc<AtomicBool>,
+    #[allow(dead_code)]
     stream: cpal::Stream,
 }
 
 impl<T: AudioOutputSample + cpal::SizedSample> CpalAudioOutputImpl<T> {
     pub fn try_open(
         spec: SignalSpec,
-        duration: Duration,
         device: &cpal::Device,
+        tap: VisualizationTap,
     ) -> Result<Box<dyn AudioOutput>> {
         let num_channels = spec.channels.count();
 
@@ -114,16 +161,30 @@ impl<T: AudioOutputSample + cpal::SizedSample> CpalAudioOutputImpl<T> {
         let ring_buf = SpscRb::new(ring_len);
         let (ring_buf_producer, ring_buf_consumer) = (ring_buf.producer(), ring_buf.consumer());
 
+        // Set by the stream when the device fails (e.g. it gets disconnected), so that it can be reopened
+        let failed = Arc::new(AtomicBool::new(false));
+        let f = failed.clone();
+
         let stream_result = device.build_output_stream(
             &config,
-            move |data: &mut [T], _: &cpal::OutputCallbackInfo| {
+            move |data: &mut [T], info: &cpal::OutputCallbackInfo| {
                 // Write out as many samples as possible from the ring buffer to the audio
                 // output.
                 let written = ring_buf_consumer.read(data).unwrap_or(0);
                 // Mute any remaining samples.
-                data[written..].iter_mut().for_each(|s| *s = T::MID);
+                data[written..].iter_mut().for_each(|s| *s = T::EQUILIBRIUM);
+                // What the device plays is what gets visualized, silence included
+                let timestamp = info.timestamp();
+                tap.write(
+                    data,
+                    spec,
+                    timestamp.playback.duration_since(&timestamp.callback),
+                );
+            },
+            move |err| {
+                eprintln!("audio output error: {:?}", err);
+                f.store(true, Ordering::Relaxed);
             },
-            move |err| eprintln!("audio output error: {:?}", err),
             None,
         );
 
@@ -142,46 +203,436 @@ impl<T: AudioOutputSample + cpal::SizedSample> CpalAudioOutputImpl<T> {
             return Err(AudioOutputError::PlayStreamError);
         }
 
-        let sample_buf = SampleBuffer::<T>::new(duration, spec);
-
         Ok(Box::new(CpalAudioOutputImpl {
+            ring_buf,
             ring_buf_producer,
-            sample_buf,
+            samples: vec![],
+            spec,
+            failed,
             stream,
         }))
     }
 }
 
 impl<T: AudioOutputSample> AudioOutput for CpalAudioOutputImpl<T> {
-    fn write(&mut self, decoded: AudioBufferRef<'_>, volume: f32) -> Result<()> {
+    fn write(&mut self, samples: &[f32], volume: f32) -> Result<()> {
         // Do nothing if there are no audio frames.
-        if decoded.frames() == 0 {
+        if samples.is_empty() {
             return Ok(());
         }
 
-        // Audio samples must be interleaved for cpal. Interleave the samples in the audio
-        // buffer into the sample buffer.
-        self.sample_buf.copy_interleaved_ref(decoded);
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| T::from_sample(sample * volume)));
 
-        // Write all the interleaved samples to the ring buffer.
-        let mut samples: Vec<T> = self.sample_buf.samples().to_vec();
-        for sample in samples.iter_mut() {
-            *sample = sample.mul_amp(volume.to_sample());
+        // Write all the interleaved samples to the ring buffer, without blocking forever if the stream stops reading them.
+        let mut samples = self.samples.as_slice();
+        while !samples.is_empty() {
+            if self.failed.load(Ordering::Relaxed) {
+                return Err(AudioOutputError::StreamClosedError);
+            }
+            match self.ring_buf_producer.write(samples) {
+                Ok(written) => samples = &samples[written..],
+                Err(_) => thread::sleep(Duration::from_millis(5)),
+            }
         }
 
-        while let Some(written) = self.ring_buf_producer.write_blocking(samples.as_slice()) {
-            samples = samples[written..].to_vec();
+        Ok(())
+    }
+
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    fn flush(&mut self) {
+        self.ring_buf.clear();
+    }
+}
+
+/// Opens an `AudioOutput`, used every time the output of a `Player` needs to be (re)opened
+pub type OutputOpener = dyn Fn(VisualizationTap) -> Result<Box<dyn AudioOutput>> + Send + Sync;
+
+/// Which output a `Player` plays on, see `Player::with_output`
+#[derive(Clone, Default)]
+pub enum OutputBackend {
+    /// The sound card, the device can be changed with `Player::set_device`
+    #[default]
+    Device,
+    /// Discards the audio, see `NullAudioOutput`
+    Null { spec: SignalSpec, paced: bool },
+    /// Writes the audio to `path`, see `FileAudioOutput`
+    File {
+        path: PathBuf,
+        format: FileFormat,
+        spec: SignalSpec,
+    },
+    /// Any other output, what gets played must be written to the tap to be visualized
+    Custom(Arc<OutputOpener>),
+}
+
+impl OutputBackend {
+    /// Returns whether it plays on a device, thus if it can be changed
+    pub fn is_device(&self) -> bool {
+        matches!(self, OutputBackend::Device)
+    }
+
+    /// Opens the output, `device` is only used by `OutputBackend::Device`
+    pub(crate) fn open(
+        &self,
+        device: Option<&str>,
+        tap: VisualizationTap,
+    ) -> Result<Box<dyn AudioOutput>> {
+        match self {
+            OutputBackend::Device => CpalAudioOutput::try_open(device, tap),
+            OutputBackend::Null { spec, paced } => {
+                Ok(Box::new(NullAudioOutput::new(*spec, *paced, tap)))
+            }
+            OutputBackend::File { path, format, spec } => {
+                match FileAudioOutput::create(path, *format, *spec, tap) {
+                    Ok(output) => Ok(Box::new(output)),
+                    Err(err) => {
+                        eprintln!("can't create {}: {err}", path.display());
+                        Err(AudioOutputError::OpenStreamError)
+                    }
+                }
+            }
+            OutputBackend::Custom(open) => open(tap),
+        }
+    }
+}
+
+impl Debug for OutputBackend {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputBackend::Device => write!(f, "Device"),
+            OutputBackend::Null { spec, paced } => f
+                .debug_struct("Null")
+                .field("spec", spec)
+                .field("paced", paced)
+                .finish(),
+            OutputBackend::File { path, format, spec } => f
+                .debug_struct("File")
+                .field("path", path)
+                .field("format", format)
+                .field("spec", spec)
+                .finish(),
+            OutputBackend::Custom(_) => write!(f, "Custom"),
+        }
+    }
+}
+
+/// Output that discards the audio, to play without a sound card (e.g. on a server)
+///
+/// If it's `paced` the audio takes as long as on a real device, otherwise it's consumed as fast as it's decoded
+pub struct NullAudioOutput {
+    spec: SignalSpec,
+    paced: bool,
+    tap: VisualizationTap,
+    samples: Vec<f32>,
+    /// When the frames written so far started to be played
+    start: Instant,
+    frames: u64,
+}
+
+impl NullAudioOutput {
+    pub fn new(spec: SignalSpec, paced: bool, tap: VisualizationTap) -> Self {
+        Self {
+            spec,
+            paced,
+            tap,
+            samples: vec![],
+            start: Instant::now(),
+            frames: 0,
         }
+    }
+}
 
+impl AudioOutput for NullAudioOutput {
+    fn write(&mut self, samples: &[f32], volume: f32) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        let rate = self.spec.rate as f64;
+        // Nothing was written for a while (e.g. it was paused), so it starts again from silence
+        if self.start.elapsed().as_secs_f64() > self.frames as f64 / rate {
+            self.flush();
+        }
+        self.frames += (samples.len() / self.spec.channels.count()) as u64;
+        let ahead = (self.frames as f64 / rate - self.start.elapsed().as_secs_f64()).max(0.0);
+
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| sample * volume));
+        if self.paced {
+            let ahead = Duration::from_secs_f64(ahead);
+            self.tap.write(&self.samples, self.spec, Some(ahead));
+            if let Some(wait) = ahead.checked_sub(NULL_BUFFER) {
+                thread::sleep(wait);
+            }
+        } else {
+            self.tap.write(&self.samples, self.spec, None);
+        }
         Ok(())
     }
 
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
     fn flush(&mut self) {
-        // Flush is best-effort, ignore the returned result.
-        let _ = self.stream.pause();
+        self.start = Instant::now();
+        self.frames = 0;
     }
 }
 
-pub fn try_open(spec: SignalSpec, duration: Duration) -> Result<Box<dyn AudioOutput>> {
-    CpalAudioOutput::try_open(spec, duration)
+/// How `FileAudioOutput` stores the audio
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum FileFormat {
+    /// WAV with 32 bit float samples
+    #[default]
+    Wav,
+    /// The raw container read by n_audio, see `raw_writer::RawWriter`
+    Raw(RawSampleFormat),
+}
+
+enum FileWriter {
+    Wav(BufWriter<File>),
+    Raw(RawWriter<BufWriter<File>>),
+}
+
+/// Output that writes the audio to a file, as fast as it's decoded
+///
+/// The header of a WAV file is completed when the output is dropped, and every second of audio meanwhile
+pub struct FileAudioOutput {
+    writer: FileWriter,
+    spec: SignalSpec,
+    tap: VisualizationTap,
+    samples: Vec<f32>,
+    wide: Vec<f64>,
+    frames: u64,
+    /// Frames written when the WAV header was last updated
+    saved: u64,
+}
+
+impl FileAudioOutput {
+    /// Creates the file at `path`, replacing it if it exists
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        format: FileFormat,
+        spec: SignalSpec,
+        tap: VisualizationTap,
+    ) -> io::Result<Self> {
+        let file = BufWriter::new(File::create(path)?);
+        let writer = match format {
+            FileFormat::Wav => FileWriter::Wav(file),
+            FileFormat::Raw(sample_format) => FileWriter::Raw(RawWriter::new(
+                file,
+                &RawHeader::from_spec(spec, sample_format),
+            )?),
+        };
+        let mut output = Self {
+            writer,
+            spec,
+            tap,
+            samples: vec![],
+            wide: vec![],
+            frames: 0,
+            saved: 0,
+        };
+        if let FileWriter::Wav(file) = &mut output.writer {
+            file.write_all(&wav_header(spec, 0))?;
+        }
+        Ok(output)
+    }
+
+    /// Rewrites the WAV header with the length written so far
+    fn update_header(&mut self) -> io::Result<()> {
+        if let FileWriter::Wav(file) = &mut self.writer {
+            file.seek(SeekFrom::Start(0))?;
+            file.write_all(&wav_header(self.spec, self.frames))?;
+            file.seek(SeekFrom::End(0))?;
+            file.flush()?;
+        }
+        self.saved = self.frames;
+        Ok(())
+    }
+
+    fn write_samples(&mut self) -> io::Result<()> {
+        match &mut self.writer {
+            FileWriter::Wav(file) => {
+                for sample in &self.samples {
+                    file.write_all(&sample.to_le_bytes())?;
+                }
+            }
+            FileWriter::Raw(raw) => {
+                self.wide.clear();
+                self.wide
+                    .extend(self.samples.iter().map(|&sample| sample as f64));
+                raw.write_samples(&self.wide)?;
+            }
+        }
+        self.frames += (self.samples.len() / self.spec.channels.count()) as u64;
+        if self.frames - self.saved >= WAV_HEADER_INTERVAL_SECS * self.spec.rate as u64 {
+            self.update_header()?;
+        }
+        Ok(())
+    }
+}
+
+impl AudioOutput for FileAudioOutput {
+    fn write(&mut self, samples: &[f32], volume: f32) -> Result<()> {
+        if samples.is_empty() {
+            return Ok(());
+        }
+
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| sample * volume));
+        self.tap.write(&self.samples, self.spec, None);
+        self.write_samples().map_err(|err| {
+            eprintln!("audio file write error: {err}");
+            AudioOutputError::WriteError
+        })
+    }
+
+    fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    /// What was written is already in the file, so there's nothing to discard
+    fn flush(&mut self) {}
+}
+
+impl Drop for FileAudioOutput {
+    fn drop(&mut self) {
+        if let Err(err) = self.update_header() {
+            eprintln!("audio file write error: {err}");
+        }
+    }
+}
+
+/// Header of a WAV file with 32 bit float samples, long `frames` frames
+fn wav_header(spec: SignalSpec, frames: u64) -> [u8; WAV_HEADER] {
+    let channels = spec.channels.count() as u16;
+    let block = channels as u32 * 4;
+    // Files bigger than 4 GiB can't be described, readers will go on until the end anyway
+    let data = u32::try_from(frames * block as u64)
+        .unwrap_or(u32::MAX)
+        .min(u32::MAX - WAV_HEADER as u32);
+
+    let mut header = [0; WAV_HEADER];
+    let fields: [&[u8]; 13] = [
+        b"RIFF",
+        &(data + WAV_HEADER as u32 - 8).to_le_bytes(),
+        b"WAVE",
+        b"fmt ",
+        &16u32.to_le_bytes(),
+        // IEEE float
+        &3u16.to_le_bytes(),
+        &channels.to_le_bytes(),
+        &spec.rate.to_le_bytes(),
+        &(spec.rate * block).to_le_bytes(),
+        &(block as u16).to_le_bytes(),
+        &32u16.to_le_bytes(),
+        b"data",
+        &data.to_le_bytes(),
+    ];
+    let mut start = 0;
+    for field in fields {
+        header[start..start + field.len()].copy_from_slice(field);
+        start += field.len();
+    }
+    header
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const SAMPLES: [f32; 4] = [0.5, -0.5, 1.0, -1.0];
+
+    fn stereo() -> SignalSpec {
+        SignalSpec::new(8000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+    }
+
+    /// Writes `SAMPLES` at half volume to a new file of the given format, returning its bytes
+    fn write_file(format: FileFormat) -> Vec<u8> {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out");
+        let mut output =
+            FileAudioOutput::create(&path, format, stereo(), VisualizationTap::default()).unwrap();
+        output.write(&SAMPLES, 0.5).unwrap();
+        drop(output);
+        fs::read(path).unwrap()
+    }
+
+    #[test]
+    fn writes_wav() {
+        let bytes = write_file(FileFormat::Wav);
+        let data = SAMPLES.len() as u32 * 4;
+
+        let mut header = vec![];
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(data + 36).to_le_bytes());
+        header.extend_from_slice(b"WAVEfmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&3u16.to_le_bytes());
+        header.extend_from_slice(&2u16.to_le_bytes());
+        header.extend_from_slice(&8000u32.to_le_bytes());
+        header.extend_from_slice(&(8000u32 * 8).to_le_bytes());
+        header.extend_from_slice(&8u16.to_le_bytes());
+        header.extend_from_slice(&32u16.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&data.to_le_bytes());
+
+        assert_eq!(bytes[..WAV_HEADER], header[..]);
+        assert_eq!(
+            bytes[WAV_HEADER..],
+            [0.25f32, -0.25, 0.5, -0.5]
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn writes_raw() {
+        let bytes = write_file(FileFormat::Raw(RawSampleFormat::F64));
+
+        let mut header = vec![];
+        header.extend_from_slice(b"SbirdRaw");
+        header.extend_from_slice(&0u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes());
+        header.extend_from_slice(&4u16.to_le_bytes());
+        header.extend_from_slice(&8000u32.to_le_bytes());
+        header.extend_from_slice(&2u32.to_le_bytes());
+        header.extend_from_slice(&3u32.to_le_bytes());
+        header.extend_from_slice(&0u32.to_le_bytes());
+
+        assert_eq!(bytes[..header.len()], header[..]);
+        assert_eq!(
+            bytes[header.len()..],
+            [0.25f64, -0.25, 0.5, -0.5]
+                .iter()
+                .flat_map(|sample| sample.to_le_bytes())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn updates_the_wav_header_while_writing() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("out.wav");
+        let spec = stereo();
+        let mut output =
+            FileAudioOutput::create(&path, FileFormat::Wav, spec, VisualizationTap::default())
+                .unwrap();
+
+        let one_second = vec![0.0; spec.rate as usize * 2];
+        output.write(&one_second, 1.0).unwrap();
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(bytes[..WAV_HEADER], wav_header(spec, spec.rate as u64));
+    }
 }
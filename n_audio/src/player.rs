@@ -1,15 +1,34 @@
+use crate::dca::DcaIndexMode;
+use crate::dsp::Dsp;
+use crate::engine::AudioEngine;
+use crate::event::{EventBus, PlayerEvent};
 use crate::music_track::MusicTrack;
-use crate::{output, Message, TrackTime, CODEC_REGISTRY};
-use flume::{Receiver, SendError, Sender};
+use crate::output::OutputBackend;
+use crate::replay_gain::{ReplayGain, ReplayGainMode};
+use crate::resampler::ResamplerQuality;
+use crate::time_stretch::{TimeStretchMode, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
+use crate::visualization::VisualizationTap;
+use crate::{Crossfade, CrossfadeCurve, Message, NError, TrackTime, CODEC_REGISTRY};
+use flume::{Receiver, Sender, TryRecvError};
 use std::ffi::OsStr;
+use std::io::ErrorKind;
+use std::mem;
 use std::path::Path;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::thread;
 use std::thread::JoinHandle;
-use std::{io, thread};
-use symphonia::core::codecs::DecoderOptions;
-use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
-use symphonia::core::units::Time;
+use std::time::{Duration, Instant};
+use symphonia::core::audio::{AsAudioBufferRef, AudioBuffer, Signal, SignalSpec};
+use symphonia::core::codecs::{Decoder, DecoderOptions};
+use symphonia::core::errors::{Error, SeekErrorKind};
+use symphonia::core::formats::{FormatReader, Packet, SeekMode, SeekTo};
+use symphonia::core::units::{Time, TimeBase};
 // TODO: update docs
 
+/// How often `PlayerEvent::Position` gets sent by default
+const DEFAULT_POSITION_INTERVAL: Duration = Duration::from_millis(250);
+
 /// The main actor for everything.
 ///
 /// Using this struct is really easy, just add a file you want to play (be sure of it being an audio file supported by Symphonia or it being an opus file) and call `Player::play` and you've done everything!
@@ -18,31 +37,65 @@ pub struct Player {
     is_paused: bool,
     volume: f32,
     playback_speed: f32,
+    crossfade: Crossfade,
+    replay_gain_mode: ReplayGainMode,
+    output: OutputBackend,
+    device: Option<String>,
+    resampler_quality: ResamplerQuality,
+    time_stretch_mode: TimeStretchMode,
+    position_interval: Duration,
+    events: EventBus,
+    tap: VisualizationTap,
     cached_get_time: Option<TrackTime>,
+    /// Whether a track was sent to the thread and it didn't end yet, the thread lives longer than a single track
+    playing: AtomicBool,
+    /// Id given to the last track sent to the thread
+    last_id: AtomicU64,
     thread: Option<JoinHandle<()>>,
     tx: Option<Sender<Message>>,
     rx_t: Option<Receiver<Message>>,
     rx_e: Option<Receiver<Message>>,
+    rx_n: Option<Receiver<Message>>,
+    rx_err: Option<Receiver<Message>>,
 }
 
 impl Player {
-    /// Instance a new `Player`
+    /// Instance a new `Player`, playing on the sound card
     pub fn new(volume: f32, playback_speed: f32) -> Self {
+        Self::with_output(volume, playback_speed, OutputBackend::Device)
+    }
+
+    /// Instance a new `Player` playing on `output`, e.g. to play without a sound card or to write what's played to a
+    /// file
+    pub fn with_output(volume: f32, playback_speed: f32, output: OutputBackend) -> Self {
         Player {
             is_paused: false,
             volume,
             playback_speed,
+            crossfade: Crossfade::default(),
+            replay_gain_mode: ReplayGainMode::default(),
+            output,
+            device: None,
+            resampler_quality: ResamplerQuality::default(),
+            time_stretch_mode: TimeStretchMode::default(),
+            position_interval: DEFAULT_POSITION_INTERVAL,
+            events: EventBus::default(),
+            tap: VisualizationTap::default(),
             cached_get_time: None,
+            playing: AtomicBool::new(false),
+            last_id: AtomicU64::new(0),
             thread: None,
             tx: None,
             rx_t: None,
             rx_e: None,
+            rx_n: None,
+            rx_err: None,
         }
     }
 
     /// Pauses the current playing track, if any
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn pause(&mut self) -> Result<(), SendError<Message>> {
+    pub async fn pause(&mut self) -> Result<(), NError> {
         if let Some(tx) = &self.tx {
             tx.send_async(Message::Pause).await?;
             self.is_paused = true;
@@ -52,7 +105,7 @@ impl Player {
 
     /// Unpauses the current playing track, if any
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn unpause(&mut self) -> Result<(), SendError<Message>> {
+    pub async fn unpause(&mut self) -> Result<(), NError> {
         if let Some(tx) = &self.tx {
             tx.send_async(Message::Play).await?;
             self.is_paused = false;
@@ -75,7 +128,7 @@ impl Player {
 
     /// Sets the output volume
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn set_volume(&mut self, volume: f32) -> Result<(), SendError<Message>> {
+    pub async fn set_volume(&mut self, volume: f32) -> Result<(), NError> {
         if let Some(tx) = &self.tx {
             tx.send_async(Message::Volume(volume)).await?;
         }
@@ -83,24 +136,143 @@ impl Player {
         Ok(())
     }
 
-    /// Sets the playback speed
+    pub fn get_playback_speed(&self) -> f32 {
+        self.playback_speed
+    }
+
+    /// Sets the playback speed, clamped between `MIN_PLAYBACK_SPEED` and `MAX_PLAYBACK_SPEED`
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn set_playback_speed(
-        &mut self,
-        playback_speed: f32,
-    ) -> Result<(), SendError<Message>> {
+    pub async fn set_playback_speed(&mut self, playback_speed: f32) -> Result<(), NError> {
+        let playback_speed = playback_speed.clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED);
         if let Some(tx) = &self.tx {
             tx.send_async(Message::PlaybackSpeed(playback_speed))
                 .await?;
-            self.playback_speed = playback_speed;
         }
+        self.playback_speed = playback_speed;
+        Ok(())
+    }
+
+    pub fn get_time_stretch_mode(&self) -> TimeStretchMode {
+        self.time_stretch_mode
+    }
+
+    /// Sets whether the playback speed preserves the pitch of the tracks or changes it like a tape
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_time_stretch_mode(
+        &mut self,
+        time_stretch_mode: TimeStretchMode,
+    ) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::TimeStretchMode(time_stretch_mode))
+                .await?;
+        }
+        self.time_stretch_mode = time_stretch_mode;
+        Ok(())
+    }
+
+    pub fn get_crossfade(&self) -> Crossfade {
+        self.crossfade
+    }
+
+    /// Sets how the current track gets mixed with the next one, if any is preloaded
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_crossfade(&mut self, crossfade: Crossfade) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::Crossfade(crossfade)).await?;
+        }
+        self.crossfade = crossfade;
+        Ok(())
+    }
+
+    pub fn get_replay_gain_mode(&self) -> ReplayGainMode {
+        self.replay_gain_mode
+    }
+
+    /// Sets which ReplayGain values get applied to the tracks
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_replay_gain_mode(
+        &mut self,
+        replay_gain_mode: ReplayGainMode,
+    ) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::ReplayGainMode(replay_gain_mode))
+                .await?;
+        }
+        self.replay_gain_mode = replay_gain_mode;
+        Ok(())
+    }
+
+    /// Returns the name of the output device used, `None` if it's the default one
+    pub fn get_device(&self) -> Option<&str> {
+        self.device.as_deref()
+    }
+
+    /// Returns the output chosen when the `Player` was created
+    pub fn get_output(&self) -> &OutputBackend {
+        &self.output
+    }
+
+    /// Sets the output device used, `None` to use the default one
+    /// The playback continues on the new device from the same position
+    /// It only has effect when playing on `OutputBackend::Device`
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_device(&mut self, device: Option<String>) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::Device(device.clone())).await?;
+        }
+        self.device = device;
+        Ok(())
+    }
+
+    pub fn get_resampler_quality(&self) -> ResamplerQuality {
+        self.resampler_quality
+    }
+
+    /// Sets the quality used to convert the tracks to the sample rate of the output device
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_resampler_quality(
+        &mut self,
+        resampler_quality: ResamplerQuality,
+    ) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::ResamplerQuality(resampler_quality))
+                .await?;
+        }
+        self.resampler_quality = resampler_quality;
+        Ok(())
+    }
+
+    /// Returns a new `Receiver` of the events sent by this player, see `EventBus::subscribe`
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.events.subscribe()
+    }
+
+    pub(crate) fn event_bus(&self) -> &EventBus {
+        &self.events
+    }
+
+    pub fn get_position_interval(&self) -> Duration {
+        self.position_interval
+    }
+
+    /// Sets how often `PlayerEvent::Position` gets sent while playing
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_position_interval(
+        &mut self,
+        position_interval: Duration,
+    ) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::PositionInterval(position_interval))
+                .await?;
+        }
+        self.position_interval = position_interval;
         Ok(())
     }
 
     /// Seeks to the set timestamp
-    /// Be aware that if the timestamp isn't valid the track thread will panic
+    /// If the timestamp isn't valid the error gets reported by `Player::get_errors`
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn seek_to(&self, seconds: u64, mut frac: f64) -> Result<(), SendError<Message>> {
+    pub async fn seek_to(&self, seconds: u64, mut frac: f64) -> Result<(), NError> {
         if let Some(tx) = &self.tx {
             if seconds == 0 && frac == 0.0 {
                 frac = 0.01;
@@ -138,6 +310,7 @@ impl Player {
         if let Some(rx_e) = &self.rx_e {
             while let Ok(message) = rx_e.try_recv() {
                 if let Message::End = message {
+                    self.playing.store(false, Ordering::Relaxed);
                     return true;
                 }
             }
@@ -145,211 +318,856 @@ impl Player {
         false
     }
 
+    /// Returns the tap of what is being heard, to visualize it
+    ///
+    /// It stays the same for the whole life of the `Player`, even if the output device changes
+    pub fn visualization(&self) -> VisualizationTap {
+        self.tap.clone()
+    }
+
+    /// Returns the names of the processors in the DSP chain, in the order they are run
+    /// It only errors if it can't reach the thread (so something serious may have happened)
+    pub async fn get_dsp(&mut self) -> Result<Vec<String>, NError> {
+        let (tx, rx) = flume::bounded(1);
+        self.engine().send_async(Message::DspNames(tx)).await?;
+        rx.recv_async().await.map_err(|_| NError::Disconnected)
+    }
+
+    /// Inserts `dsp` in the DSP chain at `index` (clamped to its length), replacing the processor with the same name
+    /// The change is heard right away, even while playing
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn insert_dsp(&mut self, index: usize, dsp: Box<dyn Dsp>) -> Result<(), NError> {
+        self.engine()
+            .send_async(Message::DspInsert(index, dsp))
+            .await?;
+        Ok(())
+    }
+
+    /// Adds `dsp` at the end of the DSP chain, see `Player::insert_dsp`
+    pub async fn push_dsp(&mut self, dsp: Box<dyn Dsp>) -> Result<(), NError> {
+        self.insert_dsp(usize::MAX, dsp).await
+    }
+
+    /// Removes the processor called `name` from the DSP chain
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn remove_dsp(&mut self, name: &str) -> Result<(), NError> {
+        self.engine()
+            .send_async(Message::DspRemove(name.to_string()))
+            .await?;
+        Ok(())
+    }
+
+    /// Sets the parameter `parameter` of the processor called `dsp`, see `Dsp::set_parameter`
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_dsp_parameter(
+        &mut self,
+        dsp: &str,
+        parameter: &str,
+        value: f32,
+    ) -> Result<(), NError> {
+        self.engine()
+            .send_async(Message::DspParameter(
+                dsp.to_string(),
+                parameter.to_string(),
+                value,
+            ))
+            .await?;
+        Ok(())
+    }
+
+    /// Skips the processor called `dsp` without removing it from the DSP chain, or runs it again
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn set_dsp_bypass(&mut self, dsp: &str, bypassed: bool) -> Result<(), NError> {
+        self.engine()
+            .send_async(Message::DspBypass(dsp.to_string(), bypassed))
+            .await?;
+        Ok(())
+    }
+
+    /// Returns the errors that the track thread has reported since the last call
+    /// A track that can't be played gets skipped, so `Player::has_ended` will also return `true`
+    pub fn get_errors(&self) -> Vec<Arc<NError>> {
+        let mut errors = vec![];
+        if let Some(rx_err) = &self.rx_err {
+            while let Ok(message) = rx_err.try_recv() {
+                if let Message::Error(err) = message {
+                    errors.push(err);
+                }
+            }
+        }
+        errors
+    }
+
     /// Returns whether if any track is playing
     /// Note that this function doesn't check if the track is paused or not
     pub fn is_playing(&self) -> bool {
-        self.thread.is_some()
+        // The track thread may have sent `Message::End` without `Player::has_ended` being called yet
+        self.playing.load(Ordering::Relaxed)
+            && self.rx_e.as_ref().is_none_or(|rx_e| rx_e.is_empty())
     }
 
     /// Ends the current track playing, if any
     /// It only errors if it can't send the message (so something serious may have happened)
-    pub async fn end_current(&self) -> Result<(), SendError<Message>> {
+    pub async fn end_current(&self) -> Result<(), NError> {
         if let Some(tx) = &self.tx {
             tx.send_async(Message::Exit).await?;
         }
+        self.playing.store(false, Ordering::Relaxed);
         Ok(())
     }
 
-    /// Plays a certain track given its file path
+    /// Plays a certain track given its file path or its HTTP(S) URL, returning its id (see `Player::play_with_gain`)
     pub fn play_from_path<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>>(
         &mut self,
         path: P,
-    ) -> io::Result<()> {
-        let music_track = MusicTrack::new(path)?;
-        self.play(music_track.get_format()?);
-
-        Ok(())
+    ) -> Result<u64, NError> {
+        let music_track = MusicTrack::new(path)?
+            .with_dca_index(DcaIndexMode::Cached)
+            .with_events(self.events.clone());
+        let (format, replay_gain) = music_track.get_format_with_gain()?;
+        self.play_with_gain(format, replay_gain)
     }
 
-    /// Plays a certain track
-    pub fn play_from_track(&mut self, track: &MusicTrack) -> io::Result<()> {
-        self.play(track.get_format()?);
-        Ok(())
+    /// Plays a certain track, returning its id (see `Player::play_with_gain`)
+    pub fn play_from_track(&mut self, track: &MusicTrack) -> Result<u64, NError> {
+        let (format, replay_gain) = track.get_format_with_gain()?;
+        self.play_with_gain(format, replay_gain)
     }
 
-    /// Plays a certain track given its format
-    pub fn play(&mut self, format: Box<dyn FormatReader>) {
-        let volume = self.volume;
-        let playback_speed = self.playback_speed;
+    /// Plays a certain track given its format, returning its id (see `Player::play_with_gain`)
+    /// It only errors if it can't send the track to the track thread (so something serious may have happened)
+    pub fn play(&mut self, format: Box<dyn FormatReader>) -> Result<u64, NError> {
+        self.play_with_gain(format, ReplayGain::default())
+    }
 
-        let (tx, rx) = flume::unbounded();
+    /// Plays a certain track given its format and the ReplayGain info that couldn't be read from the format itself
+    /// Errors found while playing it are reported by `Player::get_errors`
+    /// Returns the id of the track, which tells what `PlayerEvent::LengthMeasured` is about
+    /// It only errors if it can't send the track to the track thread (so something serious may have happened)
+    pub fn play_with_gain(
+        &mut self,
+        format: Box<dyn FormatReader>,
+        replay_gain: ReplayGain,
+    ) -> Result<u64, NError> {
+        // Every track gets its own channels, so that messages sent about the previous one are never received
         let (tx_t, rx_t) = flume::unbounded();
         let (tx_e, rx_e) = flume::unbounded();
+        let (tx_n, rx_n) = flume::unbounded();
 
-        let thread =
-            thread::spawn(move || Self::thread_fn(format, rx, tx_t, tx_e, volume, playback_speed));
+        let id = self.next_id();
+        let tx = self.engine();
+        tx.send(Message::Load(id, format, replay_gain, tx_t, tx_e, tx_n))?;
 
         self.is_paused = false;
+        self.playing.store(true, Ordering::Relaxed);
+        self.rx_n = Some(rx_n);
         self.rx_e = Some(rx_e);
         self.rx_t = Some(rx_t);
-        self.tx = Some(tx);
+        Ok(id)
+    }
+
+    fn next_id(&self) -> u64 {
+        self.last_id.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Returns the sender of the audio engine thread, (re)spawning it if it isn't running
+    fn engine(&mut self) -> Sender<Message> {
+        if let Some(tx) = &self.tx {
+            if self.thread.as_ref().is_some_and(|t| !t.is_finished()) {
+                return tx.clone();
+            }
+        }
+
+        let volume = self.volume;
+        let playback_speed = self.playback_speed;
+        let crossfade = self.crossfade;
+        let replay_gain_mode = self.replay_gain_mode;
+        let output = self.output.clone();
+        let device = self.device.clone();
+        let resampler_quality = self.resampler_quality;
+        let time_stretch_mode = self.time_stretch_mode;
+        let position_interval = self.position_interval;
+        let events = self.events.clone();
+        let tap = self.tap.clone();
+        let (tx, rx) = flume::unbounded();
+        // Errors aren't about a single track, so they are received from the same channel for the whole life of the thread
+        let (tx_err, rx_err) = flume::unbounded();
+
+        let thread = thread::spawn(move || {
+            Self::thread_fn(
+                rx,
+                tx_err,
+                events,
+                tap,
+                position_interval,
+                output,
+                device,
+                resampler_quality,
+                time_stretch_mode,
+                volume,
+                playback_speed,
+                crossfade,
+                replay_gain_mode,
+            )
+        });
+
+        self.tx = Some(tx.clone());
+        self.rx_err = Some(rx_err);
         self.thread = Some(thread);
+        tx
+    }
+
+    /// Queues a track to be played as soon as the current one ends, reusing the same output so
+    /// that there's no gap between the two
+    /// If `crossfade` is `true` and crossfading is enabled, the two tracks will be mixed together
+    /// Returns the id of the track, see `Player::play_with_gain`
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn preload(
+        &self,
+        format: Box<dyn FormatReader>,
+        replay_gain: ReplayGain,
+        crossfade: bool,
+    ) -> Result<u64, NError> {
+        let id = self.next_id();
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::Preload(id, format, replay_gain, crossfade))
+                .await?;
+        }
+        Ok(id)
+    }
+
+    /// Discards the preloaded track, if any
+    /// It only errors if it can't send the message (so something serious may have happened)
+    pub async fn clear_preload(&self) -> Result<(), NError> {
+        if let Some(tx) = &self.tx {
+            tx.send_async(Message::ClearPreload).await?;
+        }
+        Ok(())
+    }
+
+    /// Returns whether the track thread has sent `Message::Advanced`, thus switching to the preloaded track
+    pub fn has_advanced(&self) -> bool {
+        if let Some(rx_n) = &self.rx_n {
+            while let Ok(message) = rx_n.try_recv() {
+                if let Message::Advanced = message {
+                    return true;
+                }
+            }
+        }
+        false
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn thread_fn(
-        mut format: Box<dyn FormatReader>,
         rx: Receiver<Message>,
-        tx_t: Sender<Message>,
-        tx_e: Sender<Message>,
+        tx_err: Sender<Message>,
+        events: EventBus,
+        tap: VisualizationTap,
+        position_interval: Duration,
+        output: OutputBackend,
+        device: Option<String>,
+        resampler_quality: ResamplerQuality,
+        time_stretch_mode: TimeStretchMode,
         mut volume: f32,
         mut playback_speed: f32,
+        mut crossfade: Crossfade,
+        mut replay_gain_mode: ReplayGainMode,
     ) {
-        // Vars used for audio output
-        let track = format.default_track().expect("Can't load tracks");
-        let track_id = track.id;
-        let time_base = track.codec_params.time_base;
-        let duration = track
-            .codec_params
-            .n_frames
-            .map(|frames| track.codec_params.start_ts + frames)
-            .unwrap();
-
-        let mut decoder = CODEC_REGISTRY
-            .make(&track.codec_params, &DecoderOptions::default())
-            .expect("Can't load decoder");
-        let mut audio_output = None;
-
-        let mut spec = None;
-        let mut dur = None;
+        // The output is kept open for the whole life of the thread
+        let mut engine = AudioEngine::new(output, device, resampler_quality, tap);
+        engine.set_time_stretch_mode(time_stretch_mode);
+        let mut playback: Option<Playback> = None;
 
         // Vars used to control audio output
         let mut is_paused = false;
-        let mut exit = false;
+        let mut ticker = PositionTicker::new(position_interval);
+
+        // The owner may have stopped listening, it'll be noticed when it disconnects from the thread
+        let report = |err: NError| {
+            let err = Arc::new(err);
+            events.emit(PlayerEvent::Error(err.clone()));
+            let _ = tx_err.send(Message::Error(err));
+        };
 
         loop {
-            if let Some(message) = if is_paused {
-                rx.recv().ok()
+            let message = if is_paused || playback.is_none() {
+                match rx.recv() {
+                    Ok(message) => Some(message),
+                    Err(_) => return,
+                }
             } else {
-                rx.try_recv().ok()
-            } {
+                match rx.try_recv() {
+                    Ok(message) => Some(message),
+                    Err(TryRecvError::Empty) => None,
+                    Err(TryRecvError::Disconnected) => return,
+                }
+            };
+
+            if let Some(message) = message {
                 match message {
-                    Message::Play => is_paused = false,
-                    Message::Pause => is_paused = true,
-                    // from: https://stackoverflow.com/a/1165198
-                    Message::Volume(v) => volume = 1.0 - (1.0 - (v * v)).sqrt(),
+                    Message::Play => {
+                        if is_paused && playback.is_some() {
+                            events.emit(PlayerEvent::Resumed);
+                        }
+                        is_paused = false;
+                    }
+                    Message::Pause => {
+                        if !is_paused && playback.is_some() {
+                            events.emit(PlayerEvent::Paused);
+                        }
+                        is_paused = true;
+                    }
+                    Message::Volume(v) => volume = perceived_volume(v),
                     Message::PlaybackSpeed(speed) => playback_speed = speed,
+                    Message::Crossfade(c) => crossfade = c,
+                    Message::ReplayGainMode(mode) => replay_gain_mode = mode,
+                    Message::Device(device) => {
+                        engine.set_device(device.clone());
+                        events.emit(PlayerEvent::DeviceChanged(device));
+                    }
+                    Message::ResamplerQuality(quality) => engine.set_resampler_quality(quality),
+                    Message::TimeStretchMode(mode) => engine.set_time_stretch_mode(mode),
+                    Message::PositionInterval(interval) => ticker.interval = interval,
+                    Message::DspInsert(index, dsp) => engine.dsp_mut().insert(index, dsp),
+                    Message::DspRemove(name) => {
+                        engine.dsp_mut().remove(&name);
+                    }
+                    Message::DspParameter(dsp, name, value) => {
+                        engine.dsp_mut().set_parameter(&dsp, &name, value);
+                    }
+                    Message::DspBypass(dsp, bypassed) => {
+                        engine.dsp_mut().set_bypass(&dsp, bypassed);
+                    }
+                    Message::DspNames(tx) => {
+                        let _ = tx.send(engine.dsp_mut().names());
+                    }
+                    Message::Load(id, format, replay_gain, tx_t, tx_e, tx_n) => {
+                        engine.flush();
+                        is_paused = false;
+                        playback = match Source::new(id, format, replay_gain, false) {
+                            Ok(source) => {
+                                ticker.reset();
+                                events.emit(PlayerEvent::TrackStarted);
+                                Some(Playback {
+                                    source,
+                                    preloaded: None,
+                                    fade: None,
+                                    tx_t,
+                                    tx_e,
+                                    tx_n,
+                                })
+                            }
+                            Err(err) => {
+                                report(err);
+                                // Let the owner move on to another track
+                                let _ = tx_e.send(Message::End);
+                                events.emit(PlayerEvent::Ended);
+                                None
+                            }
+                        };
+                    }
+                    Message::Preload(id, format, replay_gain, can_crossfade) => {
+                        if let Some(playback) = &mut playback {
+                            match Source::new(id, format, replay_gain, can_crossfade) {
+                                Ok(source) => playback.preloaded = Some(source),
+                                Err(err) => report(err),
+                            }
+                        }
+                    }
+                    Message::ClearPreload => {
+                        if let Some(playback) = &mut playback {
+                            playback.preloaded = None;
+                        }
+                    }
                     Message::Exit => {
-                        exit = true;
-                        break;
+                        engine.flush();
+                        playback = None;
                     }
                     Message::Seek(time) => {
-                        if let Err(err) = format.seek(
-                            SeekMode::Coarse,
-                            SeekTo::Time {
-                                time,
-                                track_id: Some(track_id),
-                            },
-                        ) {
-                            println!("error seeking");
-                            if !err.to_string().contains("end of stream") {
-                                eprintln!(
-                                    "Couldn't seek to position {}+{}\nError: {}",
-                                    time.seconds, time.frac, err
-                                );
-                            } else {
-                                break;
+                        if let Some(p) = &mut playback {
+                            match p.source.format.seek(
+                                SeekMode::Coarse,
+                                SeekTo::Time {
+                                    time,
+                                    track_id: Some(p.source.track_id),
+                                },
+                            ) {
+                                Ok(seeked) => {
+                                    if let Some(time) = p.source.time(seeked.actual_ts) {
+                                        events.emit(PlayerEvent::Seeked(time));
+                                    }
+                                }
+                                Err(err) => {
+                                    if !is_seek_past_end(&err) {
+                                        report(err.into());
+                                    } else {
+                                        p.end(&events);
+                                        playback = None;
+                                        continue;
+                                    }
+                                }
                             }
+                            ticker.reset();
+                            engine.flush();
+                            p.source.primed = None;
+                            p.fade = None;
                         }
                     }
                     _ => {}
                 }
             }
 
-            if exit {
-                break;
+            let Some(p) = &mut playback else {
+                continue;
+            };
+            if is_paused {
+                continue;
             }
 
-            if !is_paused {
-                let packet = match format.next_packet() {
+            let playing = 'step: {
+                if p.fade.is_none() && crossfade.is_enabled() {
+                    if let Some(length) = p
+                        .preloaded
+                        .as_ref()
+                        .and_then(|next| p.source.crossfade_length(next, crossfade.duration))
+                    {
+                        let outgoing = mem::replace(&mut p.source, p.preloaded.take().unwrap());
+                        p.fade = Some(Fade::new(outgoing, length));
+                        // The owner must be able to see the message as soon as it gets the event
+                        if p.tx_n.send(Message::Advanced).is_err() {
+                            break 'step false;
+                        }
+                        events.emit(PlayerEvent::TrackStarted);
+                    }
+                }
+
+                // The first buffer of a preloaded track was already decoded ahead of time
+                if let Some((ts, mut buffer)) = p.source.primed.take() {
+                    if let Some(time) = p.source.send_time(ts, &p.tx_t) {
+                        ticker.tick(time, &events);
+                    }
+                    let gain = p.source.replay_gain.factor(replay_gain_mode);
+                    Fade::apply(
+                        &mut p.fade,
+                        &mut buffer,
+                        crossfade.curve,
+                        gain,
+                        replay_gain_mode,
+                        &events,
+                    );
+                    if let Err(err) =
+                        engine.write(buffer.as_audio_buffer_ref(), volume * gain, playback_speed)
+                    {
+                        report(err);
+                    }
+                    break 'step true;
+                }
+
+                let packet = match p.source.format.next_packet() {
                     Ok(packet) => packet,
-                    Err(_err) => {
-                        break;
+                    Err(err) => {
+                        if !is_end_of_stream(&err) {
+                            report(err.into());
+                        } else if let Some(length) = p.source.measured_length() {
+                            events.emit(PlayerEvent::LengthMeasured(p.source.id, length));
+                        }
+                        if let Some(next) = p.preloaded.take() {
+                            p.source = next;
+                            let sent = p.tx_n.send(Message::Advanced).is_ok();
+                            events.emit(PlayerEvent::TrackStarted);
+                            break 'step sent;
+                        }
+                        p.end(&events);
+                        break 'step false;
                     }
                 };
 
-                if packet.track_id() != track_id {
-                    continue;
+                if packet.track_id() != p.source.track_id {
+                    break 'step true;
                 }
+                p.source.last_ts = packet.ts();
+                p.source.end_ts = packet.ts() + packet.dur();
 
-                while !format.metadata().is_latest() {
-                    format.metadata().pop();
+                while !p.source.format.metadata().is_latest() {
+                    p.source.format.metadata().pop();
                 }
-                if let Some(time_base) = time_base {
-                    let position = time_base.calc_time(packet.ts());
-                    let length = time_base.calc_time(duration);
-                    if let Err(err) = tx_t.send(Message::Time(TrackTime {
-                        position: position.seconds as f64 + position.frac,
-                        length: length.seconds as f64 + length.frac,
-                    })) {
-                        if let Ok(message) = rx.try_recv() {
-                            if let Message::Exit = message {
-                                exit = true;
-                                break;
-                            }
-                            if exit {
-                                break;
-                            } else {
-                                panic!("Can't send Time message: {}", err);
-                            }
-                        }
-                    }
+                if let Some(time) = p.source.send_time(packet.ts(), &p.tx_t) {
+                    ticker.tick(time, &events);
                 }
 
-                match decoder.decode(&packet) {
+                match p.source.decoder.decode(&packet) {
                     Ok(decoded) => {
-                        if audio_output.is_none() {
-                            let mut tmp_spec = *decoded.spec();
-                            tmp_spec.rate = (tmp_spec.rate as f32 * playback_speed).round() as u32;
-                            spec = Some(tmp_spec);
-                            dur = Some(decoded.capacity() as u64);
-                            audio_output =
-                                Some(output::try_open(spec.unwrap(), dur.unwrap()).unwrap());
-                        } else {
-                            let mut new_spec = *decoded.spec();
-                            new_spec.rate = (new_spec.rate as f32 * playback_speed).round() as u32;
-                            let new_dur = decoded.capacity() as u64;
-                            let mut changed = false;
-                            if new_spec != spec.unwrap() {
-                                spec = Some(new_spec);
-                                changed = true;
-                            }
-                            if new_dur != dur.unwrap() {
-                                dur = Some(new_dur);
-                                changed = true
-                            }
-                            if changed {
-                                audio_output =
-                                    Some(output::try_open(spec.unwrap(), dur.unwrap()).unwrap());
+                        p.source.spec = Some(*decoded.spec());
+                        let gain = p.source.replay_gain.factor(replay_gain_mode);
+                        if p.fade.is_some() {
+                            let mut buffer =
+                                AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                            decoded.convert(&mut buffer);
+                            Fade::apply(
+                                &mut p.fade,
+                                &mut buffer,
+                                crossfade.curve,
+                                gain,
+                                replay_gain_mode,
+                                &events,
+                            );
+                            if let Err(err) = engine.write(
+                                buffer.as_audio_buffer_ref(),
+                                volume * gain,
+                                playback_speed,
+                            ) {
+                                report(err);
                             }
-                        }
-
-                        if let Some(audio_output) = &mut audio_output {
-                            audio_output.write(decoded, volume).unwrap()
+                        } else if let Err(err) =
+                            engine.write(decoded, volume * gain, playback_speed)
+                        {
+                            report(err);
                         }
                     }
-                    Err(symphonia::core::errors::Error::DecodeError(err)) => {
+                    Err(Error::DecodeError(err)) => {
                         eprintln!("Decode error: {}", err);
                     }
                     Err(err) => {
-                        eprintln!("Error has occurred in decoding packet: {}", err);
-                        break;
+                        report(err.into());
+                        p.end(&events);
+                        break 'step false;
                     }
                 }
+                true
+            };
+
+            if !playing {
+                playback = None;
+            }
+        }
+    }
+}
+
+/// The track currently fed to the audio engine, along with the channels used to report about it
+struct Playback {
+    source: Source,
+    preloaded: Option<Source>,
+    fade: Option<Fade>,
+    tx_t: Sender<Message>,
+    tx_e: Sender<Message>,
+    tx_n: Sender<Message>,
+}
+
+impl Playback {
+    /// Notifies the owner that the track ended by itself
+    fn end(&self, events: &EventBus) {
+        // The owner doesn't care anymore about this track if it can't receive the message
+        let _ = self.tx_e.send(Message::End);
+        // Sent after the message, so that `Player::has_ended` is already `true` when the event is received
+        events.emit(PlayerEvent::Ended);
+    }
+}
+
+/// A track opened and ready to be decoded by the track thread
+pub(crate) struct Source {
+    /// See `Player::play_with_gain`
+    id: u64,
+    pub(crate) format: Box<dyn FormatReader>,
+    pub(crate) decoder: Box<dyn Decoder>,
+    pub(crate) track_id: u32,
+    time_base: Option<TimeBase>,
+    /// `None` for live streams
+    duration: Option<u64>,
+    pub(crate) last_ts: u64,
+    /// Timestamp right after the last packet read
+    pub(crate) end_ts: u64,
+    pub(crate) spec: Option<SignalSpec>,
+    pub(crate) primed: Option<(u64, AudioBuffer<f32>)>,
+    pub(crate) replay_gain: ReplayGain,
+    can_crossfade: bool,
+}
+
+impl Source {
+    pub(crate) fn new(
+        id: u64,
+        mut format: Box<dyn FormatReader>,
+        mut replay_gain: ReplayGain,
+        can_crossfade: bool,
+    ) -> Result<Self, NError> {
+        if let Some(rev) = format.metadata().current() {
+            replay_gain.merge(ReplayGain::from_tags(rev.tags()));
+        }
+
+        let track = format.default_track().ok_or(NError::NoTrack)?;
+        let replay_gain = replay_gain.with_codec_params(&track.codec_params);
+        let track_id = track.id;
+        let time_base = track.codec_params.time_base;
+        let duration = track
+            .codec_params
+            .n_frames
+            .map(|frames| track.codec_params.start_ts + frames);
+
+        let decoder = CODEC_REGISTRY
+            .make(&track.codec_params, &DecoderOptions::default())
+            .map_err(|_| NError::UnsupportedCodec)?;
+
+        let mut source = Self {
+            id,
+            format,
+            decoder,
+            track_id,
+            time_base,
+            duration,
+            last_ts: 0,
+            end_ts: 0,
+            spec: None,
+            primed: None,
+            replay_gain,
+            can_crossfade,
+        };
+        source.prime();
+        Ok(source)
+    }
+
+    /// Decodes the first buffer ahead of time, so that it's ready as soon as the track starts
+    fn prime(&mut self) {
+        while let Ok(packet) = self.format.next_packet() {
+            if packet.track_id() != self.track_id {
+                continue;
+            }
+
+            match self.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let mut buffer =
+                        AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                    decoded.convert(&mut buffer);
+                    self.spec = Some(*decoded.spec());
+                    self.end_ts = packet.ts() + packet.dur();
+                    self.primed = Some((packet.ts(), buffer));
+                    return;
+                }
+                Err(Error::DecodeError(err)) => {
+                    eprintln!("Decode error: {}", err);
+                }
+                Err(_) => return,
             }
         }
-        if !exit {
-            tx_e.send(Message::End).expect("Can't send End message");
+    }
+
+    /// Returns how many frames of this source should be mixed with `next`, if it's time to start crossfading
+    fn crossfade_length(&self, next: &Source, duration: f32) -> Option<u64> {
+        if !next.can_crossfade || self.spec.is_none() || self.spec != next.spec {
+            return None;
         }
+        let time_base = self.time_base?;
+
+        let remaining = time_base.calc_time(self.duration?.saturating_sub(self.last_ts));
+        let remaining = remaining.seconds as f64 + remaining.frac;
+        if remaining > duration as f64 {
+            return None;
+        }
+
+        Some((remaining * self.spec?.rate as f64) as u64)
+    }
+
+    /// Returns the timestamp `ts` along with the length of the track, if it's known
+    pub(crate) fn time(&self, ts: u64) -> Option<TrackTime> {
+        let time_base = self.time_base?;
+        let position = time_base.calc_time(ts);
+        let length = self
+            .duration
+            .map(|duration| time_base.calc_time(duration))
+            .map(|length| length.seconds as f64 + length.frac);
+        Some(TrackTime {
+            position: position.seconds as f64 + position.frac,
+            length,
+        })
+    }
+
+    /// Returns the length in seconds of the packets read so far, which is exact once the end of the track is reached
+    fn measured_length(&self) -> Option<f64> {
+        let length = self.time_base?.calc_time(self.end_ts);
+        Some(length.seconds as f64 + length.frac)
+    }
+
+    /// Sends the current timestamp to the owner of the track thread, returning it
+    fn send_time(&self, ts: u64, tx_t: &Sender<Message>) -> Option<TrackTime> {
+        let time = self.time(ts)?;
+        // The owner may have stopped listening, it'll be noticed when it disconnects from the thread
+        let _ = tx_t.send(Message::Time(time));
+        Some(time)
+    }
+}
+
+/// Sends `PlayerEvent::Position` no more often than `interval`
+struct PositionTicker {
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl PositionTicker {
+    fn new(interval: Duration) -> Self {
+        Self {
+            interval,
+            last: None,
+        }
+    }
+
+    /// Makes the next position be sent right away, used when the position jumps
+    fn reset(&mut self) {
+        self.last = None;
+    }
+
+    fn tick(&mut self, time: TrackTime, events: &EventBus) {
+        if self.last.is_none_or(|last| last.elapsed() >= self.interval) {
+            self.last = Some(Instant::now());
+            events.emit(PlayerEvent::Position(time));
+        }
+    }
+}
+
+/// Maps the volume set by the user to the gain applied to the samples, so that it sounds linear
+pub(crate) fn perceived_volume(volume: f32) -> f32 {
+    // from: https://stackoverflow.com/a/1165198
+    1.0 - (1.0 - (volume * volume)).sqrt()
+}
+
+/// Returns whether `err` only means that there's nothing left to read
+pub(crate) fn is_end_of_stream(err: &Error) -> bool {
+    matches!(err, Error::IoError(err) if err.kind() == ErrorKind::UnexpectedEof)
+}
+
+/// Returns whether the seek failed because it went past the end of the track
+pub(crate) fn is_seek_past_end(err: &Error) -> bool {
+    matches!(err, Error::SeekError(SeekErrorKind::OutOfRange))
+        || err.to_string().contains("end of stream")
+}
+
+/// The outgoing track while it's being crossfaded with the current one
+struct Fade {
+    outgoing: Source,
+    pending: Vec<Vec<f32>>,
+    position: u64,
+    length: u64,
+    ended: bool,
+    /// Exact length of the outgoing track, once it's read to its end
+    measured: Option<f64>,
+}
+
+impl Fade {
+    fn new(outgoing: Source, length: u64) -> Self {
+        let channels = outgoing.spec.map(|spec| spec.channels.count()).unwrap_or(0);
+
+        Self {
+            outgoing,
+            pending: vec![vec![]; channels],
+            position: 0,
+            length: length.max(1),
+            ended: false,
+            measured: None,
+        }
+    }
+
+    /// Mixes the outgoing track into `buffer`, stopping the fade when it's done
+    ///
+    /// `gain` is the ReplayGain factor that will be applied to `buffer`, used to keep the outgoing track at its own level
+    fn apply(
+        fade: &mut Option<Fade>,
+        buffer: &mut AudioBuffer<f32>,
+        curve: CrossfadeCurve,
+        gain: f32,
+        replay_gain_mode: ReplayGainMode,
+        events: &EventBus,
+    ) {
+        if let Some(f) = fade {
+            let relative_gain = f.outgoing.replay_gain.factor(replay_gain_mode) / gain;
+            f.mix(buffer, curve, relative_gain);
+            if f.is_done() {
+                // What's left isn't heard, but it's read anyway to know how long the track is
+                while !f.ended && f.next_packet().is_some() {}
+                if let Some(length) = f.measured {
+                    events.emit(PlayerEvent::LengthMeasured(f.outgoing.id, length));
+                }
+                *fade = None;
+            }
+        }
+    }
+
+    fn mix(&mut self, buffer: &mut AudioBuffer<f32>, curve: CrossfadeCurve, relative_gain: f32) {
+        let frames = buffer.frames();
+        self.fill(frames);
+
+        let gains = (0..frames)
+            .map(|frame| curve.gains((self.position + frame as u64) as f32 / self.length as f32))
+            .collect::<Vec<(f32, f32)>>();
+
+        for (ch, pending) in self.pending.iter_mut().enumerate() {
+            if ch >= buffer.spec().channels.count() {
+                break;
+            }
+            for (frame, sample) in buffer.chan_mut(ch).iter_mut().enumerate() {
+                let (gain_out, gain_in) = gains[frame];
+                let outgoing = pending.get(frame).copied().unwrap_or(0.0);
+                *sample = *sample * gain_in + outgoing * gain_out * relative_gain;
+            }
+            pending.drain(..frames.min(pending.len()));
+        }
+
+        self.position += frames as u64;
+    }
+
+    /// Decodes the outgoing track until there are at least `frames` frames ready to be mixed
+    fn fill(&mut self, frames: usize) {
+        if let Some((_, buffer)) = self.outgoing.primed.take() {
+            self.push(&buffer);
+        }
+
+        while !self.ended && self.pending.first().is_some_and(|p| p.len() < frames) {
+            let Some(packet) = self.next_packet() else {
+                break;
+            };
+
+            match self.outgoing.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    let mut buffer =
+                        AudioBuffer::<f32>::new(decoded.capacity() as u64, *decoded.spec());
+                    decoded.convert(&mut buffer);
+                    self.push(&buffer);
+                }
+                Err(Error::DecodeError(err)) => {
+                    eprintln!("Decode error: {}", err);
+                }
+                Err(_) => self.ended = true,
+            }
+        }
+    }
+
+    /// Reads the next packet of the outgoing track, `None` once it's ended
+    fn next_packet(&mut self) -> Option<Packet> {
+        loop {
+            match self.outgoing.format.next_packet() {
+                Ok(packet) if packet.track_id() != self.outgoing.track_id => {}
+                Ok(packet) => {
+                    self.outgoing.end_ts = packet.ts() + packet.dur();
+                    return Some(packet);
+                }
+                Err(err) => {
+                    self.ended = true;
+                    if is_end_of_stream(&err) {
+                        self.measured = self.outgoing.measured_length();
+                    }
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn push(&mut self, buffer: &AudioBuffer<f32>) {
+        for (ch, pending) in self.pending.iter_mut().enumerate() {
+            if ch < buffer.spec().channels.count() {
+                pending.extend_from_slice(buffer.chan(ch));
+            }
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.position >= self.length
+            || (self.ended && self.pending.first().is_none_or(|p| p.is_empty()))
     }
 }
 
@@ -1,14 +1,22 @@
+use crate::dca::DcaIndexMode;
+use crate::http_source::is_url;
 use crate::music_track::MusicTrack;
+use crate::output::OutputBackend;
 use crate::player::Player;
-use crate::{remove_ext, strip_absolute_path};
+use crate::replay_gain::{ReplayGain, ReplayGainMode};
+use crate::{remove_ext, strip_absolute_path, NError};
 use rand::prelude::SliceRandom;
 use rand::rng;
 use std::cmp::PartialEq;
-use std::io;
-use std::io::ErrorKind;
 use std::ops::{Deref, DerefMut};
 use std::path::PathBuf;
 use std::sync::Arc;
+use symphonia::core::formats::FormatReader;
+
+/// How many seconds before the end of the current track the next one gets preloaded
+const PRELOAD_THRESHOLD: f64 = 5.0;
+/// How many of the last tracks given to the player are remembered by `QueuePlayer::track_by_id`
+const REMEMBERED_TRACKS: usize = 4;
 
 #[derive(Default, Eq, PartialEq, Debug, Clone)]
 pub enum LoopStatus {
@@ -23,6 +31,9 @@ pub struct QueuePlayer {
     player: Player,
     index: usize,
     loop_status: LoopStatus,
+    preloaded: Option<usize>,
+    /// Ids of the last tracks given to the player, along with their entry, oldest first
+    played: Vec<(u64, Arc<str>)>,
 }
 
 impl Default for QueuePlayer {
@@ -33,7 +44,12 @@ impl Default for QueuePlayer {
 
 impl QueuePlayer {
     pub fn new(path: String) -> Self {
-        let player = Player::new(1.0, 1.0);
+        Self::with_output(path, OutputBackend::Device)
+    }
+
+    /// Instance a new `QueuePlayer` playing on `output`, see `Player::with_output`
+    pub fn with_output(path: String, output: OutputBackend) -> Self {
+        let player = Player::with_output(1.0, 1.0, output);
 
         QueuePlayer {
             queue: vec![],
@@ -41,6 +57,8 @@ impl QueuePlayer {
             index: usize::MAX - 1,
             path,
             loop_status: LoopStatus::Playlist,
+            preloaded: None,
+            played: vec![],
         }
     }
 
@@ -64,7 +82,10 @@ impl QueuePlayer {
         self.path = path;
     }
 
-    pub fn set_loop_status(&mut self, loop_status: LoopStatus) {
+    pub async fn set_loop_status(&mut self, loop_status: LoopStatus) {
+        if self.loop_status != loop_status {
+            self.discard_preload().await;
+        }
         self.loop_status = loop_status;
     }
 
@@ -73,7 +94,12 @@ impl QueuePlayer {
     }
 
     pub async fn get_path_for_file(&self, i: usize) -> Option<PathBuf> {
-        Some(PathBuf::from(&self.path).join(self.queue.get(i)?.as_ref()))
+        let track = self.queue.get(i)?.as_ref();
+        // Streams are kept as their URL
+        if is_url(track) {
+            return Some(PathBuf::from(track));
+        }
+        Some(PathBuf::from(&self.path).join(track))
     }
 
     pub fn queue(&self) -> &[Arc<str>] {
@@ -88,10 +114,13 @@ impl QueuePlayer {
 
     #[inline]
     pub async fn add<P: Into<Arc<str>>>(&mut self, path: P) {
+        // The track after the last one changes
+        self.discard_preload().await;
         self.queue.push(path.into());
     }
 
     pub async fn add_all<P: Into<String>>(&mut self, paths: impl IntoIterator<Item = P>) {
+        self.discard_preload().await;
         self.queue.append(
             &mut paths
                 .into_iter()
@@ -100,19 +129,27 @@ impl QueuePlayer {
         );
     }
 
+    /// Removes the entry at `index`, keeping the current index on the same track if it comes after it
     #[inline]
-    pub fn remove(&mut self, index: usize) {
+    pub async fn remove(&mut self, index: usize) {
+        self.discard_preload().await;
+        // Nothing is playing if the index is past the end
+        if index < self.index && self.index < self.len() {
+            self.index -= 1;
+        }
         self.queue.remove(index);
     }
 
     #[inline]
     pub async fn clear(&mut self) {
+        self.discard_preload().await;
         self.queue.clear();
         self.index = usize::MAX - 1;
     }
 
     #[inline]
-    pub fn shuffle(&mut self) {
+    pub async fn shuffle(&mut self) {
+        self.discard_preload().await;
         self.queue.shuffle(&mut rng());
     }
 
@@ -120,38 +157,71 @@ impl QueuePlayer {
         self.queue.get(self.index).map(|t| t.clone())
     }
 
-    pub async fn play(&mut self) -> io::Result<()> {
+    async fn open_format(
+        &self,
+        index: usize,
+    ) -> Result<(Box<dyn FormatReader>, ReplayGain), NError> {
         let track = MusicTrack::new(
-            self.get_path_for_file(self.index)
+            self.get_path_for_file(index)
                 .await
-                .ok_or(io::Error::from(ErrorKind::NotFound))?
-                .to_str()
-                .unwrap(),
-        )?;
-        let format = tokio::task::spawn_blocking(move || track.get_format()).await??;
+                .ok_or(NError::NoTrack)?
+                .to_string_lossy()
+                .to_string(),
+        )?
+        .with_dca_index(DcaIndexMode::Cached)
+        .with_events(self.player.event_bus().clone());
+        let (format, mut replay_gain) =
+            tokio::task::spawn_blocking(move || track.get_format_with_gain())
+                .await
+                .map_err(|err| NError::Io(err.into()))??;
+
+        // The track is considered part of an album playback if it's next to another track of the same album
+        if self.player.get_replay_gain_mode() == ReplayGainMode::Auto {
+            for neighbour in [index.wrapping_sub(1), index + 1] {
+                if neighbour < self.len() && self.is_same_album(index, neighbour).await {
+                    replay_gain.album_context = true;
+                    break;
+                }
+            }
+        }
+
+        Ok((format, replay_gain))
+    }
 
-        self.player.play(format);
+    pub async fn play(&mut self) -> Result<(), NError> {
+        let (format, replay_gain) = self.open_format(self.index).await?;
+
+        self.preloaded = None;
+        let id = self.player.play_with_gain(format, replay_gain)?;
+        self.remember(id, self.index);
         Ok(())
     }
 
-    pub async fn play_index(&mut self, index: usize) -> io::Result<()> {
+    pub async fn play_index(&mut self, index: usize) -> Result<(), NError> {
         self.index = index;
 
         self.play().await
     }
 
-    pub async fn play_next(&mut self, ignore_loop: bool) -> io::Result<()> {
+    /// Returns the index that `QueuePlayer::play_next` would play
+    fn next_index(&self, ignore_loop: bool) -> usize {
+        let mut index = self.index;
         if ignore_loop || self.loop_status == LoopStatus::Playlist {
-            self.index += 1;
+            index += 1;
 
-            if self.index >= self.len() {
-                self.index = 0;
+            if index >= self.len() {
+                index = 0;
             }
         }
+        index
+    }
+
+    pub async fn play_next(&mut self, ignore_loop: bool) -> Result<(), NError> {
+        self.index = self.next_index(ignore_loop);
         self.play().await
     }
 
-    pub async fn play_previous(&mut self) -> io::Result<()> {
+    pub async fn play_previous(&mut self) -> Result<(), NError> {
         if self.index == 0 {
             self.index = self.len();
         }
@@ -161,6 +231,114 @@ impl QueuePlayer {
         self.play().await
     }
 
+    /// Opens the track that follows the current one (respecting the loop status) and hands it to the player
+    /// when the current one is about to end, so that the switch between them happens without any gap
+    pub async fn preload_next(&mut self) -> Result<(), NError> {
+        if self.preloaded.is_some() || self.is_empty() || !self.player.is_playing() {
+            return Ok(());
+        }
+
+        let time = match self.player.get_time() {
+            Some(time) => time,
+            None => return Ok(()),
+        };
+        // Live streams don't have an end to get close to
+        let Some(length) = time.length else {
+            return Ok(());
+        };
+        let crossfade = self.player.get_crossfade();
+        let threshold = PRELOAD_THRESHOLD
+            + if crossfade.is_enabled() {
+                crossfade.duration as f64
+            } else {
+                0.0
+            };
+        if length - time.position > threshold {
+            return Ok(());
+        }
+
+        let index = self.next_index(false);
+        let can_crossfade = crossfade.is_enabled() && !self.is_same_album(self.index, index).await;
+        let (format, replay_gain) = self.open_format(index).await?;
+        let id = self
+            .player
+            .preload(format, replay_gain, can_crossfade)
+            .await?;
+        self.preloaded = Some(index);
+        self.remember(id, index);
+
+        Ok(())
+    }
+
+    /// Returns whether the two entries are the same track or consecutive tracks of the same album,
+    /// which must not be crossfaded to keep gapless albums intact
+    async fn is_same_album(&self, first: usize, second: usize) -> bool {
+        if first == second {
+            return true;
+        }
+
+        let mut albums = vec![];
+        for i in [first, second] {
+            let track = match self.get_path_for_file(i).await {
+                Some(path) => MusicTrack::new(path.to_string_lossy().to_string()),
+                None => return false,
+            };
+            let album = match track {
+                Ok(track) => tokio::task::spawn_blocking(move || track.get_meta())
+                    .await
+                    .ok()
+                    .and_then(|meta| meta.ok())
+                    .map(|meta| meta.album),
+                Err(_) => None,
+            };
+            match album {
+                Some(album) if !album.is_empty() => albums.push(album),
+                _ => return false,
+            }
+        }
+
+        albums[0] == albums[1]
+    }
+
+    /// Returns whether the player has moved on to the preloaded track, updating the current index accordingly
+    pub fn check_advanced(&mut self) -> bool {
+        if self.player.has_advanced() {
+            if let Some(index) = self.preloaded.take() {
+                self.index = index;
+            }
+            return true;
+        }
+        false
+    }
+
+    /// Returns the entry of the queue that was given to the player with `id`, if it's one of the last ones
+    ///
+    /// It's meant for the events about a track (e.g. `PlayerEvent::LengthMeasured`), which may arrive after the
+    /// queue moved on
+    pub fn track_by_id(&self, id: u64) -> Option<Arc<str>> {
+        self.played
+            .iter()
+            .find(|(played, _)| *played == id)
+            .map(|(_, track)| track.clone())
+    }
+
+    fn remember(&mut self, id: u64, index: usize) {
+        if let Some(track) = self.queue.get(index) {
+            if self.played.len() >= REMEMBERED_TRACKS {
+                self.played.remove(0);
+            }
+            self.played.push((id, track.clone()));
+        }
+    }
+
+    async fn discard_preload(&mut self) {
+        if self.preloaded.take().is_some() {
+            if let Err(err) = self.player.clear_preload().await {
+                eprintln!("can't discard preloaded track: {err}");
+            }
+        }
+    }
+
     pub fn get_index_from_track_name(&self, name: &str) -> Option<usize> {
         self.queue
             .iter()
@@ -1,22 +1,107 @@
+use crate::opus::discrete_channels;
+use serde::{Deserialize, Serialize};
 use std::io::{Seek, SeekFrom};
 use symphonia::core::{
     audio::Channels,
-    codecs::{CodecParameters, CODEC_TYPE_PCM_F32LE},
-    errors::{self as symph_err, Result as SymphResult, SeekErrorKind},
+    codecs::{
+        CodecParameters, CodecType, CODEC_TYPE_PCM_F32LE, CODEC_TYPE_PCM_F64LE,
+        CODEC_TYPE_PCM_S16LE, CODEC_TYPE_PCM_S24LE, CODEC_TYPE_PCM_S32LE,
+    },
+    errors::{self as symph_err, Error as SymphError, Result as SymphResult, SeekErrorKind},
     formats::prelude::*,
     io::{MediaSource, MediaSourceStream, ReadBytes, SeekBuffered},
-    meta::{Metadata as SymphMetadata, MetadataLog},
+    meta::{Metadata as SymphMetadata, MetadataBuilder, MetadataLog, StandardTagKey, Tag, Value},
     probe::{Descriptor, Instantiate, QueryDescriptor},
+    sample::SampleFormat,
     units::TimeStamp,
 };
 
 // Original code from the Songbird project
 
+/// Signature shared by both versions of the header
+pub(crate) const MAGIC: &[u8; 8] = b"SbirdRaw";
+/// Version of the extended header written by `RawWriter`
+pub(crate) const VERSION: u16 = 1;
+
+/// How the samples of a raw file are stored, always little-endian
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum RawSampleFormat {
+    I16,
+    I24,
+    I32,
+    #[default]
+    F32,
+    F64,
+}
+
+impl RawSampleFormat {
+    /// Bytes taken by a single sample
+    pub fn size(&self) -> usize {
+        match self {
+            RawSampleFormat::I16 => 2,
+            RawSampleFormat::I24 => 3,
+            RawSampleFormat::I32 => 4,
+            RawSampleFormat::F32 => 4,
+            RawSampleFormat::F64 => 8,
+        }
+    }
+
+    pub(crate) fn id(&self) -> u16 {
+        match self {
+            RawSampleFormat::I16 => 0,
+            RawSampleFormat::I24 => 1,
+            RawSampleFormat::I32 => 2,
+            RawSampleFormat::F32 => 3,
+            RawSampleFormat::F64 => 4,
+        }
+    }
+
+    fn from_id(id: u16) -> Option<Self> {
+        match id {
+            0 => Some(RawSampleFormat::I16),
+            1 => Some(RawSampleFormat::I24),
+            2 => Some(RawSampleFormat::I32),
+            3 => Some(RawSampleFormat::F32),
+            4 => Some(RawSampleFormat::F64),
+            _ => None,
+        }
+    }
+
+    fn codec(&self) -> CodecType {
+        match self {
+            RawSampleFormat::I16 => CODEC_TYPE_PCM_S16LE,
+            RawSampleFormat::I24 => CODEC_TYPE_PCM_S24LE,
+            RawSampleFormat::I32 => CODEC_TYPE_PCM_S32LE,
+            RawSampleFormat::F32 => CODEC_TYPE_PCM_F32LE,
+            RawSampleFormat::F64 => CODEC_TYPE_PCM_F64LE,
+        }
+    }
+
+    fn sample_format(&self) -> SampleFormat {
+        match self {
+            RawSampleFormat::I16 => SampleFormat::S16,
+            RawSampleFormat::I24 => SampleFormat::S24,
+            RawSampleFormat::I32 => SampleFormat::S32,
+            RawSampleFormat::F32 => SampleFormat::F32,
+            RawSampleFormat::F64 => SampleFormat::F64,
+        }
+    }
+}
+
+/// Optional metadata block of the extended header, stored as JSON
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub struct RawMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    /// Samples per channel of the whole file
+    pub duration: Option<u64>,
+}
+
 impl QueryDescriptor for RawReader {
     fn query() -> &'static [Descriptor] {
         &[symphonia_core::support_format!(
             "raw",
-            "Raw arbitrary-length f32 audio container.",
+            "Raw arbitrary-length PCM audio container.",
             &["rawf32"],
             &[],
             &[b"SbirdRaw"]
@@ -28,18 +113,33 @@ impl QueryDescriptor for RawReader {
     }
 }
 
-/// Symphonia support for a simple container for raw f32-PCM data of unknown duration.
+/// Symphonia support for a simple container for raw PCM data of unknown duration.
 ///
 /// Contained files have a simple header:
 /// * the 8-byte signature `b"SbirdRaw"`,
 /// * the sample rate, as a little-endian `u32`,
 /// * the channel count, as a little-endian `u32`.
 ///
-/// The remainder of the file is interleaved little-endian `f32` samples.
+/// The remainder of the file is interleaved little-endian `f32` samples, mono or stereo.
+///
+/// The extended header has, after the signature (all little-endian):
+/// * a `u32` zero, where the legacy header has the sample rate,
+/// * the version of the header, as a `u16` (currently 1),
+/// * the sample format, as a `u16` (0: `i16`, 1: `i24`, 2: `i32`, 3: `f32`, 4: `f64`),
+/// * the sample rate, as a `u32`,
+/// * the channel count, as a `u32`,
+/// * the channel layout, as a `u32` Symphonia `Channels` mask (0 for discrete channels),
+/// * the length of the metadata block, as a `u32` (0 if there's none),
+/// * the metadata block, a JSON `RawMetadata`.
+///
+/// The remainder of the file is interleaved samples in the given format.
 pub struct RawReader {
     source: MediaSourceStream,
     track: Track,
     meta: MetadataLog,
+    sample_format: RawSampleFormat,
+    /// Byte position of the first sample
+    data_start: u64,
     curr_ts: TimeStamp,
     max_ts: Option<TimeStamp>,
 }
@@ -49,35 +149,105 @@ impl FormatReader for RawReader {
         let mut magic = [0u8; 8];
         ReadBytes::read_buf_exact(&mut source, &mut magic[..])?;
 
-        if &magic != b"SbirdRaw" {
+        if &magic != MAGIC {
             source.seek_buffered_rel(-(magic.len() as isize));
             return symph_err::decode_error("rawf32: illegal magic byte sequence.");
         }
 
-        let sample_rate = source.read_u32()?;
-        let n_chans = source.read_u32()?;
+        let mut meta = MetadataLog::default();
+        let mut n_frames = None;
+
+        // A legacy header has the sample rate here, which can't be zero
+        let (sample_format, sample_rate, chans) = match source.read_u32()? {
+            0 => {
+                let version = source.read_u16()?;
+                if version > VERSION {
+                    return symph_err::unsupported_error("raw: unsupported header version");
+                }
+
+                let sample_format = RawSampleFormat::from_id(source.read_u16()?)
+                    .ok_or(SymphError::Unsupported("raw: unknown sample format"))?;
+                let sample_rate = source.read_u32()?;
+                let n_chans = source.read_u32()?;
+                let mask = source.read_u32()?;
 
-        let chans = match n_chans {
-            1 => Channels::FRONT_LEFT,
-            2 => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
-            _ => {
-                return symph_err::decode_error(
-                    "rawf32: channel layout is not stereo or mono for fmt_pcm",
-                )
+                let chans = if mask == 0 {
+                    discrete_channels(n_chans as usize)
+                } else {
+                    Channels::from_bits(mask)
+                        .ok_or(SymphError::Unsupported("raw: unknown channel layout"))?
+                };
+                if n_chans == 0 || chans.count() != n_chans as usize {
+                    return symph_err::unsupported_error(
+                        "raw: channel layout doesn't match the channel count",
+                    );
+                }
+
+                let size = source.read_u32()?;
+                if size > 0 {
+                    let raw_json = source.read_boxed_slice_exact(size as usize)?;
+                    let metadata: RawMetadata = serde_json::from_slice(&raw_json)
+                        .map_err(|_| SymphError::DecodeError("raw: malformed metadata block"))?;
+
+                    let mut revision = MetadataBuilder::new();
+                    if let Some(t) = metadata.title {
+                        revision.add_tag(Tag::new(
+                            Some(StandardTagKey::TrackTitle),
+                            "title",
+                            Value::String(t),
+                        ));
+                    }
+                    if let Some(t) = metadata.artist {
+                        revision.add_tag(Tag::new(
+                            Some(StandardTagKey::Artist),
+                            "artist",
+                            Value::String(t),
+                        ));
+                    }
+                    meta.push(revision.metadata());
+                    n_frames = metadata.duration;
+                }
+
+                (sample_format, sample_rate, chans)
+            }
+            sample_rate => {
+                let n_chans = source.read_u32()?;
+
+                let chans = match n_chans {
+                    1 => Channels::FRONT_LEFT,
+                    2 => Channels::FRONT_LEFT | Channels::FRONT_RIGHT,
+                    _ => {
+                        return symph_err::decode_error(
+                            "rawf32: channel layout is not stereo or mono for fmt_pcm",
+                        )
+                    }
+                };
+
+                (RawSampleFormat::F32, sample_rate, chans)
             }
         };
 
+        if sample_rate == 0 {
+            return symph_err::decode_error("raw: sample rate can't be zero");
+        }
+
+        let bits = (sample_format.size() as u32) * 8;
         let mut codec_params = CodecParameters::new();
 
         codec_params
-            .for_codec(CODEC_TYPE_PCM_F32LE)
-            .with_bits_per_coded_sample((std::mem::size_of::<f32>() as u32) * 8)
-            .with_bits_per_sample((std::mem::size_of::<f32>() as u32) * 8)
+            .for_codec(sample_format.codec())
+            .with_bits_per_coded_sample(bits)
+            .with_bits_per_sample(bits)
             .with_sample_rate(sample_rate)
             .with_time_base(TimeBase::new(1, sample_rate))
-            .with_sample_format(symphonia_core::sample::SampleFormat::F32)
+            .with_sample_format(sample_format.sample_format())
             .with_max_frames_per_packet(sample_rate as u64 / 50)
             .with_channels(chans);
+        if let Some(n_frames) = n_frames {
+            codec_params.with_n_frames(n_frames);
+        }
+
+        let data_start = source.pos();
 
         Ok(Self {
             source,
@@ -86,9 +256,11 @@ impl FormatReader for RawReader {
                 language: None,
                 codec_params,
             },
-            meta: MetadataLog::default(),
+            meta,
+            sample_format,
+            data_start,
             curr_ts: 0,
-            max_ts: None,
+            max_ts: n_frames,
         })
     }
 
@@ -134,7 +306,7 @@ impl FormatReader for RawReader {
             .expect("Channel count is built into format.")
             .count() as u64;
 
-        let seek_pos = 16 + (std::mem::size_of::<f32>() as u64) * (ts * chan_count);
+        let seek_pos = self.data_start + (self.sample_format.size() as u64) * (ts * chan_count);
 
         self.source.seek(SeekFrom::Start(seek_pos))?;
         self.curr_ts = ts;
@@ -167,7 +339,7 @@ impl FormatReader for RawReader {
             .expect("Channel count is built into format.")
             .count();
 
-        let sample_unit = std::mem::size_of::<f32>() * chan_count;
+        let sample_unit = self.sample_format.size() * chan_count;
 
         // Aim for 20ms (50Hz).
         let buf = self.source.read_boxed_slice((rate / 50) * sample_unit)?;
@@ -184,3 +356,138 @@ impl FormatReader for RawReader {
         self.source
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_writer::{RawHeader, RawWriter};
+    use crate::CODEC_REGISTRY;
+    use std::io::{Cursor, ErrorKind};
+    use symphonia::core::audio::{SampleBuffer, SignalSpec};
+    use symphonia::core::codecs::DecoderOptions;
+
+    /// Samples that every format stores exactly
+    const SAMPLES: [f64; 6] = [0.5, -0.25, 0.125, -1.0, 0.75, 0.0];
+
+    fn open(bytes: Vec<u8>) -> SymphResult<RawReader> {
+        let source = MediaSourceStream::new(Box::new(Cursor::new(bytes)), Default::default());
+        RawReader::try_new(source, &FormatOptions::default())
+    }
+
+    /// Decodes the whole file, returning its interleaved samples
+    fn decode(reader: &mut RawReader) -> Vec<f64> {
+        let mut decoder = CODEC_REGISTRY
+            .make(&reader.track.codec_params, &DecoderOptions::default())
+            .unwrap();
+        let mut samples = vec![];
+        loop {
+            let packet = match reader.next_packet() {
+                Ok(packet) if packet.dur() > 0 => packet,
+                Ok(_) => break,
+                Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+                Err(err) => panic!("{err}"),
+            };
+            let decoded = decoder.decode(&packet).unwrap();
+            let mut buffer = SampleBuffer::<f64>::new(decoded.capacity() as u64, *decoded.spec());
+            buffer.copy_interleaved_ref(decoded);
+            samples.extend_from_slice(buffer.samples());
+        }
+        samples
+    }
+
+    fn write(header: &RawHeader) -> Vec<u8> {
+        let mut writer = RawWriter::new(vec![], header).unwrap();
+        writer.write_samples(&SAMPLES).unwrap();
+        writer.finish().unwrap()
+    }
+
+    #[test]
+    fn round_trip() {
+        let surround = Channels::FRONT_LEFT | Channels::FRONT_RIGHT | Channels::FRONT_CENTRE;
+        let formats = [
+            RawSampleFormat::I16,
+            RawSampleFormat::I24,
+            RawSampleFormat::I32,
+            RawSampleFormat::F32,
+            RawSampleFormat::F64,
+        ];
+        for sample_format in formats {
+            for layout in [None, Some(surround)] {
+                let header = RawHeader {
+                    sample_rate: 8000,
+                    channels: 3,
+                    layout,
+                    sample_format,
+                    metadata: Some(RawMetadata {
+                        title: Some(String::from("Title")),
+                        artist: None,
+                        duration: Some(2),
+                    }),
+                };
+                let mut reader = open(write(&header)).unwrap();
+
+                let params = &reader.track.codec_params;
+                assert_eq!(params.sample_rate, Some(8000));
+                assert_eq!(params.n_frames, Some(2));
+                assert_eq!(
+                    params.channels,
+                    Some(layout.unwrap_or(discrete_channels(3)))
+                );
+                let title = reader
+                    .metadata()
+                    .current()
+                    .map(|revision| revision.tags()[0].value.to_string());
+                assert_eq!(title.as_deref(), Some("Title"));
+                assert_eq!(decode(&mut reader), SAMPLES, "{sample_format:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn reads_the_legacy_header() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&44100u32.to_le_bytes());
+        bytes.extend_from_slice(&2u32.to_le_bytes());
+        for sample in SAMPLES {
+            bytes.extend_from_slice(&(sample as f32).to_le_bytes());
+        }
+
+        let mut reader = open(bytes).unwrap();
+        let params = &reader.track.codec_params;
+        assert_eq!(reader.data_start, 16);
+        assert_eq!(params.sample_rate, Some(44100));
+        assert_eq!(
+            params.channels,
+            Some(Channels::FRONT_LEFT | Channels::FRONT_RIGHT)
+        );
+        assert_eq!(params.n_frames, None);
+        assert_eq!(decode(&mut reader), SAMPLES);
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        let header = RawHeader::from_spec(
+            SignalSpec::new(8000, Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+            RawSampleFormat::F32,
+        );
+        let bytes = write(&header);
+        assert!(open(bytes.clone()).is_ok());
+
+        let mut newer = bytes.clone();
+        newer[12..14].copy_from_slice(&(VERSION + 1).to_le_bytes());
+        assert!(matches!(open(newer), Err(SymphError::Unsupported(_))));
+
+        let mut format = bytes.clone();
+        format[14..16].copy_from_slice(&5u16.to_le_bytes());
+        assert!(matches!(open(format), Err(SymphError::Unsupported(_))));
+
+        // A stereo mask with three channels
+        let mut channels = bytes.clone();
+        channels[20..24].copy_from_slice(&3u32.to_le_bytes());
+        assert!(matches!(open(channels), Err(SymphError::Unsupported(_))));
+
+        let mut magic = bytes;
+        magic[0] = b's';
+        assert!(open(magic).is_err());
+    }
+}
@@ -0,0 +1,250 @@
+//! Dumping of decoded audio to the raw PCM container read by n_audio
+
+use crate::music_track::MusicTrack;
+pub use crate::raw::{RawMetadata, RawSampleFormat};
+use crate::raw::{MAGIC, VERSION};
+use crate::{NError, CODEC_REGISTRY};
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io;
+use std::io::{BufWriter, ErrorKind, Write};
+use std::path::Path;
+use symphonia::core::audio::{AudioBufferRef, Channels, SignalSpec};
+use symphonia::core::codecs::DecoderOptions;
+use symphonia::core::errors::Error as SymphError;
+use symphonia_core::audio::SampleBuffer;
+
+/// What goes in the header of a raw file
+#[derive(Clone, Debug, Default)]
+pub struct RawHeader {
+    pub sample_rate: u32,
+    pub channels: u32,
+    /// Layout of the channels, `None` if they are discrete
+    pub layout: Option<Channels>,
+    pub sample_format: RawSampleFormat,
+    pub metadata: Option<RawMetadata>,
+}
+
+impl RawHeader {
+    /// Header for audio of the given spec, keeping its layout
+    pub fn from_spec(spec: SignalSpec, sample_format: RawSampleFormat) -> Self {
+        Self {
+            sample_rate: spec.rate,
+            channels: spec.channels.count() as u32,
+            layout: Some(spec.channels),
+            sample_format,
+            metadata: None,
+        }
+    }
+}
+
+/// Writes a raw file with the extended header
+pub struct RawWriter<W: Write> {
+    writer: W,
+    channels: usize,
+    sample_format: RawSampleFormat,
+    samples: Option<SampleBuffer<f64>>,
+    bytes: Vec<u8>,
+}
+
+impl<W: Write> RawWriter<W> {
+    /// Writes the header and the metadata block, the samples must follow
+    pub fn new(mut writer: W, header: &RawHeader) -> io::Result<Self> {
+        if header.sample_rate == 0 || header.channels == 0 {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "raw files need a sample rate and at least a channel",
+            ));
+        }
+        if let Some(layout) = header.layout {
+            if layout.count() != header.channels as usize {
+                return Err(io::Error::new(
+                    ErrorKind::InvalidInput,
+                    "channel layout doesn't match the channel count",
+                ));
+            }
+        }
+        let metadata = match &header.metadata {
+            Some(metadata) => serde_json::to_vec(metadata)?,
+            None => vec![],
+        };
+        let len = u32::try_from(metadata.len())
+            .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "raw metadata too large"))?;
+
+        writer.write_all(MAGIC)?;
+        writer.write_all(&0u32.to_le_bytes())?;
+        writer.write_all(&VERSION.to_le_bytes())?;
+        writer.write_all(&header.sample_format.id().to_le_bytes())?;
+        writer.write_all(&header.sample_rate.to_le_bytes())?;
+        writer.write_all(&header.channels.to_le_bytes())?;
+        writer.write_all(
+            &header
+                .layout
+                .map_or(0, |layout| layout.bits())
+                .to_le_bytes(),
+        )?;
+        writer.write_all(&len.to_le_bytes())?;
+        writer.write_all(&metadata)?;
+
+        Ok(Self {
+            writer,
+            channels: header.channels as usize,
+            sample_format: header.sample_format,
+            samples: None,
+            bytes: vec![],
+        })
+    }
+
+    /// Writes interleaved samples, they are clipped to [-1.0, 1.0] when stored as integers
+    pub fn write_samples(&mut self, samples: &[f64]) -> io::Result<()> {
+        if !samples.len().is_multiple_of(self.channels) {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "samples don't fill the last frame",
+            ));
+        }
+
+        self.bytes.clear();
+        for &sample in samples {
+            match self.sample_format {
+                RawSampleFormat::I16 => self
+                    .bytes
+                    .extend_from_slice(&(quantize(sample, 16) as i16).to_le_bytes()),
+                RawSampleFormat::I24 => self
+                    .bytes
+                    .extend_from_slice(&quantize(sample, 24).to_le_bytes()[..3]),
+                RawSampleFormat::I32 => self
+                    .bytes
+                    .extend_from_slice(&quantize(sample, 32).to_le_bytes()),
+                RawSampleFormat::F32 => {
+                    self.bytes.extend_from_slice(&(sample as f32).to_le_bytes())
+                }
+                RawSampleFormat::F64 => self.bytes.extend_from_slice(&sample.to_le_bytes()),
+            }
+        }
+
+        self.writer.write_all(&self.bytes)
+    }
+
+    /// Writes a decoded buffer, it must have the channels given in the header
+    pub fn write(&mut self, buffer: AudioBufferRef) -> io::Result<()> {
+        let spec = *buffer.spec();
+        if spec.channels.count() != self.channels {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "buffer has a different channel count",
+            ));
+        }
+
+        let mut samples = match self.samples.take() {
+            Some(samples) if samples.capacity() >= buffer.capacity() * self.channels => samples,
+            _ => SampleBuffer::new(buffer.capacity() as u64, spec),
+        };
+        samples.copy_interleaved_ref(buffer);
+        let result = self.write_samples(samples.samples());
+        self.samples = Some(samples);
+
+        result
+    }
+
+    /// Flushes what's left and returns the inner writer
+    pub fn finish(mut self) -> io::Result<W> {
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Converts `sample` to a signed integer of `bits` bits, stored in an `i32`
+fn quantize(sample: f64, bits: u32) -> i32 {
+    let scale = (1u64 << (bits - 1)) as f64;
+    (sample * scale).round().clamp(-scale, scale - 1.0) as i32
+}
+
+/// Decodes the track at `input` to a raw file at `output`
+pub fn dump_file<P: AsRef<Path> + AsRef<OsStr> + Clone + Into<String>, Q: AsRef<Path>>(
+    input: P,
+    output: Q,
+    sample_format: RawSampleFormat,
+) -> Result<(), NError> {
+    let track = MusicTrack::new(input)?;
+    let file = BufWriter::new(File::create(output)?);
+    dump(&track, file, sample_format)?;
+    Ok(())
+}
+
+/// Decodes `track`, writing it to `output` at its own sample rate and channels
+///
+/// The metadata block gets the title, the artist and the duration of the track
+pub fn dump<W: Write>(
+    track: &MusicTrack,
+    output: W,
+    sample_format: RawSampleFormat,
+) -> Result<W, NError> {
+    let mut format = track.get_format()?;
+    let source = format.default_track().ok_or(NError::NoTrack)?;
+    let track_id = source.id;
+    let params = source.codec_params.clone();
+
+    let mut decoder = CODEC_REGISTRY
+        .make(&params, &DecoderOptions::default())
+        .map_err(|_| NError::UnsupportedCodec)?;
+
+    let metadata = track.get_meta().ok();
+    let metadata = RawMetadata {
+        title: metadata.as_ref().map(|meta| meta.title.clone()),
+        artist: metadata
+            .map(|meta| meta.artist)
+            .filter(|artist| !artist.is_empty()),
+        duration: params.n_frames,
+    };
+    let header = |spec: SignalSpec| RawHeader {
+        metadata: Some(metadata.clone()),
+        ..RawHeader::from_spec(spec, sample_format)
+    };
+
+    // The header is written once the first packet is decoded, since not every codec tells its channels up front
+    let mut output = Some(output);
+    let mut writer = None;
+
+    loop {
+        let packet = match format.next_packet() {
+            Ok(packet) => packet,
+            Err(SymphError::IoError(err)) if err.kind() == ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err.into()),
+        };
+        if packet.track_id() != track_id {
+            continue;
+        }
+
+        let decoded = match decoder.decode(&packet) {
+            Ok(decoded) => decoded,
+            Err(SymphError::DecodeError(err)) => {
+                eprintln!("Decode error: {}", err);
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+
+        let writer = match (&mut writer, output.take()) {
+            (Some(writer), _) => writer,
+            (writer, Some(output)) => {
+                writer.insert(RawWriter::new(output, &header(*decoded.spec()))?)
+            }
+            (None, None) => unreachable!("the output is taken only by the writer"),
+        };
+        writer.write(decoded)?;
+    }
+
+    let writer = match (writer, output) {
+        (Some(writer), _) => writer,
+        (None, Some(output)) => {
+            let (Some(rate), Some(channels)) = (params.sample_rate, params.channels) else {
+                return Err(NError::NoTrack);
+            };
+            RawWriter::new(output, &header(SignalSpec::new(rate, channels)))?
+        }
+        (None, None) => unreachable!("the output is taken only by the writer"),
+    };
+
+    Ok(writer.finish()?)
+}
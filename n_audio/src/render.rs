@@ -0,0 +1,315 @@
+//! Rendering of tracks without an output and without a clock, e.g. to export them or to test the decoders
+//!
+//! `Renderer` runs the same steps as the thread of a `Player` (decoding, seeking, playback speed, DSP chain and
+//! volume) synchronously and as fast as it can, so the same track rendered the same way always gives the same samples,
+//! bit for bit
+
+use crate::dsp::DspChain;
+use crate::engine::AudioEngine;
+use crate::music_track::MusicTrack;
+use crate::player::{is_end_of_stream, is_seek_past_end, perceived_volume, Source};
+use crate::replay_gain::{ReplayGain, ReplayGainMode};
+use crate::resampler::ResamplerQuality;
+use crate::time_stretch::{TimeStretchMode, MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
+use crate::{NError, TrackTime};
+use symphonia::core::audio::{AsAudioBufferRef, SignalSpec};
+use symphonia::core::errors::Error;
+use symphonia::core::formats::{FormatReader, SeekMode, SeekTo};
+use symphonia::core::units::Time;
+
+/// How a `Renderer` processes the track, the default leaves it as it is
+#[derive(Copy, Clone, Debug)]
+pub struct RenderOptions {
+    /// Spec of the rendered samples, `None` to keep the one of the track
+    pub spec: Option<SignalSpec>,
+    /// On the same scale of `Player::set_volume`
+    pub volume: f32,
+    pub playback_speed: f32,
+    pub time_stretch_mode: TimeStretchMode,
+    pub resampler_quality: ResamplerQuality,
+    pub replay_gain_mode: ReplayGainMode,
+}
+
+impl Default for RenderOptions {
+    fn default() -> Self {
+        Self {
+            spec: None,
+            volume: 1.0,
+            playback_speed: 1.0,
+            time_stretch_mode: TimeStretchMode::default(),
+            resampler_quality: ResamplerQuality::default(),
+            replay_gain_mode: ReplayGainMode::default(),
+        }
+    }
+}
+
+/// Renders a track to interleaved samples, one packet at a time (see the module docs)
+pub struct Renderer {
+    source: Source,
+    engine: AudioEngine,
+    spec: SignalSpec,
+    volume: f32,
+    playback_speed: f32,
+    replay_gain_mode: ReplayGainMode,
+    samples: Vec<f32>,
+    ended: bool,
+}
+
+impl Renderer {
+    /// Instance a new `Renderer` of `format`, `replay_gain` is the info that couldn't be read from the format itself
+    pub fn new(
+        format: Box<dyn FormatReader>,
+        replay_gain: ReplayGain,
+        options: RenderOptions,
+    ) -> Result<Self, NError> {
+        let source = Source::new(0, format, replay_gain, false)?;
+        // The spec of the track is known once its first packet is decoded
+        let spec = options.spec.or(source.spec).ok_or(NError::NoTrack)?;
+
+        let mut engine = AudioEngine::default();
+        engine.set_resampler_quality(options.resampler_quality);
+        engine.set_time_stretch_mode(options.time_stretch_mode);
+
+        let mut renderer = Self {
+            source,
+            engine,
+            spec,
+            volume: 1.0,
+            playback_speed: 1.0,
+            replay_gain_mode: options.replay_gain_mode,
+            samples: vec![],
+            ended: false,
+        };
+        renderer.set_volume(options.volume);
+        renderer.set_playback_speed(options.playback_speed);
+        Ok(renderer)
+    }
+
+    /// Instance a new `Renderer` of `track`, see `Renderer::new`
+    pub fn from_track(track: &MusicTrack, options: RenderOptions) -> Result<Self, NError> {
+        let (format, replay_gain) = track.get_format_with_gain()?;
+        Self::new(format, replay_gain, options)
+    }
+
+    /// Returns the spec of the rendered samples
+    pub fn spec(&self) -> SignalSpec {
+        self.spec
+    }
+
+    /// Returns the processors run on the audio before the volume is applied
+    pub fn dsp_mut(&mut self) -> &mut DspChain {
+        self.engine.dsp_mut()
+    }
+
+    /// Changes the volume of the next packets, see `Player::set_volume`
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = perceived_volume(volume);
+    }
+
+    /// Changes the playback speed of the next packets, see `Player::set_playback_speed`
+    pub fn set_playback_speed(&mut self, playback_speed: f32) {
+        self.playback_speed = playback_speed.clamp(MIN_PLAYBACK_SPEED, MAX_PLAYBACK_SPEED);
+    }
+
+    /// Returns the position of the last packet rendered, `None` if the track doesn't have a time base
+    pub fn position(&self) -> Option<TrackTime> {
+        self.source.time(self.source.last_ts)
+    }
+
+    /// Returns whether the whole track was rendered
+    pub fn is_ended(&self) -> bool {
+        self.ended
+    }
+
+    /// Moves to the given position like `Player::seek_to`, returning where the track actually is now
+    ///
+    /// Seeking past the end ends the rendering
+    pub fn seek_to(&mut self, seconds: u64, mut frac: f64) -> Result<Option<TrackTime>, NError> {
+        if seconds == 0 && frac == 0.0 {
+            frac = 0.01;
+        }
+
+        let seeked = self.source.format.seek(
+            SeekMode::Coarse,
+            SeekTo::Time {
+                time: Time { seconds, frac },
+                track_id: Some(self.source.track_id),
+            },
+        );
+        self.engine.flush();
+        self.source.primed = None;
+        match seeked {
+            Ok(seeked) => {
+                self.ended = false;
+                Ok(self.source.time(seeked.actual_ts))
+            }
+            Err(err) if is_seek_past_end(&err) => {
+                self.ended = true;
+                Ok(None)
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// Renders the next packet of the track, returning its interleaved samples or `None` once it's ended
+    ///
+    /// A packet may give no samples at all, e.g. if it can't be decoded or while the resampler is filling up
+    pub fn render_packet(&mut self) -> Result<Option<&[f32]>, NError> {
+        if self.ended {
+            return Ok(None);
+        }
+
+        let gain = self.source.replay_gain.factor(self.replay_gain_mode);
+        let volume = self.volume * gain;
+
+        // The first buffer was already decoded when the track was opened
+        let samples = if let Some((_, buffer)) = self.source.primed.take() {
+            self.engine
+                .render(buffer.as_audio_buffer_ref(), self.spec, self.playback_speed)
+        } else {
+            let packet = match self.source.format.next_packet() {
+                Ok(packet) => packet,
+                Err(err) if is_end_of_stream(&err) => {
+                    self.ended = true;
+                    return Ok(None);
+                }
+                Err(err) => {
+                    self.ended = true;
+                    return Err(err.into());
+                }
+            };
+            if packet.track_id() != self.source.track_id {
+                return Ok(Some(&[]));
+            }
+            self.source.last_ts = packet.ts();
+            self.source.end_ts = packet.ts() + packet.dur();
+
+            match self.source.decoder.decode(&packet) {
+                Ok(decoded) => {
+                    self.source.spec = Some(*decoded.spec());
+                    self.engine.render(decoded, self.spec, self.playback_speed)
+                }
+                Err(Error::DecodeError(err)) => {
+                    eprintln!("Decode error: {}", err);
+                    return Ok(Some(&[]));
+                }
+                Err(err) => {
+                    self.ended = true;
+                    return Err(err.into());
+                }
+            }
+        };
+
+        // Applied like the outputs do, so that the samples are the same that would be played
+        self.samples.clear();
+        self.samples
+            .extend(samples.iter().map(|sample| sample * volume));
+        Ok(Some(&self.samples))
+    }
+
+    /// Renders everything left of the track, see `Renderer::render_packet`
+    pub fn render_to_end(&mut self) -> Result<Vec<f32>, NError> {
+        let mut rendered = vec![];
+        while let Some(samples) = self.render_packet()? {
+            rendered.extend_from_slice(samples);
+        }
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::raw_writer::{RawHeader, RawMetadata, RawSampleFormat, RawWriter};
+    use std::fs::File;
+    use std::io::BufWriter;
+    use symphonia::core::audio::Channels;
+    use tempfile::TempDir;
+
+    const RATE: u32 = 8000;
+    const SECONDS: usize = 3;
+
+    /// Samples of the fixture, a stereo ramp that never repeats within a second
+    fn fixture() -> Vec<f32> {
+        (0..RATE as usize * SECONDS * 2)
+            .map(|i| (i % (RATE as usize * 2)) as f32 / (RATE as f32 * 2.0) - 0.5)
+            .collect()
+    }
+
+    /// Writes `fixture` to a raw file inside a new directory
+    fn write_fixture() -> (TempDir, String) {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("fixture.rawf32");
+        let header = RawHeader {
+            metadata: Some(RawMetadata {
+                duration: Some((RATE as usize * SECONDS) as u64),
+                ..RawMetadata::default()
+            }),
+            ..RawHeader::from_spec(
+                SignalSpec::new(RATE, Channels::FRONT_LEFT | Channels::FRONT_RIGHT),
+                RawSampleFormat::F32,
+            )
+        };
+        let file = BufWriter::new(File::create(&path).unwrap());
+        let mut writer = RawWriter::new(file, &header).unwrap();
+        let samples: Vec<f64> = fixture().into_iter().map(f64::from).collect();
+        writer.write_samples(&samples).unwrap();
+        writer.finish().unwrap();
+
+        let path = path.to_str().unwrap().to_string();
+        (dir, path)
+    }
+
+    fn renderer(path: &str, options: RenderOptions) -> Renderer {
+        let track = MusicTrack::new(path).unwrap();
+        Renderer::from_track(&track, options).unwrap()
+    }
+
+    #[test]
+    fn renders_the_same_samples() {
+        let (_dir, path) = write_fixture();
+        let first = renderer(&path, RenderOptions::default())
+            .render_to_end()
+            .unwrap();
+        let second = renderer(&path, RenderOptions::default())
+            .render_to_end()
+            .unwrap();
+
+        assert_eq!(first, second);
+        assert_eq!(first, fixture());
+    }
+
+    #[test]
+    fn seeks_to_the_position() {
+        let (_dir, path) = write_fixture();
+        let mut renderer = renderer(&path, RenderOptions::default());
+        renderer.render_packet().unwrap();
+
+        let seeked = renderer.seek_to(1, 0.5).unwrap().unwrap();
+        assert_eq!(seeked.position, 1.5);
+        assert_eq!(seeked.length, Some(SECONDS as f64));
+
+        let rendered = renderer.render_to_end().unwrap();
+        let start = RATE as usize * 3;
+        assert_eq!(rendered, fixture()[start..]);
+        assert!(renderer.is_ended());
+
+        assert!(renderer.seek_to(SECONDS as u64 + 1, 0.0).unwrap().is_none());
+        assert!(renderer.is_ended());
+    }
+
+    #[test]
+    fn scales_the_volume() {
+        let (_dir, path) = write_fixture();
+        let options = RenderOptions {
+            volume: 0.5,
+            ..RenderOptions::default()
+        };
+        let rendered = renderer(&path, options).render_to_end().unwrap();
+
+        let gain = perceived_volume(0.5);
+        assert!(gain > 0.0 && gain < 0.5);
+        let expected: Vec<f32> = fixture().iter().map(|sample| sample * gain).collect();
+        assert_eq!(rendered, expected);
+    }
+}
@@ -0,0 +1,208 @@
+//! ReplayGain and R128 loudness normalization
+
+use symphonia::core::codecs::{CodecParameters, CODEC_TYPE_OPUS};
+use symphonia::core::meta::{StandardTagKey, Tag};
+
+/// Difference in dB between the ReplayGain reference level (-18 LUFS) and the R128 one (-23 LUFS)
+const R128_TO_REPLAY_GAIN: f32 = 5.0;
+
+/// Which gain gets applied during playback
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    /// Album gain when the track is played together with the rest of its album, track gain otherwise
+    Auto,
+}
+
+/// Gains (in dB) and peaks (linear, 1.0 is full scale) read from the tags of a track
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+    /// Gain stored in the Opus header, always applied as required by RFC 7845
+    pub output_gain: f32,
+    /// Whether the track is being played together with the rest of its album, used by `ReplayGainMode::Auto`
+    pub album_context: bool,
+}
+
+impl ReplayGain {
+    /// Reads both ReplayGain (`REPLAYGAIN_*`) and Opus R128 (`R128_*`) tags
+    pub fn from_tags<'a>(tags: impl IntoIterator<Item = &'a Tag>) -> Self {
+        let mut replay_gain = Self::default();
+        let mut r128_track = None;
+        let mut r128_album = None;
+
+        for tag in tags {
+            let value = tag.value.to_string();
+            match tag.std_key {
+                Some(StandardTagKey::ReplayGainTrackGain) => {
+                    replay_gain.track_gain = parse_gain(&value)
+                }
+                Some(StandardTagKey::ReplayGainTrackPeak) => {
+                    replay_gain.track_peak = parse_gain(&value)
+                }
+                Some(StandardTagKey::ReplayGainAlbumGain) => {
+                    replay_gain.album_gain = parse_gain(&value)
+                }
+                Some(StandardTagKey::ReplayGainAlbumPeak) => {
+                    replay_gain.album_peak = parse_gain(&value)
+                }
+                _ => match tag.key.to_uppercase().as_str() {
+                    "R128_TRACK_GAIN" => r128_track = parse_r128(&value),
+                    "R128_ALBUM_GAIN" => r128_album = parse_r128(&value),
+                    _ => {}
+                },
+            }
+        }
+
+        // R128 tags are stored only in Opus files, where they take precedence
+        if r128_track.is_some() {
+            replay_gain.track_gain = r128_track;
+        }
+        if r128_album.is_some() {
+            replay_gain.album_gain = r128_album;
+        }
+
+        replay_gain
+    }
+
+    /// Reads the output gain from the Opus identification header, if the track is an Opus one
+    pub fn with_codec_params(mut self, codec_params: &CodecParameters) -> Self {
+        if codec_params.codec == CODEC_TYPE_OPUS {
+            if let Some(header) = &codec_params.extra_data {
+                if header.len() >= 18 && &header[..8] == b"OpusHead" {
+                    self.output_gain = i16::from_le_bytes([header[16], header[17]]) as f32 / 256.0;
+                }
+            }
+        }
+        self
+    }
+
+    /// Fills the values missing in `self` with the ones found in `other`
+    pub fn merge(&mut self, other: ReplayGain) {
+        self.track_gain = self.track_gain.or(other.track_gain);
+        self.track_peak = self.track_peak.or(other.track_peak);
+        self.album_gain = self.album_gain.or(other.album_gain);
+        self.album_peak = self.album_peak.or(other.album_peak);
+        if self.output_gain == 0.0 {
+            self.output_gain = other.output_gain;
+        }
+    }
+
+    /// Returns the linear factor to apply to the samples, lowered if needed so that the peak doesn't clip
+    pub fn factor(&self, mode: ReplayGainMode) -> f32 {
+        let use_album = match mode {
+            ReplayGainMode::Off => return db_to_linear(self.output_gain),
+            ReplayGainMode::Track => false,
+            ReplayGainMode::Album => true,
+            ReplayGainMode::Auto => self.album_context,
+        };
+
+        let (gain, peak) = if use_album && self.album_gain.is_some() {
+            (self.album_gain, self.album_peak)
+        } else if self.track_gain.is_some() {
+            (self.track_gain, self.track_peak)
+        } else {
+            (self.album_gain, self.album_peak)
+        };
+
+        let factor = db_to_linear(self.output_gain + gain.unwrap_or(0.0));
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+fn db_to_linear(db: f32) -> f32 {
+    10.0f32.powf(db / 20.0)
+}
+
+/// Parses values like `-6.54 dB` or `0.988312`
+fn parse_gain(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .trim_end_matches(|c: char| c.is_alphabetic())
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Parses a Q7.8 R128 gain, converting it to the ReplayGain reference level
+fn parse_r128(value: &str) -> Option<f32> {
+    value
+        .trim()
+        .parse::<i16>()
+        .ok()
+        .map(|gain| gain as f32 / 256.0 + R128_TO_REPLAY_GAIN)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_db(factor: f32, db: f32) {
+        assert!(
+            (20.0 * factor.log10() - db).abs() < 1e-4,
+            "{factor} isn't {db} dB"
+        );
+    }
+
+    #[test]
+    fn factor_of_the_mode() {
+        let replay_gain = ReplayGain {
+            track_gain: Some(-6.0),
+            album_gain: Some(-3.0),
+            output_gain: -1.0,
+            ..ReplayGain::default()
+        };
+        assert_db(replay_gain.factor(ReplayGainMode::Off), -1.0);
+        assert_db(replay_gain.factor(ReplayGainMode::Track), -7.0);
+        assert_db(replay_gain.factor(ReplayGainMode::Album), -4.0);
+        assert_db(replay_gain.factor(ReplayGainMode::Auto), -7.0);
+
+        let in_album = ReplayGain {
+            album_context: true,
+            ..replay_gain
+        };
+        assert_db(in_album.factor(ReplayGainMode::Auto), -4.0);
+    }
+
+    #[test]
+    fn factor_falls_back() {
+        let track_only = ReplayGain {
+            track_gain: Some(-6.0),
+            ..ReplayGain::default()
+        };
+        assert_db(track_only.factor(ReplayGainMode::Album), -6.0);
+
+        let album_only = ReplayGain {
+            album_gain: Some(-3.0),
+            ..ReplayGain::default()
+        };
+        assert_db(album_only.factor(ReplayGainMode::Track), -3.0);
+
+        assert_eq!(ReplayGain::default().factor(ReplayGainMode::Track), 1.0);
+    }
+
+    #[test]
+    fn factor_does_not_clip_the_peak() {
+        let replay_gain = ReplayGain {
+            track_gain: Some(6.0),
+            track_peak: Some(0.8),
+            ..ReplayGain::default()
+        };
+        assert_eq!(replay_gain.factor(ReplayGainMode::Track), 1.25);
+
+        let quiet = ReplayGain {
+            track_peak: Some(0.1),
+            ..replay_gain
+        };
+        assert_db(quiet.factor(ReplayGainMode::Track), 6.0);
+    }
+}
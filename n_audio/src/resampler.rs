@@ -0,0 +1,269 @@
+//! Sample rate conversion, used to play any track at the rate of the output device
+
+use std::f64::consts::PI;
+
+/// How many points of the kernel are computed for every input sample
+const PHASES: usize = 256;
+
+/// Trade-off between the quality of the conversion and its cost
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ResamplerQuality {
+    /// Linear interpolation, the cheapest one, but it lets some aliasing through
+    Linear,
+    /// Windowed sinc using 16 taps
+    #[default]
+    Medium,
+    /// Windowed sinc using 64 taps
+    High,
+}
+
+impl ResamplerQuality {
+    /// Zero crossings of the sinc on each side of the kernel
+    fn zero_crossings(&self) -> f64 {
+        match self {
+            ResamplerQuality::Linear => 1.0,
+            ResamplerQuality::Medium => 8.0,
+            ResamplerQuality::High => 32.0,
+        }
+    }
+
+    /// Cutoff frequency, relative to the Nyquist frequency of the lowest rate
+    fn cutoff(&self) -> f64 {
+        match self {
+            ResamplerQuality::Linear => 1.0,
+            ResamplerQuality::Medium => 0.9,
+            ResamplerQuality::High => 0.97,
+        }
+    }
+
+    /// Beta of the Kaiser window
+    fn beta(&self) -> f64 {
+        match self {
+            ResamplerQuality::Linear => 0.0,
+            ResamplerQuality::Medium => 6.0,
+            ResamplerQuality::High => 9.0,
+        }
+    }
+}
+
+/// Polyphase resampler, keeping its state between buffers so that they can be converted one by one
+#[derive(Clone, Debug)]
+pub struct Resampler {
+    quality: ResamplerQuality,
+    cutoff: f64,
+    /// Half the width of the kernel, in input samples
+    radius: f64,
+    kernel: Vec<f32>,
+    history: Vec<Vec<f32>>,
+    /// Position of the next output sample, in input samples, relative to the start of `history`
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(quality: ResamplerQuality) -> Self {
+        let mut resampler = Self {
+            quality,
+            cutoff: 0.0,
+            radius: 0.0,
+            kernel: vec![],
+            history: vec![],
+            position: 0.0,
+        };
+        resampler.set_cutoff(1.0);
+        resampler
+    }
+
+    pub fn quality(&self) -> ResamplerQuality {
+        self.quality
+    }
+
+    /// Discards the samples kept from the previous buffers
+    pub fn reset(&mut self) {
+        self.history.clear();
+        self.position = 0.0;
+    }
+
+    /// Converts the planar samples in `input` by `ratio` (input rate / output rate), appending the resulting
+    /// interleaved samples to `out`
+    ///
+    /// The last few input samples are kept until the next call, since they are needed to compute the following output
+    pub fn process(&mut self, input: &[Vec<f32>], ratio: f64, out: &mut Vec<f32>) {
+        if input.is_empty() || ratio <= 0.0 {
+            return;
+        }
+        if self.history.len() != input.len() {
+            self.history = vec![vec![]; input.len()];
+            self.position = 0.0;
+        }
+
+        for (history, input) in self.history.iter_mut().zip(input) {
+            history.extend_from_slice(input);
+        }
+        let len = self.history[0].len();
+
+        // The rates are the same, so there's nothing to filter: the samples are only interleaved, along with the ones
+        // kept for the kernel if the ratio just changed
+        if ratio == 1.0 {
+            let start = (self.position.round() as usize).min(len);
+            out.extend(
+                (start..len).flat_map(|i| self.history.iter().map(move |history| history[i])),
+            );
+            self.history.iter_mut().for_each(Vec::clear);
+            self.position = 0.0;
+            return;
+        }
+
+        // Lower the cutoff when downsampling, so that nothing above the new Nyquist frequency gets through
+        let cutoff = (1.0 / ratio).min(1.0);
+        if (cutoff - self.cutoff).abs() > 1e-3 {
+            self.set_cutoff(cutoff);
+        }
+
+        while self.position + self.radius < len as f64 {
+            let start = (self.position - self.radius).ceil().max(0.0) as usize;
+            let end = ((self.position + self.radius).floor() as usize).min(len - 1);
+
+            for history in &self.history {
+                let mut sample = 0.0;
+                for (k, x) in history.iter().enumerate().take(end + 1).skip(start) {
+                    sample += x * self.kernel_at(self.position - k as f64);
+                }
+                out.push(sample);
+            }
+            self.position += ratio;
+        }
+
+        // Drop the samples that won't be used anymore
+        let used = ((self.position - self.radius).floor() - 1.0).max(0.0) as usize;
+        let used = used.min(len);
+        for history in &mut self.history {
+            history.drain(..used);
+        }
+        self.position -= used as f64;
+    }
+
+    fn set_cutoff(&mut self, cutoff: f64) {
+        self.cutoff = cutoff;
+
+        if self.quality == ResamplerQuality::Linear {
+            self.radius = 1.0;
+            self.kernel = (0..=PHASES + 1)
+                .map(|i| (1.0 - i as f64 / PHASES as f64).max(0.0) as f32)
+                .collect();
+            return;
+        }
+
+        let fc = cutoff * self.quality.cutoff();
+        let beta = self.quality.beta();
+        self.radius = self.quality.zero_crossings() / fc;
+
+        let len = (self.radius * PHASES as f64).ceil() as usize + 2;
+        self.kernel = (0..len)
+            .map(|i| {
+                let t = i as f64 / PHASES as f64;
+                if t >= self.radius {
+                    return 0.0;
+                }
+                let x = PI * fc * t;
+                let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+                let window =
+                    bessel_i0(beta * (1.0 - (t / self.radius).powi(2)).sqrt()) / bessel_i0(beta);
+                (fc * sinc * window) as f32
+            })
+            .collect();
+    }
+
+    /// Returns the value of the kernel at `t` input samples from its center
+    fn kernel_at(&self, t: f64) -> f32 {
+        let index = t.abs() * PHASES as f64;
+        let i = index as usize;
+        if i + 1 >= self.kernel.len() {
+            return 0.0;
+        }
+        let frac = (index - i as f64) as f32;
+        self.kernel[i] + (self.kernel[i + 1] - self.kernel[i]) * frac
+    }
+}
+
+impl Default for Resampler {
+    fn default() -> Self {
+        Self::new(ResamplerQuality::default())
+    }
+}
+
+/// Modified Bessel function of the first kind, used by the Kaiser window
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let half = x / 2.0;
+    for k in 1..32 {
+        term *= (half / k as f64).powi(2);
+        sum += term;
+        if term < sum * 1e-12 {
+            break;
+        }
+    }
+    sum
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: f64, len: usize) -> Vec<f32> {
+        (0..len)
+            .map(|i| (2.0 * PI * frequency * i as f64 / rate).sin() as f32)
+            .collect()
+    }
+
+    #[test]
+    fn same_rate_is_unchanged() {
+        let left = sine(1000.0, 48000.0, 4800);
+        let right = sine(15000.0, 48000.0, 4800);
+        for quality in [
+            ResamplerQuality::Linear,
+            ResamplerQuality::Medium,
+            ResamplerQuality::High,
+        ] {
+            let mut resampler = Resampler::new(quality);
+            let mut out = vec![];
+            for (left, right) in left.chunks(1000).zip(right.chunks(1000)) {
+                resampler.process(&[left.to_vec(), right.to_vec()], 1.0, &mut out);
+            }
+            let expected: Vec<f32> = left
+                .iter()
+                .zip(&right)
+                .flat_map(|(left, right)| [*left, *right])
+                .collect();
+            assert_eq!(out, expected, "{quality:?}");
+        }
+    }
+
+    #[test]
+    fn converts_the_rate() {
+        for quality in [
+            ResamplerQuality::Linear,
+            ResamplerQuality::Medium,
+            ResamplerQuality::High,
+        ] {
+            let input = sine(1000.0, 44100.0, 44100);
+            let mut resampler = Resampler::new(quality);
+            let mut out = vec![];
+            for chunk in input.chunks(1024) {
+                resampler.process(&[chunk.to_vec()], 44100.0 / 48000.0, &mut out);
+            }
+            // Only the last samples needed by the kernel are missing
+            assert!((47900..=48000).contains(&out.len()), "{}", out.len());
+
+            let steady = &out[out.len() / 4..out.len() * 3 / 4];
+            let crossings = steady
+                .windows(2)
+                .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+                .count();
+            let frequency = crossings as f64 * 48000.0 / steady.len() as f64;
+            assert!((frequency - 1000.0).abs() < 10.0, "{frequency} Hz");
+            let peak = steady.iter().fold(0.0f32, |peak, x| peak.max(x.abs()));
+            assert!((peak - 1.0).abs() < 0.02, "{quality:?} peak {peak}");
+        }
+    }
+}
@@ -0,0 +1,228 @@
+//! Time stretching, used to change the playback speed without changing the pitch
+
+use std::f32::consts::PI;
+
+/// Lowest playback speed supported
+pub const MIN_PLAYBACK_SPEED: f32 = 0.5;
+/// Highest playback speed supported
+pub const MAX_PLAYBACK_SPEED: f32 = 3.0;
+
+/// Length of the frames overlapped by the stretcher, in seconds
+const FRAME_LENGTH: f64 = 0.03;
+/// How far a frame may be moved to better match the previous one, in seconds
+const TOLERANCE: f64 = 0.008;
+
+/// How the playback speed gets applied
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum TimeStretchMode {
+    /// The tracks keep their original pitch
+    #[default]
+    PreservePitch,
+    /// The tracks get played faster or slower like a tape would do, changing their pitch
+    Tape,
+}
+
+/// WSOLA (Waveform Similarity Overlap-Add) time stretcher, keeping its state between buffers so that they can be
+/// processed one by one
+#[derive(Clone, Debug)]
+pub struct TimeStretch {
+    rate: u32,
+    /// Half the length of a frame, it's how many samples get written for every frame
+    hop: usize,
+    tolerance: usize,
+    window: Vec<f32>,
+    input: Vec<Vec<f32>>,
+    /// Position in `input` where the next frame should ideally start
+    position: f64,
+    /// Where the samples that naturally follow the previous frame start in `input`
+    natural: Option<usize>,
+    /// Second half of the previous frame, to be overlapped with the next one
+    tail: Vec<Vec<f32>>,
+}
+
+impl TimeStretch {
+    pub fn new(rate: u32) -> Self {
+        let hop = ((rate as f64 * FRAME_LENGTH / 2.0) as usize).max(1);
+        let tolerance = (rate as f64 * TOLERANCE) as usize;
+        // Hann windows overlapped by half of their length always add up to 1
+        let window = (0..hop * 2)
+            .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / (hop * 2) as f32).cos())
+            .collect();
+
+        Self {
+            rate,
+            hop,
+            tolerance,
+            window,
+            input: vec![],
+            position: 0.0,
+            natural: None,
+            tail: vec![],
+        }
+    }
+
+    pub fn rate(&self) -> u32 {
+        self.rate
+    }
+
+    /// Discards the samples kept from the previous buffers
+    pub fn reset(&mut self) {
+        self.input.clear();
+        self.tail.clear();
+        self.position = 0.0;
+        self.natural = None;
+    }
+
+    /// Stretches the planar samples in `input` so that they last `1 / speed` times as long, appending them to `out`
+    ///
+    /// A frame of samples is kept until the next call, since it's needed to compute the following output
+    pub fn process(&mut self, input: &[Vec<f32>], speed: f64, out: &mut [Vec<f32>]) {
+        if input.is_empty() || speed <= 0.0 {
+            return;
+        }
+        if self.input.len() != input.len() {
+            self.reset();
+            self.input = vec![vec![]; input.len()];
+            self.tail = vec![vec![0.0; self.hop]; input.len()];
+        }
+
+        for (buffered, input) in self.input.iter_mut().zip(input) {
+            buffered.extend_from_slice(input);
+        }
+
+        let len = self.input[0].len();
+        let frame = self.hop * 2;
+        loop {
+            let nominal = self.position.round() as usize;
+            if nominal + self.tolerance + frame > len {
+                break;
+            }
+
+            let start = match self.natural {
+                Some(natural) => self.best_start(natural, nominal),
+                None => nominal,
+            };
+
+            for ((input, tail), out) in self.input.iter().zip(&mut self.tail).zip(out.iter_mut()) {
+                for i in 0..self.hop {
+                    out.push(tail[i] + input[start + i] * self.window[i]);
+                    tail[i] = input[start + self.hop + i] * self.window[self.hop + i];
+                }
+            }
+
+            self.natural = Some(start + self.hop);
+            self.position += self.hop as f64 * speed;
+        }
+
+        // Drop the samples that won't be used anymore
+        let mut used = (self.position.floor() as usize).saturating_sub(self.tolerance);
+        if let Some(natural) = self.natural {
+            used = used.min(natural);
+        }
+        let used = used.min(len);
+        for input in &mut self.input {
+            input.drain(..used);
+        }
+        self.position -= used as f64;
+        self.natural = self.natural.map(|natural| natural - used);
+    }
+
+    /// Finds the start of the frame, near `nominal`, that best continues the samples starting at `natural`
+    fn best_start(&self, natural: usize, nominal: usize) -> usize {
+        let from = nominal.saturating_sub(self.tolerance);
+        let to = nominal + self.tolerance;
+
+        // Search roughly first and then refine around the best match, to keep the cost low
+        let coarse = self.best_in(natural, (from..=to).step_by(4));
+        let refined = coarse.saturating_sub(3).max(from)..=(coarse + 3).min(to);
+        self.best_in(natural, refined)
+    }
+
+    fn best_in(&self, natural: usize, candidates: impl Iterator<Item = usize>) -> usize {
+        let mut best = (f32::MIN, natural);
+
+        for candidate in candidates {
+            let mut correlation = 0.0;
+            let mut energy = 0.0;
+            for i in (0..self.hop).step_by(2) {
+                let (mut a, mut b) = (0.0, 0.0);
+                for input in &self.input {
+                    a += input[natural + i];
+                    b += input[candidate + i];
+                }
+                correlation += a * b;
+                energy += b * b;
+            }
+
+            let similarity = correlation / (energy + f32::EPSILON).sqrt();
+            if similarity > best.0 {
+                best = (similarity, candidate);
+            }
+        }
+
+        best.1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+    const FREQUENCY: f64 = 440.0;
+
+    /// Stretches two seconds of a stereo sine, fed in buffers of uneven sizes like the decoders do
+    fn stretch(speed: f64) -> Vec<Vec<f32>> {
+        let samples: Vec<f32> = (0..RATE as usize * 2)
+            .map(|i| (2.0 * std::f64::consts::PI * FREQUENCY * i as f64 / RATE as f64).sin() as f32)
+            .collect();
+        let mut time_stretch = TimeStretch::new(RATE);
+        let mut out = vec![vec![], vec![]];
+        for (i, buffer) in samples.chunks(1152).enumerate() {
+            // Buffers shorter than a frame must be kept until there's enough input
+            let (first, second) = buffer.split_at(if i % 3 == 0 { 100 } else { 0 });
+            for buffer in [first, second] {
+                time_stretch.process(&[buffer.to_vec(), buffer.to_vec()], speed, &mut out);
+            }
+        }
+        out
+    }
+
+    #[test]
+    fn keeps_duration_and_pitch() {
+        for speed in [0.5, 0.8, 1.0, 1.25, 2.0, 3.0] {
+            let out = stretch(speed);
+            assert_eq!(out[0], out[1]);
+
+            // Up to a frame and the tolerance are kept for the next buffer
+            let expected = RATE as f64 * 2.0 / speed;
+            let missing = expected - out[0].len() as f64;
+            assert!(
+                (0.0..=RATE as f64 * (FRAME_LENGTH + TOLERANCE) / speed + 1.0).contains(&missing),
+                "{} samples at speed {speed}, expected {expected}",
+                out[0].len()
+            );
+
+            let steady = &out[0][out[0].len() / 4..out[0].len() * 3 / 4];
+            let crossings = steady
+                .windows(2)
+                .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+                .count();
+            let frequency = crossings as f64 * RATE as f64 / steady.len() as f64;
+            assert!(
+                (frequency - FREQUENCY).abs() < FREQUENCY * 0.02,
+                "{frequency} Hz at speed {speed}"
+            );
+        }
+    }
+
+    #[test]
+    fn reset_discards_the_input() {
+        let mut time_stretch = TimeStretch::new(RATE);
+        let mut out = vec![vec![]];
+        time_stretch.process(&[vec![1.0; 1000]], 1.5, &mut out);
+        time_stretch.reset();
+        time_stretch.process(&[vec![0.0; RATE as usize]], 1.5, &mut out);
+        assert!(out[0].iter().all(|sample| *sample == 0.0));
+    }
+}
@@ -0,0 +1,322 @@
+//! Access to the audio that is being heard, e.g. to draw a spectrum or a level meter
+//!
+//! The output keeps writing the samples it sends to the device in a `VisualizationTap`, without any lock, so that
+//! reading them never makes the playback stutter (see `Player::visualization`)
+
+use dasp::sample::ToSample;
+use rustfft::num_complex::Complex;
+use rustfft::{Fft, FftPlanner};
+use std::f32::consts::PI;
+use std::fmt;
+use std::fmt::{Debug, Formatter};
+use std::sync::atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use symphonia::core::audio::{Channels, SignalSpec};
+
+/// How many samples the tap remembers, a bit more than half a second of stereo audio at 48 kHz
+pub const TAP_CAPACITY: usize = 1 << 16;
+/// Lowest frequency shown by `Spectrum::bands`
+const MIN_FREQUENCY: f32 = 20.0;
+/// Highest frequency shown by `Spectrum::bands`, if the rate allows it
+const MAX_FREQUENCY: f32 = 20000.0;
+
+struct Inner {
+    /// Ring of interleaved samples, stored as the bits of an `f32`
+    samples: Box<[AtomicU32]>,
+    /// How many samples were written since the start, the last one is at `written - 1`
+    written: AtomicUsize,
+    /// Rate and channels of the samples, 0 until something gets written
+    spec: AtomicU64,
+    /// How many samples were written but aren't heard yet, because they are still in the buffers of the device
+    delay: AtomicUsize,
+}
+
+/// The most recent samples sent to the output, after the DSP chain and the volume
+///
+/// It's cheap to clone and every clone reads the same samples
+#[derive(Clone)]
+pub struct VisualizationTap {
+    inner: Arc<Inner>,
+}
+
+impl Default for VisualizationTap {
+    fn default() -> Self {
+        Self {
+            inner: Arc::new(Inner {
+                samples: (0..TAP_CAPACITY).map(|_| AtomicU32::new(0)).collect(),
+                written: AtomicUsize::new(0),
+                spec: AtomicU64::new(0),
+                delay: AtomicUsize::new(0),
+            }),
+        }
+    }
+}
+
+impl VisualizationTap {
+    /// Returns the spec of the samples, `None` if nothing was played yet
+    pub fn spec(&self) -> Option<SignalSpec> {
+        let spec = self.inner.spec.load(Ordering::Acquire);
+        let rate = (spec >> 32) as u32;
+        let channels = Channels::from_bits_truncate(spec as u32);
+        (rate != 0 && channels.count() != 0).then(|| SignalSpec::new(rate, channels))
+    }
+
+    /// Replaces `samples` with the last `frames` interleaved frames that can be heard right now, returning their spec
+    ///
+    /// It's `None` if nothing was played yet, and there may be less frames than asked if the playback just started
+    /// The samples being overwritten while they are read may get mixed with newer ones, which is fine for drawing them
+    pub fn read(&self, frames: usize, samples: &mut Vec<f32>) -> Option<SignalSpec> {
+        samples.clear();
+        let spec = self.spec()?;
+        let channels = spec.channels.count();
+
+        let written = self.inner.written.load(Ordering::Acquire);
+        let delay = self.inner.delay.load(Ordering::Relaxed);
+        // Half of the ring is left to the writer, so that what is read is not being overwritten
+        let available = written.min(TAP_CAPACITY / 2).saturating_sub(delay);
+        let mut end = written - delay.min(written);
+        end -= end % channels;
+        let len = (frames * channels).min(available - available % channels);
+
+        samples.extend(
+            (end - len..end).map(|i| {
+                f32::from_bits(self.inner.samples[i % TAP_CAPACITY].load(Ordering::Relaxed))
+            }),
+        );
+        Some(spec)
+    }
+
+    /// Appends the samples sent to the device, `delay` is how long they'll take to be heard, if the device knows it
+    pub(crate) fn write<T: ToSample<f32> + Copy>(
+        &self,
+        data: &[T],
+        spec: SignalSpec,
+        delay: Option<Duration>,
+    ) {
+        let spec = ((spec.rate as u64) << 32) | spec.channels.bits() as u64;
+        if self.inner.spec.load(Ordering::Relaxed) != spec {
+            self.inner.spec.store(spec, Ordering::Release);
+        }
+        if let Some(delay) = delay {
+            let channels = (spec as u32).count_ones() as f64;
+            let delay = delay.as_secs_f64() * (spec >> 32) as f64 * channels;
+            self.inner
+                .delay
+                .store((delay as usize).min(TAP_CAPACITY / 4), Ordering::Relaxed);
+        }
+
+        // There's a single writer, the stream of the output
+        let written = self.inner.written.load(Ordering::Relaxed);
+        for (i, sample) in data.iter().copied().enumerate() {
+            let sample: f32 = sample.to_sample_();
+            self.inner.samples[(written + i) % TAP_CAPACITY]
+                .store(sample.to_bits(), Ordering::Relaxed);
+        }
+        self.inner
+            .written
+            .store(written.wrapping_add(data.len()), Ordering::Release);
+    }
+}
+
+impl Debug for VisualizationTap {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("VisualizationTap")
+            .field("spec", &self.spec())
+            .field("written", &self.inner.written.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+/// Levels of a channel, as amplitudes where 1.0 is full scale
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Meter {
+    pub peak: f32,
+    pub rms: f32,
+}
+
+impl Meter {
+    /// Measures the levels of every channel of the interleaved `samples`
+    pub fn measure(samples: &[f32], channels: usize) -> Vec<Meter> {
+        let channels = channels.max(1);
+        let frames = samples.len() / channels;
+        (0..channels)
+            .map(|ch| {
+                let channel = samples.iter().skip(ch).step_by(channels).take(frames);
+                let (peak, sum) = channel.fold((0.0f32, 0.0f64), |(peak, sum), sample| {
+                    (peak.max(sample.abs()), sum + (*sample as f64).powi(2))
+                });
+                Meter {
+                    peak,
+                    rms: if frames > 0 {
+                        (sum / frames as f64).sqrt() as f32
+                    } else {
+                        0.0
+                    },
+                }
+            })
+            .collect()
+    }
+}
+
+/// Converts an amplitude to decibels relative to full scale, silence is `f32::NEG_INFINITY`
+pub fn to_db(amplitude: f32) -> f32 {
+    20.0 * amplitude.log10()
+}
+
+/// Computes the spectrum of the audio, using a Hann window over a fixed number of frames
+pub struct Spectrum {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    buffer: Vec<Complex<f32>>,
+    magnitudes: Vec<f32>,
+}
+
+impl Spectrum {
+    /// Instance a new `Spectrum` over `size` frames, which is faster if it's a power of two
+    pub fn new(size: usize) -> Self {
+        let size = size.max(2);
+        Self {
+            fft: FftPlanner::new().plan_fft_forward(size),
+            window: (0..size)
+                .map(|i| 0.5 - 0.5 * (2.0 * PI * i as f32 / (size - 1) as f32).cos())
+                .collect(),
+            buffer: vec![],
+            magnitudes: vec![],
+        }
+    }
+
+    /// Returns how many frames are analyzed at once
+    pub fn size(&self) -> usize {
+        self.window.len()
+    }
+
+    /// Analyzes the last `Spectrum::size` frames of the interleaved `samples`, mixed to mono
+    ///
+    /// Returns the amplitude of `size / 2` bins, going from 0 Hz to half the rate, where a full scale sine is 1.0
+    /// Missing frames are treated as silence
+    pub fn process(&mut self, samples: &[f32], channels: usize) -> &[f32] {
+        let channels = channels.max(1);
+        let size = self.size();
+        let frames = samples.len() / channels;
+        let start = frames.saturating_sub(size);
+
+        self.buffer.clear();
+        self.buffer
+            .resize(size - (frames - start), Complex::default());
+        self.buffer.extend((start..frames).map(|frame| {
+            let sum = samples[frame * channels..(frame + 1) * channels]
+                .iter()
+                .sum::<f32>();
+            Complex::new(sum / channels as f32, 0.0)
+        }));
+        for (value, window) in self.buffer.iter_mut().zip(&self.window) {
+            *value *= window;
+        }
+        self.fft.process(&mut self.buffer);
+
+        // The Hann window halves the amplitude, and half of it goes to the negative frequencies
+        let scale = 4.0 / size as f32;
+        self.magnitudes.clear();
+        self.magnitudes
+            .extend(self.buffer[..size / 2].iter().map(|bin| bin.norm() * scale));
+        &self.magnitudes
+    }
+
+    /// Groups the `magnitudes` returned by `Spectrum::process` in `count` bands spaced logarithmically, from 20 Hz to
+    /// 20 kHz (or half the rate), as it's heard
+    ///
+    /// Every band gets the loudest bin in it, in decibels
+    pub fn bands(magnitudes: &[f32], rate: u32, count: usize) -> Vec<f32> {
+        let nyquist = rate as f32 / 2.0;
+        if magnitudes.is_empty() || count == 0 || nyquist <= MIN_FREQUENCY {
+            return vec![f32::NEG_INFINITY; count];
+        }
+        let max = MAX_FREQUENCY.min(nyquist);
+        let hz_per_bin = nyquist / magnitudes.len() as f32;
+        let frequency =
+            |band: usize| MIN_FREQUENCY * (max / MIN_FREQUENCY).powf(band as f32 / count as f32);
+
+        (0..count)
+            .map(|band| {
+                let start = (frequency(band) / hz_per_bin) as usize;
+                // Low bands may be narrower than a bin, they still need one
+                let end = ((frequency(band + 1) / hz_per_bin) as usize).max(start + 1);
+                magnitudes[start.min(magnitudes.len() - 1)..end.min(magnitudes.len())]
+                    .iter()
+                    .copied()
+                    .fold(0.0, f32::max)
+            })
+            .map(to_db)
+            .collect()
+    }
+}
+
+impl Debug for Spectrum {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Spectrum")
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::FRAC_1_SQRT_2;
+
+    /// Interleaved stereo sine with the same samples on both channels
+    fn stereo_sine(frames: usize, cycles_per_frame: f32, amplitude: f32) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = amplitude * (2.0 * PI * cycles_per_frame * i as f32).sin();
+                [sample, sample]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn measures_the_levels() {
+        let mut samples = stereo_sine(1000, 0.01, 1.0);
+        for frame in samples.chunks_mut(2) {
+            frame[1] = -0.5;
+        }
+
+        let meters = Meter::measure(&samples, 2);
+        assert_eq!(meters.len(), 2);
+        assert!((meters[0].peak - 1.0).abs() < 1e-3);
+        assert!((meters[0].rms - FRAC_1_SQRT_2).abs() < 1e-3);
+        assert_eq!(
+            meters[1],
+            Meter {
+                peak: 0.5,
+                rms: 0.5
+            }
+        );
+
+        assert_eq!(Meter::measure(&[], 2), [Meter::default(); 2]);
+    }
+
+    #[test]
+    fn spectrum_of_a_full_scale_sine() {
+        let size = 1024;
+        let bin = 64;
+        let mut spectrum = Spectrum::new(size);
+        // More frames than needed, only the last ones are analyzed
+        let samples = stereo_sine(size * 2, bin as f32 / size as f32, 1.0);
+        let magnitudes = spectrum.process(&samples, 2).to_vec();
+
+        assert_eq!(magnitudes.len(), size / 2);
+        assert!((magnitudes[bin] - 1.0).abs() < 0.01, "{}", magnitudes[bin]);
+        for (i, magnitude) in magnitudes.iter().enumerate() {
+            if i.abs_diff(bin) > 1 {
+                assert!(*magnitude < 0.01, "bin {i} is {magnitude}");
+            }
+        }
+
+        assert!(spectrum
+            .process(&[], 2)
+            .iter()
+            .all(|magnitude| *magnitude == 0.0));
+    }
+}
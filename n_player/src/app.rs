@@ -1,17 +1,24 @@
 use crate::localization::{get_locale_denominator, localize};
 use crate::runner::{run, RunnerMessage, RunnerSeek};
 use crate::{
-    add_all_tracks_to_player, bus_server, get_image_squared, AppData, FileTrack, Localization,
-    MainWindow, SettingsData, Theme, TrackData, WindowSize,
+    add_all_tracks_to_player, bus_server, get_image_squared, AppData, CrossfadeCurve, EqBand,
+    EqBandData, EqFilter, EqPreset, FileTrack, Localization, MainWindow, ReplayGainMode,
+    ResamplerQuality, SettingsData, Theme, TrackData, Visualizer, WindowSize,
 };
 use flume::{Receiver, Sender};
+use n_audio::equalizer::{EqSettings, Equalizer, MAX_PARAMETRIC_BANDS};
+use n_audio::http_source::is_url;
+use n_audio::loudness;
 use n_audio::music_track::MusicTrack;
 use n_audio::queue::QueuePlayer;
-use n_audio::remove_ext;
+use n_audio::visualization::{Spectrum, VisualizationTap};
+use n_audio::{remove_ext, NError, TrackTime};
 use pollster::FutureExt;
-use slint::{ComponentHandle, Model, VecModel, Weak};
+use slint::{ComponentHandle, Model, SharedString, VecModel, Weak};
+use std::collections::HashMap;
 use std::mem;
 use std::ops::DerefMut;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tempfile::NamedTempFile;
@@ -22,9 +29,24 @@ pub type Settings = Arc<RwLock<crate::settings::Settings>>;
 #[allow(type_alias_bounds)]
 pub type Platform<P: crate::platform::Platform + Send + 'static> = Arc<RwLock<P>>;
 
+/// How often the visualizer gets redrawn
+const VISUALIZER_INTERVAL: Duration = Duration::from_millis(33);
+/// Frames analyzed by the spectrum, about 40 ms at 48 kHz
+const SPECTRUM_SIZE: usize = 2048;
+const SPECTRUM_BANDS: usize = 48;
+/// The spectrum shows the levels from here to 0 dBFS
+const SPECTRUM_FLOOR: f32 = -60.0;
+/// How much a bar of the spectrum can fall every time it's redrawn
+const SPECTRUM_FALL: f32 = 0.05;
+/// Frames drawn by the oscilloscope, they are reduced to `WAVEFORM_POINTS`
+const WAVEFORM_FRAMES: usize = 1024;
+const WAVEFORM_POINTS: usize = 256;
+
 enum Changes {
     Tracks(Vec<TrackData>),
     Metadata(usize, TrackData),
+    /// A track added to the queue after the scan, like an opened stream
+    Added(TrackData),
 }
 
 pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
@@ -45,7 +67,33 @@ pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
     let tmp = NamedTempFile::new().unwrap();
     let (tx, rx) = flume::unbounded();
 
-    let player = QueuePlayer::new(settings.read().await.path.clone());
+    let mut player = QueuePlayer::new(settings.read().await.path.clone());
+    player
+        .set_crossfade(settings.read().await.crossfade())
+        .await
+        .unwrap();
+    player
+        .set_replay_gain_mode(settings.read().await.replay_gain.into())
+        .await
+        .unwrap();
+    player
+        .set_device(settings.read().await.output_device.clone())
+        .await
+        .unwrap();
+    player
+        .set_resampler_quality(settings.read().await.resampler_quality.into())
+        .await
+        .unwrap();
+    player
+        .set_time_stretch_mode(settings.read().await.time_stretch_mode())
+        .await
+        .unwrap();
+    if let Some(equalizer) = settings.read().await.equalizer() {
+        player
+            .insert_dsp(0, Box::new(Equalizer::new(equalizer)))
+            .await
+            .unwrap();
+    }
 
     let runner = Arc::new(RwLock::new(crate::runner::Runner::new(player)));
 
@@ -65,13 +113,17 @@ pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
         .await
         .unwrap();
     let (tx_tracks, rx_tracks) = flume::unbounded();
+    let (tx_loudness, rx_loudness) = flume::unbounded();
+    let (tx_scanned, rx_scanned) = flume::unbounded();
     let s = settings.clone();
     let future = tokio::spawn(async move {
         let runner_future = tokio::task::spawn(run(r.clone(), rx));
         let bus_future = tokio::task::spawn(bus_server::run(p.clone(), r.clone(), tmp));
         let loader_future = tokio::task::spawn(loader(r.clone(), s, p, tx_l, rx_path, tx_tracks));
+        let scanner_future =
+            tokio::task::spawn(loudness_scanner(r.clone(), rx_loudness, tx_scanned));
 
-        let _ = tokio::join!(runner_future, bus_future, loader_future);
+        let _ = tokio::join!(runner_future, bus_future, loader_future, scanner_future);
     });
 
     let (tx_searching, rx_searching) = flume::unbounded();
@@ -85,6 +137,7 @@ pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
         tx_searching,
         tx_changing,
         tx_path,
+        tx_loudness,
     )
     .await;
 
@@ -101,10 +154,18 @@ pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
         rx_changing,
         rx_searching,
         rx_l,
+        rx_scanned,
+    ));
+
+    let visualizer = tokio::task::spawn(visualizer_task(
+        runner.read().await.visualization(),
+        settings.clone(),
+        main_window.as_weak(),
     ));
 
     tokio::task::block_in_place(|| main_window.run().unwrap());
 
+    visualizer.abort();
     updater.abort();
     future.abort();
 
@@ -119,6 +180,41 @@ pub async fn run_app<P: crate::platform::Platform + Send + 'static + Sync>(
     settings.read().await.save(platform.read().await).await;
 }
 
+/// Shows the current settings of the equalizer and the saved presets
+fn show_equalizer(settings_data: &SettingsData, settings: &crate::settings::Settings) {
+    let preset = &settings.eq_preset;
+    settings_data.set_equalizer(settings.equalizer);
+    settings_data.set_eq_preamp(preset.preamp);
+    // New models recreate the sliders, whose values aren't bound anymore once they are dragged
+    settings_data.set_eq_graphic(VecModel::from_slice(&preset.graphic));
+    let bands: Vec<EqBandData> = preset.parametric.iter().copied().map(Into::into).collect();
+    settings_data.set_eq_bands(VecModel::from_slice(&bands));
+    let names: Vec<SharedString> = settings
+        .eq_presets
+        .iter()
+        .map(|preset| SharedString::from(preset.name.as_str()))
+        .collect();
+    settings_data.set_eq_presets(VecModel::from_slice(&names));
+    let index = settings
+        .eq_presets
+        .iter()
+        .position(|saved| !preset.name.is_empty() && saved.name == preset.name);
+    settings_data.set_eq_preset(index.map(|index| index as i32).unwrap_or(-1));
+}
+
+/// Saves the current settings of the equalizer as a preset, replacing the one with the same name
+fn save_preset(settings: &mut crate::settings::Settings) {
+    let preset = settings.eq_preset.clone();
+    match settings
+        .eq_presets
+        .iter_mut()
+        .find(|saved| saved.name == preset.name)
+    {
+        Some(saved) => *saved = preset,
+        None => settings.eq_presets.push(preset),
+    }
+}
+
 async fn setup_data<P: crate::platform::Platform + Send + 'static>(
     settings: Settings,
     platform: Platform<P>,
@@ -127,6 +223,7 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
     tx_searching: Sender<String>,
     tx_changing: Sender<()>,
     tx_path: Sender<(String, bool)>,
+    tx_loudness: Sender<()>,
 ) {
     localize(
         settings.read().await.locale.clone(),
@@ -148,6 +245,34 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
         settings_data.set_height(settings.window_size.height as f32);
         settings_data.set_save_window_size(settings.save_window_size);
         settings_data.set_current_path(settings.path.clone().into());
+        settings_data.set_crossfade(settings.crossfade as i32);
+        settings_data.set_crossfade_curve(i32::from(settings.crossfade_curve));
+        settings_data.set_replay_gain(i32::from(settings.replay_gain));
+        settings_data.set_resampler_quality(i32::from(settings.resampler_quality));
+        settings_data.set_preserve_pitch(settings.preserve_pitch);
+        show_equalizer(&settings_data, &settings);
+        settings_data.set_visualizer(i32::from(settings.visualizer));
+    }
+
+    // The first entry is the default device, used when the saved one isn't available anymore
+    let devices = tokio::task::spawn_blocking(n_audio::device::output_devices)
+        .await
+        .unwrap_or_default()
+        .into_iter()
+        .fold(vec![], |mut devices: Vec<String>, device| {
+            // Devices with the same name in different hosts can't be told apart
+            if !devices.contains(&device.name) {
+                devices.push(device.name);
+            }
+            devices
+        });
+    let mut device_names = vec![main_window.global::<Localization>().get_default_device()];
+    device_names.extend(devices.iter().map(SharedString::from));
+    settings_data.set_output_devices(VecModel::from_slice(&device_names));
+    if let Some(device) = &settings.read().await.output_device {
+        if let Some(index) = devices.iter().position(|d| d == device) {
+            settings_data.set_output_device(index as i32 + 1);
+        }
     }
 
     let p = platform.clone();
@@ -167,6 +292,10 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
                 Some(denominator.to_string()),
                 window.global::<Localization>(),
             );
+            window
+                .global::<SettingsData>()
+                .get_output_devices()
+                .set_row_data(0, window.global::<Localization>().get_default_device());
             let s = s.clone();
             let p = p.clone();
             slint::spawn_local(async move {
@@ -200,6 +329,100 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
         })
         .unwrap();
     });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_crossfade(move |seconds, curve| {
+        if let Ok(curve) = CrossfadeCurve::try_from(curve) {
+            let s = s.clone();
+            let p = p.clone();
+            let t = t.clone();
+            slint::spawn_local(async move {
+                let mut settings = s.write().await;
+                settings.crossfade = seconds.max(0) as f64;
+                settings.crossfade_curve = curve;
+                t.send_async(RunnerMessage::SetCrossfade(settings.crossfade()))
+                    .await
+                    .unwrap();
+                settings.save(p.read().await).await;
+            })
+            .unwrap();
+        }
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_replay_gain(move |mode| {
+        if let Ok(mode) = ReplayGainMode::try_from(mode) {
+            let s = s.clone();
+            let p = p.clone();
+            let t = t.clone();
+            slint::spawn_local(async move {
+                t.send_async(RunnerMessage::SetReplayGainMode(mode.into()))
+                    .await
+                    .unwrap();
+                s.write().await.replay_gain = mode;
+                s.read().await.save(p.read().await).await;
+            })
+            .unwrap();
+        }
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_output_device(move |index| {
+        let device = usize::try_from(index - 1)
+            .ok()
+            .and_then(|index| devices.get(index).cloned());
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            t.send_async(RunnerMessage::SetDevice(device.clone()))
+                .await
+                .unwrap();
+            s.write().await.output_device = device;
+            s.read().await.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_resampler_quality(move |quality| {
+        if let Ok(quality) = ResamplerQuality::try_from(quality) {
+            let s = s.clone();
+            let p = p.clone();
+            let t = t.clone();
+            slint::spawn_local(async move {
+                t.send_async(RunnerMessage::SetResamplerQuality(quality.into()))
+                    .await
+                    .unwrap();
+                s.write().await.resampler_quality = quality;
+                s.read().await.save(p.read().await).await;
+            })
+            .unwrap();
+        }
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_toggle_preserve_pitch(move |preserve_pitch| {
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            settings.preserve_pitch = preserve_pitch;
+            t.send_async(RunnerMessage::SetTimeStretchMode(
+                settings.time_stretch_mode(),
+            ))
+            .await
+            .unwrap();
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
     let path = tx_path.clone();
     settings_data.on_path(move || {
         let tx_path = path.clone();
@@ -220,6 +443,299 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
         })
         .unwrap();
     });
+    settings_data.on_scan_loudness(move || tx_loudness.send(()).unwrap());
+    let t = tx.clone();
+    settings_data.on_open_stream(move |url| {
+        let url = url.trim();
+        if !url.is_empty() {
+            t.send(RunnerMessage::OpenUri(url.to_string())).unwrap();
+        }
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_toggle_equalizer(move |enabled| {
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            settings.equalizer = enabled;
+            t.send_async(RunnerMessage::SetEqualizer(settings.equalizer()))
+                .await
+                .unwrap();
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_eq_preamp(move |preamp| {
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            t.send_async(RunnerMessage::SetEqParameter(
+                String::from("preamp"),
+                preamp,
+            ))
+            .await
+            .unwrap();
+            s.write().await.eq_preset.preamp = preamp;
+            s.read().await.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_eq_graphic(move |index, gain| {
+        let Ok(index) = usize::try_from(index) else {
+            return;
+        };
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            let Some(band) = settings.eq_preset.graphic.get_mut(index) else {
+                return;
+            };
+            *band = gain;
+            t.send_async(RunnerMessage::SetEqParameter(
+                format!("graphic.{index}"),
+                gain,
+            ))
+            .await
+            .unwrap();
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_change_eq_band(move |index, data| {
+        let (Ok(index), Ok(filter)) = (usize::try_from(index), EqFilter::try_from(data.filter))
+        else {
+            return;
+        };
+        let band = EqBand {
+            filter,
+            frequency: data.frequency,
+            gain: data.gain,
+            q: data.q,
+        };
+        let bands = window.global::<SettingsData>().get_eq_bands();
+        if index >= bands.row_count() {
+            return;
+        }
+        bands.set_row_data(index, band.into());
+        let s = s.clone();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            let Some(old) = settings.eq_preset.parametric.get_mut(index) else {
+                return;
+            };
+            *old = band;
+            let parameters = [
+                ("type", i32::from(band.filter) as f32),
+                ("frequency", band.frequency),
+                ("gain", band.gain),
+                ("q", band.q),
+            ];
+            for (name, value) in parameters {
+                t.send_async(RunnerMessage::SetEqParameter(
+                    format!("band.{index}.{name}"),
+                    value,
+                ))
+                .await
+                .unwrap();
+            }
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_add_eq_band(move || {
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            if settings.eq_preset.parametric.len() >= MAX_PARAMETRIC_BANDS {
+                return;
+            }
+            settings.eq_preset.parametric.push(EqBand::default());
+            // The new band starts with the default settings, so its count is enough
+            t.send_async(RunnerMessage::SetEqParameter(
+                String::from("bands"),
+                settings.eq_preset.parametric.len() as f32,
+            ))
+            .await
+            .unwrap();
+            show_equalizer(&window.global::<SettingsData>(), &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_remove_eq_band(move |index| {
+        let Ok(index) = usize::try_from(index) else {
+            return;
+        };
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            if index >= settings.eq_preset.parametric.len() {
+                return;
+            }
+            settings.eq_preset.parametric.remove(index);
+            // The bands after it move back by one, so they are all sent again
+            t.send_async(RunnerMessage::SetEqualizer(settings.equalizer()))
+                .await
+                .unwrap();
+            show_equalizer(&window.global::<SettingsData>(), &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_load_eq_preset(move |index| {
+        let Ok(index) = usize::try_from(index) else {
+            return;
+        };
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            let Some(preset) = settings.eq_presets.get(index).cloned() else {
+                return;
+            };
+            settings.eq_preset = preset;
+            t.send_async(RunnerMessage::SetEqualizer(settings.equalizer()))
+                .await
+                .unwrap();
+            show_equalizer(&window.global::<SettingsData>(), &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    settings_data.on_save_eq_preset(move |name| {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return;
+        }
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            settings.eq_preset.name = name;
+            save_preset(&mut settings);
+            show_equalizer(&window.global::<SettingsData>(), &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    settings_data.on_delete_eq_preset(move |index| {
+        let Ok(index) = usize::try_from(index) else {
+            return;
+        };
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        slint::spawn_local(async move {
+            let mut settings = s.write().await;
+            if index >= settings.eq_presets.len() {
+                return;
+            }
+            settings.eq_presets.remove(index);
+            // What's playing stays the same, it just isn't saved under that name anymore
+            settings.eq_preset.name.clear();
+            show_equalizer(&window.global::<SettingsData>(), &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let window = main_window.clone_strong();
+    let p = platform.clone();
+    let t = tx.clone();
+    settings_data.on_import_eq(move |path| {
+        let path = PathBuf::from(path.trim());
+        let s = s.clone();
+        let window = window.clone_strong();
+        let p = p.clone();
+        let t = t.clone();
+        slint::spawn_local(async move {
+            let imported = match tokio::fs::read_to_string(&path).await {
+                Ok(text) => EqSettings::from_equalizer_apo(&text),
+                Err(e) => Err(e),
+            };
+            let settings_data = window.global::<SettingsData>();
+            let imported = match imported {
+                Ok(imported) => imported,
+                Err(e) => {
+                    settings_data.set_eq_error(format!("{}: {e}", path.display()).into());
+                    return;
+                }
+            };
+            settings_data.set_eq_error(SharedString::new());
+
+            let mut settings = s.write().await;
+            let name = path
+                .file_stem()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            settings.eq_preset = EqPreset::new(name, imported);
+            save_preset(&mut settings);
+            t.send_async(RunnerMessage::SetEqualizer(settings.equalizer()))
+                .await
+                .unwrap();
+            show_equalizer(&settings_data, &settings);
+            settings.save(p.read().await).await;
+        })
+        .unwrap();
+    });
+    let s = settings.clone();
+    let p = platform.clone();
+    settings_data.on_change_visualizer(move |visualizer| {
+        if let Ok(visualizer) = Visualizer::try_from(visualizer) {
+            let s = s.clone();
+            let p = p.clone();
+            slint::spawn_local(async move {
+                s.write().await.visualizer = visualizer;
+                s.read().await.save(p.read().await).await;
+            })
+            .unwrap();
+        }
+    });
     let t = tx.clone();
     app_data.on_clicked(move |i| t.send(RunnerMessage::PlayTrack(i as usize)).unwrap());
     let t = tx.clone();
@@ -239,6 +755,66 @@ async fn setup_data<P: crate::platform::Platform + Send + 'static>(
     app_data.on_changing(move || tx_changing.send(()).unwrap());
 }
 
+/// Draws what is being heard, either as a spectrum or as a waveform
+async fn visualizer_task(tap: VisualizationTap, s: Settings, window: Weak<MainWindow>) {
+    let mut interval = tokio::time::interval(VISUALIZER_INTERVAL);
+    let mut spectrum = Spectrum::new(SPECTRUM_SIZE);
+    let mut samples = vec![];
+    let mut levels = vec![0.0; SPECTRUM_BANDS];
+    loop {
+        interval.tick().await;
+        let visualizer = s.read().await.visualizer;
+        if visualizer == Visualizer::Off {
+            continue;
+        }
+        let Some(spec) = tap.read(SPECTRUM_SIZE.max(WAVEFORM_FRAMES), &mut samples) else {
+            continue;
+        };
+        let channels = spec.channels.count();
+
+        if visualizer == Visualizer::Spectrum {
+            let magnitudes = spectrum.process(&samples, channels);
+            let bands = Spectrum::bands(magnitudes, spec.rate, SPECTRUM_BANDS);
+            for (level, band) in levels.iter_mut().zip(bands) {
+                // The bars rise right away but fall slowly, so that they are easier to follow
+                let band = ((band - SPECTRUM_FLOOR) / -SPECTRUM_FLOOR).clamp(0.0, 1.0);
+                *level = band.max(*level - SPECTRUM_FALL);
+            }
+            let levels = levels.clone();
+            let _ = window.upgrade_in_event_loop(move |window| {
+                let spectrum = window.global::<AppData>().get_spectrum();
+                if spectrum.row_count() == levels.len() {
+                    for (i, level) in levels.into_iter().enumerate() {
+                        spectrum.set_row_data(i, level);
+                    }
+                } else {
+                    window
+                        .global::<AppData>()
+                        .set_spectrum(VecModel::from_slice(&levels));
+                }
+            });
+        } else {
+            // Every point is the first frame of its group mixed to mono, in a 256x100 box
+            let frames = &samples[samples.len().saturating_sub(WAVEFORM_FRAMES * channels)..];
+            let step = (frames.len() / channels / WAVEFORM_POINTS).max(1);
+            let waveform = frames
+                .chunks(channels * step)
+                .take(WAVEFORM_POINTS)
+                .enumerate()
+                .map(|(i, group)| {
+                    let sample = group[..channels].iter().sum::<f32>() / channels as f32;
+                    let y = (50.0 - sample * 50.0).clamp(0.0, 100.0);
+                    format!("{} {i} {y:.1}", if i == 0 { "M" } else { "L" })
+                })
+                .collect::<Vec<String>>()
+                .join(" ");
+            let _ = window.upgrade_in_event_loop(move |window| {
+                window.global::<AppData>().set_waveform(waveform.into());
+            });
+        }
+    }
+}
+
 async fn updater_task<P: crate::platform::Platform + Send + 'static>(
     r: Runner,
     s: Settings,
@@ -248,19 +824,28 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
     rx_changing: Receiver<()>,
     rx_searching: Receiver<String>,
     rx_l: Receiver<Option<(usize, FileTrack)>>,
+    rx_scanned: Receiver<f64>,
 ) {
     let mut interval = tokio::time::interval(Duration::from_millis(250));
+    let events = r.read().await.subscribe();
     let mut searching = String::new();
     let mut old_index = usize::MAX;
     let mut loaded = 0;
     let mut saved = false;
     let mut changes = vec![];
     let mut tracks = vec![];
+    let mut rows = 0;
+    let mut measured_length = None;
     if let Ok(tracks) = rx_tracks.recv_async().await {
+        rows = tracks.len();
         changes.push(Changes::Tracks(tracks));
     }
     loop {
-        interval.tick().await;
+        // Player events are shown right away, the interval catches everything else
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = events.recv_async() => {}
+        }
         let guard = r.read().await;
         let mut index = guard.index();
         let len = guard.len();
@@ -269,10 +854,12 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
         }
         let playback = guard.playback();
         let time = guard.time();
-        let length = time.length;
+        let length = time.length.unwrap_or_default();
+        let seekable = time.is_seekable();
         let time_float = time.position;
         let volume = guard.volume();
         let position = time.format_pos();
+        let stream_title = guard.stream_title().unwrap_or_default();
 
         let change_time = if let Ok(()) = rx_changing.try_recv() {
             false
@@ -283,6 +870,7 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
         let mut new_loaded = false;
 
         if let Ok(tracks) = rx_tracks.try_recv() {
+            rows = tracks.len();
             changes.push(Changes::Tracks(tracks));
             new_loaded = true;
             loaded = 0;
@@ -311,7 +899,50 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
                 new_loaded = true;
             }
         }
-        let progress = loaded as f64 / len as f64;
+        // The cached length of a track is replaced if it was only an estimate
+        if measured_length != guard.measured_length() {
+            measured_length = guard.measured_length();
+            // The queue may have changed since the track was played
+            let measured = measured_length.as_ref().and_then(|(track, length)| {
+                let index = guard.queue().iter().position(|entry| entry == track)?;
+                Some((index, *length))
+            });
+            if let Some((index, length)) = measured {
+                if let Some(path) = guard.get_path_for_file(index).await {
+                    if let Some(file_track) = s
+                        .read()
+                        .await
+                        .update_track_length(p.read().await, remove_ext(path), length)
+                        .await
+                    {
+                        let mut track: TrackData = file_track.into();
+                        track.index = index as i32;
+                        changes.push(Changes::Metadata(index, track));
+                    }
+                }
+            }
+        }
+
+        // Local files get their rows from the loader, streams opened later need one too
+        while rows < len {
+            if let Some(track) = guard.queue().get(rows).filter(|track| is_url(track)) {
+                changes.push(Changes::Added(TrackData {
+                    artist: Default::default(),
+                    cover: Default::default(),
+                    time: TrackTime::default().format_len().into(),
+                    title: track.as_ref().into(),
+                    index: rows as i32,
+                    visible: true,
+                }));
+            }
+            rows += 1;
+        }
+
+        let mut progress = loaded as f64 / len as f64;
+        while let Ok(scanned) = rx_scanned.try_recv() {
+            progress = scanned;
+            new_loaded = true;
+        }
         if old_index != index || new_loaded {
             old_index = index;
         }
@@ -339,8 +970,10 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
                     app_data.set_time(time_float as f32);
                 }
                 app_data.set_length(length as f32);
+                app_data.set_seekable(seekable);
                 app_data.set_playback(playback);
                 app_data.set_volume(volume as f32);
+                app_data.set_stream_title(stream_title.into());
 
                 if new_loaded {
                     let progress = if progress == 1.0 {
@@ -359,6 +992,15 @@ async fn updater_task<P: crate::platform::Platform + Send + 'static>(
                         Changes::Metadata(index, track) => {
                             app_data.get_tracks().set_row_data(index, track);
                         }
+                        Changes::Added(track) => {
+                            if let Some(tracks) = app_data
+                                .get_tracks()
+                                .as_any()
+                                .downcast_ref::<VecModel<TrackData>>()
+                            {
+                                tracks.push(track);
+                            }
+                        }
                     }
                 }
 
@@ -426,8 +1068,18 @@ async fn loader_task(
             }
             if let Some(path) = runner.read().await.get_path_for_file(index).await {
                 if let Ok(track) = MusicTrack::new(path.to_string_lossy().to_string()) {
-                    if let Ok(Ok(meta)) =
-                        tokio::task::spawn_blocking(move || track.get_meta()).await
+                    // Tracks whose header doesn't tell their length are read a second time to estimate it, streams
+                    // aren't opened at all
+                    if let Ok(Ok((meta, length_estimated))) =
+                        tokio::task::spawn_blocking(move || {
+                            let mut meta = track.get_meta()?;
+                            let length_estimated = meta.time.length.is_none() && !track.is_stream();
+                            if length_estimated {
+                                meta.time.length = track.get_length()?.length;
+                            }
+                            Ok::<_, NError>((meta, length_estimated))
+                        })
+                        .await
                     {
                         let p = path.clone();
                         let image = get_image_squared(p, 128, 128).await;
@@ -440,6 +1092,7 @@ async fn loader_task(
                                     title: meta.title,
                                     artist: meta.artist,
                                     length: meta.time.length,
+                                    length_estimated,
                                     image: image
                                         .map(|i| i.flatten_to_u8()[0].clone())
                                         .unwrap_or(vec![]),
@@ -548,3 +1201,119 @@ async fn loader<P: crate::platform::Platform + Send + 'static>(
         }
     }
 }
+
+async fn loudness_task(
+    tx: Sender<usize>,
+    rx_albums: Arc<Mutex<Receiver<Option<(Vec<String>, bool)>>>>,
+) {
+    loop {
+        if let Ok(Some((paths, is_album))) = rx_albums.lock().await.recv_async().await {
+            let len = paths.len();
+            let result = tokio::task::spawn_blocking(move || {
+                let (tracks, album) = loudness::analyze_album(&paths)?;
+                for (path, track) in paths.iter().zip(tracks.iter()) {
+                    if let Err(e) = loudness::write_tags(path, track, is_album.then_some(&album)) {
+                        eprintln!("can't write loudness tags to {path}: {e}");
+                    }
+                }
+                Ok::<(), NError>(())
+            })
+            .await;
+            if let Ok(Err(e)) = result {
+                eprintln!("error happened during loudness analysis: {e}");
+            }
+
+            if let Err(e) = tx.send_async(len).await {
+                eprintln!("error happened when signaling scanned tracks, probably because the app was closed: {e}");
+            }
+        } else {
+            return;
+        }
+    }
+}
+
+async fn loudness_scanner(runner: Runner, rx: Receiver<()>, tx_scanned: Sender<f64>) {
+    loop {
+        if rx.recv_async().await.is_err() {
+            return;
+        }
+        let mut paths = vec![];
+        {
+            let guard = runner.read().await;
+            for i in 0..guard.len() {
+                if let Some(path) = guard.get_path_for_file(i).await {
+                    let path = path.to_string_lossy().to_string();
+                    // Streams have no tags to write and may never end
+                    if !is_url(&path) {
+                        paths.push(path);
+                    }
+                }
+            }
+        }
+        let len = paths.len();
+        if len == 0 {
+            continue;
+        }
+
+        let mut albums: HashMap<(PathBuf, String), Vec<String>> = HashMap::new();
+        // Tracks without an album get only the track gain
+        let mut singles = vec![];
+        for path in paths {
+            let p = path.clone();
+            let album = tokio::task::spawn_blocking(move || {
+                MusicTrack::new(p).and_then(|track| track.get_meta())
+            })
+            .await
+            .ok()
+            .and_then(|meta| meta.ok())
+            .map(|meta| meta.album)
+            .unwrap_or_default();
+
+            if album.is_empty() {
+                singles.push((vec![path], false));
+            } else {
+                let dir = Path::new(&path)
+                    .parent()
+                    .map(Path::to_path_buf)
+                    .unwrap_or_default();
+                albums.entry((dir, album)).or_default().push(path);
+            }
+        }
+
+        let mut tasks = vec![];
+        let (tx_albums, rx_albums) = flume::unbounded();
+        let rx_albums = Arc::new(Mutex::new(rx_albums));
+        let (tx_l, rx_l) = flume::unbounded();
+        let cpus = num_cpus::get();
+        for _ in 0..cpus {
+            tasks.push(tokio::task::spawn(loudness_task(
+                tx_l.clone(),
+                rx_albums.clone(),
+            )));
+        }
+        for album in albums
+            .into_values()
+            .map(|album| (album, true))
+            .chain(singles)
+        {
+            tx_albums.send_async(Some(album)).await.unwrap();
+        }
+        for _ in 0..cpus {
+            tx_albums.send_async(None).await.unwrap();
+        }
+        drop(tx_l);
+
+        let mut scanned = 0;
+        while let Ok(tracks) = rx_l.recv_async().await {
+            scanned += tracks;
+            if let Err(e) = tx_scanned.send_async(scanned as f64 / len as f64).await {
+                eprintln!("error happened during progress transfer, probably because the app was closed: {e}");
+            }
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        // Ignore the requests made while the scan was running
+        while rx.try_recv().is_ok() {}
+    }
+}
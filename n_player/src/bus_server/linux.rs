@@ -9,8 +9,8 @@ use mpris_server::{
 };
 use n_audio::music_track::MusicTrack;
 use n_audio::remove_ext;
+use n_audio::time_stretch::{MAX_PLAYBACK_SPEED, MIN_PLAYBACK_SPEED};
 use std::io::{Seek, Write};
-use std::path::PathBuf;
 use std::sync::Arc;
 use tempfile::NamedTempFile;
 use tokio::sync::RwLock;
@@ -68,7 +68,11 @@ impl RootInterface for MPRISBridge {
     }
 
     async fn supported_uri_schemes(&self) -> fdo::Result<Vec<String>> {
-        Ok(vec![])
+        Ok(vec![
+            String::from("file"),
+            String::from("http"),
+            String::from("https"),
+        ])
     }
 
     async fn supported_mime_types(&self) -> fdo::Result<Vec<String>> {
@@ -132,7 +136,11 @@ impl PlayerInterface for MPRISBridge {
         Ok(())
     }
 
-    async fn open_uri(&self, _uri: String) -> fdo::Result<()> {
+    async fn open_uri(&self, uri: String) -> fdo::Result<()> {
+        self.tx
+            .send_async(RunnerMessage::OpenUri(uri))
+            .await
+            .unwrap();
         Ok(())
     }
 
@@ -170,10 +178,18 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        let rate = self.runner.read().await.playback_speed();
+        Ok(rate)
     }
 
-    async fn set_rate(&self, _rate: PlaybackRate) -> zbus::Result<()> {
+    async fn set_rate(&self, rate: PlaybackRate) -> zbus::Result<()> {
+        // The specification says that a rate of 0.0 should pause the playback
+        let message = if rate == 0.0 {
+            RunnerMessage::Pause
+        } else {
+            RunnerMessage::SetPlaybackSpeed(rate)
+        };
+        self.tx.send_async(message).await.unwrap();
         Ok(())
     }
 
@@ -186,15 +202,18 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn metadata(&self) -> fdo::Result<Metadata> {
-        let path = self.runner.read().await.path();
-        let track_name = self.runner.read().await.current_track().await;
+        let runner = self.runner.read().await;
+        let track_name = runner.current_track().await;
         if let None = track_name {
             return Ok(Metadata::new());
         }
         let track_name = track_name.unwrap();
-        let mut path_buf = PathBuf::new();
-        path_buf.push(&path);
-        path_buf.push(track_name.as_ref());
+        let path_buf = match runner.get_path_for_file(runner.index()).await {
+            Some(path_buf) => path_buf,
+            None => return Ok(Metadata::new()),
+        };
+        let stream_title = runner.stream_title();
+        drop(runner);
         let track = MusicTrack::new(path_buf.to_str().unwrap())
             .expect("can't get track for currently playing song");
         let meta = track.get_meta();
@@ -211,7 +230,9 @@ impl PlayerInterface for MPRISBridge {
 
         let mut metadata = Metadata::new();
         if let Ok(meta) = meta {
-            metadata.set_title(Some(if !meta.title.is_empty() {
+            metadata.set_title(Some(if let Some(title) = stream_title {
+                title
+            } else if !meta.title.is_empty() {
                 meta.title
             } else {
                 remove_ext(track_name.as_ref())
@@ -221,9 +242,11 @@ impl PlayerInterface for MPRISBridge {
             } else {
                 Some(vec![meta.artist])
             });
-            metadata.set_length(Some(Time::from_millis(
-                (meta.time.length * 1000.0).floor() as i64
-            )));
+            metadata.set_length(
+                meta.time
+                    .length
+                    .map(|length| Time::from_millis((length * 1000.0).floor() as i64)),
+            );
             metadata.set_trackid(Some(ObjectPath::from_static_str_unchecked("/n_music")));
             metadata.set_art_url(image_path);
         }
@@ -252,11 +275,11 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn minimum_rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        Ok(MIN_PLAYBACK_SPEED as f64)
     }
 
     async fn maximum_rate(&self) -> fdo::Result<PlaybackRate> {
-        Ok(1.0)
+        Ok(MAX_PLAYBACK_SPEED as f64)
     }
 
     async fn can_go_next(&self) -> fdo::Result<bool> {
@@ -276,7 +299,7 @@ impl PlayerInterface for MPRISBridge {
     }
 
     async fn can_seek(&self) -> fdo::Result<bool> {
-        Ok(true)
+        Ok(self.runner.read().await.time().is_seekable())
     }
 
     async fn can_control(&self) -> fdo::Result<bool> {
@@ -5,7 +5,6 @@ use n_audio::music_track::MusicTrack;
 use n_audio::queue::LoopStatus;
 use n_audio::{remove_ext, TrackTime};
 use std::mem;
-use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 use tempfile::NamedTempFile;
@@ -19,14 +18,17 @@ pub enum Property {
     Playing(bool),
     Metadata(Metadata),
     Volume(f64),
+    Rate(f64),
     PositionChanged(f64),
+    CanSeek(bool),
     LoopStatus(LoopStatus),
 }
 
 pub struct Metadata {
     pub title: Option<String>,
     pub artists: Option<Vec<String>>,
-    pub length: f64,
+    /// `None` for live streams and other tracks of unknown length
+    pub length: Option<f64>,
     pub id: String,
     pub image_path: Option<String>,
 }
@@ -37,16 +39,23 @@ pub async fn run<P: Platform + Send + Sync>(
     tmp: NamedTempFile,
 ) {
     let mut interval = tokio::time::interval(Duration::from_millis(250));
+    let events = runner.read().await.subscribe();
     let mut properties = vec![];
     let mut playback = false;
     let mut volume = 1.0;
+    let mut rate = 1.0;
     let mut loop_status = LoopStatus::default();
     let mut index = runner.read().await.index();
     let mut time = TrackTime::default();
-    let path = runner.read().await.path();
+    let mut seekable = true;
+    let mut stream_title = None;
 
     loop {
-        interval.tick().await;
+        // React to the player right away, the interval is still needed for volume and loop status changes
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = events.recv_async() => {}
+        }
         let guard = runner.read().await;
 
         if playback != guard.playback() {
@@ -57,6 +66,10 @@ pub async fn run<P: Platform + Send + Sync>(
             volume = guard.volume();
             properties.push(Property::Volume(volume))
         }
+        if rate != guard.playback_speed() {
+            rate = guard.playback_speed();
+            properties.push(Property::Rate(rate))
+        }
         if loop_status != guard.loop_status() {
             loop_status = guard.loop_status();
             properties.push(Property::LoopStatus(loop_status.clone()));
@@ -67,17 +80,24 @@ pub async fn run<P: Platform + Send + Sync>(
             time = guard_time;
             properties.push(Property::PositionChanged(time.position));
         }
+        if seekable != guard_time.is_seekable() {
+            seekable = guard_time.is_seekable();
+            properties.push(Property::CanSeek(seekable));
+        }
 
-        if index != guard.index() {
+        // Streams change their title while they play
+        if index != guard.index() || stream_title != guard.stream_title() {
             index = guard.index();
+            stream_title = guard.stream_title();
             let track_name = match guard.current_track().await {
                 Some(track) => track,
                 None => continue,
             };
+            let path_buf = match guard.get_path_for_file(index).await {
+                Some(path_buf) => path_buf,
+                None => continue,
+            };
 
-            let mut path_buf = PathBuf::new();
-            path_buf.push(&path);
-            path_buf.push(track_name.as_ref());
             let track = MusicTrack::new(path_buf.to_str().unwrap())
                 .expect("can't get track for currently playing song");
             let meta = track.get_meta();
@@ -89,7 +109,9 @@ pub async fn run<P: Platform + Send + Sync>(
             if let Ok(meta) = meta {
                 properties.push(Property::Metadata(Metadata {
                     id: String::from("/n_music"),
-                    title: Some(if !meta.title.is_empty() {
+                    title: Some(if let Some(title) = stream_title.clone() {
+                        title
+                    } else if !meta.title.is_empty() {
                         meta.title
                     } else {
                         remove_ext(track_name.as_ref())
@@ -4,6 +4,9 @@ use bitcode::{Decode, Encode};
 use flume::{Receiver, RecvError, SendError, Sender, TryRecvError};
 use multitag::data::Picture;
 use multitag::Tag;
+use n_audio::equalizer::{Band, EqSettings, FilterType};
+use n_audio::music_track::MusicTrack;
+use n_audio::TrackTime;
 #[cfg(target_os = "android")]
 use once_cell::sync::Lazy;
 use rimage::codecs::webp::WebPDecoder;
@@ -185,16 +188,16 @@ pub fn get_image<P: AsRef<Path> + Debug>(path: P) -> Vec<u8> {
                     if let Some(cover) = cover {
                         return cover.data;
                     }
-                } else {
-                    eprintln!("not an opus or mp3 tag {path:?}");
                 }
             }
-        } else {
-            eprintln!("no album for {path:?}");
         }
     }
 
-    vec![]
+    // Formats that multitag doesn't know about (e.g. DCA) may still have a cover that Symphonia can read
+    MusicTrack::new(path.as_ref().to_string_lossy().to_string())
+        .ok()
+        .and_then(|track| track.get_cover())
+        .unwrap_or_default()
 }
 
 pub async fn add_all_tracks_to_player<P: AsRef<Path> + AsRef<OsStr> + From<String>>(
@@ -205,18 +208,21 @@ pub async fn add_all_tracks_to_player<P: AsRef<Path> + AsRef<OsStr> + From<Strin
         let mut paths = vec![];
         while let Ok(Some(file)) = dir.next_entry().await {
             if file.file_type().await.unwrap().is_file() {
-                if let Ok(Some(mime)) = infer::get_from_path(&file.path()) {
-                    if mime.mime_type().contains("audio") {
-                        let mut p = file.path().to_str().unwrap().to_string();
-                        p.shrink_to_fit();
-                        paths.push(p);
-                    }
+                let is_audio = match infer::get_from_path(file.path()) {
+                    Ok(Some(mime)) => mime.mime_type().contains("audio"),
+                    // DCA files aren't recognized by infer
+                    _ => file.path().extension().is_some_and(|ext| ext == "dca"),
+                };
+                if is_audio {
+                    let mut p = file.path().to_str().unwrap().to_string();
+                    p.shrink_to_fit();
+                    paths.push(p);
                 }
             }
         }
         runner.add_all(paths).await;
         runner.shrink_to_fit();
-        runner.shuffle();
+        runner.shuffle().await;
     }
 }
 
@@ -304,12 +310,323 @@ impl TryFrom<i32> for Theme {
     }
 }
 
+#[derive(Copy, Clone, Debug, Default, Decode, Encode)]
+pub enum CrossfadeCurve {
+    #[default]
+    Linear,
+    EqualPower,
+}
+
+impl From<CrossfadeCurve> for n_audio::CrossfadeCurve {
+    fn from(value: CrossfadeCurve) -> Self {
+        match value {
+            CrossfadeCurve::Linear => n_audio::CrossfadeCurve::Linear,
+            CrossfadeCurve::EqualPower => n_audio::CrossfadeCurve::EqualPower,
+        }
+    }
+}
+
+impl From<CrossfadeCurve> for i32 {
+    fn from(value: CrossfadeCurve) -> Self {
+        match value {
+            CrossfadeCurve::Linear => 0,
+            CrossfadeCurve::EqualPower => 1,
+        }
+    }
+}
+
+impl TryFrom<i32> for CrossfadeCurve {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        if value == 0 {
+            Ok(Self::Linear)
+        } else if value == 1 {
+            Ok(Self::EqualPower)
+        } else {
+            Err(format!("{value} is not a valid crossfade curve"))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Decode, Encode)]
+pub enum ReplayGainMode {
+    #[default]
+    Off,
+    Track,
+    Album,
+    Auto,
+}
+
+impl From<ReplayGainMode> for n_audio::replay_gain::ReplayGainMode {
+    fn from(value: ReplayGainMode) -> Self {
+        match value {
+            ReplayGainMode::Off => n_audio::replay_gain::ReplayGainMode::Off,
+            ReplayGainMode::Track => n_audio::replay_gain::ReplayGainMode::Track,
+            ReplayGainMode::Album => n_audio::replay_gain::ReplayGainMode::Album,
+            ReplayGainMode::Auto => n_audio::replay_gain::ReplayGainMode::Auto,
+        }
+    }
+}
+
+impl From<ReplayGainMode> for i32 {
+    fn from(value: ReplayGainMode) -> Self {
+        match value {
+            ReplayGainMode::Off => 0,
+            ReplayGainMode::Track => 1,
+            ReplayGainMode::Album => 2,
+            ReplayGainMode::Auto => 3,
+        }
+    }
+}
+
+impl TryFrom<i32> for ReplayGainMode {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        if value == 0 {
+            Ok(Self::Off)
+        } else if value == 1 {
+            Ok(Self::Track)
+        } else if value == 2 {
+            Ok(Self::Album)
+        } else if value == 3 {
+            Ok(Self::Auto)
+        } else {
+            Err(format!("{value} is not a valid replay gain mode"))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Decode, Encode)]
+pub enum ResamplerQuality {
+    Linear,
+    #[default]
+    Medium,
+    High,
+}
+
+impl From<ResamplerQuality> for n_audio::resampler::ResamplerQuality {
+    fn from(value: ResamplerQuality) -> Self {
+        match value {
+            ResamplerQuality::Linear => n_audio::resampler::ResamplerQuality::Linear,
+            ResamplerQuality::Medium => n_audio::resampler::ResamplerQuality::Medium,
+            ResamplerQuality::High => n_audio::resampler::ResamplerQuality::High,
+        }
+    }
+}
+
+impl From<ResamplerQuality> for i32 {
+    fn from(value: ResamplerQuality) -> Self {
+        match value {
+            ResamplerQuality::Linear => 0,
+            ResamplerQuality::Medium => 1,
+            ResamplerQuality::High => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for ResamplerQuality {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        if value == 0 {
+            Ok(Self::Linear)
+        } else if value == 1 {
+            Ok(Self::Medium)
+        } else if value == 2 {
+            Ok(Self::High)
+        } else {
+            Err(format!("{value} is not a valid resampler quality"))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Decode, Encode, PartialEq)]
+pub enum Visualizer {
+    Off,
+    #[default]
+    Spectrum,
+    Oscilloscope,
+}
+
+impl From<Visualizer> for i32 {
+    fn from(value: Visualizer) -> Self {
+        match value {
+            Visualizer::Off => 0,
+            Visualizer::Spectrum => 1,
+            Visualizer::Oscilloscope => 2,
+        }
+    }
+}
+
+impl TryFrom<i32> for Visualizer {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        if value == 0 {
+            Ok(Self::Off)
+        } else if value == 1 {
+            Ok(Self::Spectrum)
+        } else if value == 2 {
+            Ok(Self::Oscilloscope)
+        } else {
+            Err(format!("{value} is not a valid visualizer"))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default, Decode, Encode)]
+pub enum EqFilter {
+    #[default]
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl From<EqFilter> for FilterType {
+    fn from(value: EqFilter) -> Self {
+        match value {
+            EqFilter::Peaking => FilterType::Peaking,
+            EqFilter::LowShelf => FilterType::LowShelf,
+            EqFilter::HighShelf => FilterType::HighShelf,
+            EqFilter::LowPass => FilterType::LowPass,
+            EqFilter::HighPass => FilterType::HighPass,
+        }
+    }
+}
+
+impl From<FilterType> for EqFilter {
+    fn from(value: FilterType) -> Self {
+        match value {
+            FilterType::Peaking => EqFilter::Peaking,
+            FilterType::LowShelf => EqFilter::LowShelf,
+            FilterType::HighShelf => EqFilter::HighShelf,
+            FilterType::LowPass => EqFilter::LowPass,
+            FilterType::HighPass => EqFilter::HighPass,
+        }
+    }
+}
+
+impl From<EqFilter> for i32 {
+    fn from(value: EqFilter) -> Self {
+        match value {
+            EqFilter::Peaking => 0,
+            EqFilter::LowShelf => 1,
+            EqFilter::HighShelf => 2,
+            EqFilter::LowPass => 3,
+            EqFilter::HighPass => 4,
+        }
+    }
+}
+
+impl TryFrom<i32> for EqFilter {
+    type Error = String;
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        if value == 0 {
+            Ok(Self::Peaking)
+        } else if value == 1 {
+            Ok(Self::LowShelf)
+        } else if value == 2 {
+            Ok(Self::HighShelf)
+        } else if value == 3 {
+            Ok(Self::LowPass)
+        } else if value == 4 {
+            Ok(Self::HighPass)
+        } else {
+            Err(format!("{value} is not a valid equalizer filter"))
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Decode, Encode)]
+pub struct EqBand {
+    pub filter: EqFilter,
+    pub frequency: f32,
+    pub gain: f32,
+    pub q: f32,
+}
+
+impl Default for EqBand {
+    fn default() -> Self {
+        Band::default().into()
+    }
+}
+
+impl From<EqBand> for Band {
+    fn from(value: EqBand) -> Self {
+        Self {
+            filter: value.filter.into(),
+            frequency: value.frequency,
+            gain: value.gain,
+            q: value.q,
+        }
+    }
+}
+
+impl From<Band> for EqBand {
+    fn from(value: Band) -> Self {
+        Self {
+            filter: value.filter.into(),
+            frequency: value.frequency,
+            gain: value.gain,
+            q: value.q,
+        }
+    }
+}
+
+/// The settings of the equalizer saved under a name
+#[derive(Clone, Debug, Default, Decode, Encode)]
+pub struct EqPreset {
+    pub name: String,
+    pub preamp: f32,
+    pub graphic: [f32; 10],
+    pub parametric: Vec<EqBand>,
+}
+
+impl EqPreset {
+    pub fn new(name: String, settings: EqSettings) -> Self {
+        Self {
+            name,
+            preamp: settings.preamp,
+            graphic: settings.graphic,
+            parametric: settings.parametric.into_iter().map(EqBand::from).collect(),
+        }
+    }
+}
+
+impl From<EqBand> for EqBandData {
+    fn from(value: EqBand) -> Self {
+        Self {
+            filter: value.filter.into(),
+            frequency: value.frequency,
+            gain: value.gain,
+            q: value.q,
+        }
+    }
+}
+
+impl From<&EqPreset> for EqSettings {
+    fn from(value: &EqPreset) -> Self {
+        Self {
+            preamp: value.preamp,
+            graphic: value.graphic,
+            parametric: value.parametric.iter().copied().map(Band::from).collect(),
+        }
+    }
+}
+
 #[derive(Clone, Debug, Decode, Encode)]
 pub struct FileTrack {
     pub path: String,
     pub title: String,
     pub artist: String,
-    pub length: f64,
+    pub length: Option<f64>,
+    /// The length was estimated by reading the file, it's replaced once the track is played to its end
+    pub length_estimated: bool,
     pub image: Vec<u8>,
 }
 
@@ -326,11 +643,11 @@ impl From<FileTrack> for TrackData {
                 Default::default()
             },
             index: 0,
-            time: format!(
-                "{:02}:{:02}",
-                (value.length / 60.0).floor() as u64,
-                value.length.floor() as u64 % 60
-            )
+            time: TrackTime {
+                position: 0.0,
+                length: value.length,
+            }
+            .format_len()
             .into(),
             title: value.title.into(),
             visible: true,
@@ -40,6 +40,50 @@ pub struct Locale {
     check_update: Option<String>,
     update: Option<String>,
     rescan: Option<String>,
+    scan_loudness: Option<String>,
+    crossfade: Option<String>,
+    crossfade_linear: Option<String>,
+    crossfade_equal_power: Option<String>,
+    replay_gain: Option<String>,
+    replay_gain_off: Option<String>,
+    replay_gain_track: Option<String>,
+    replay_gain_album: Option<String>,
+    replay_gain_auto: Option<String>,
+    output_device: Option<String>,
+    default_device: Option<String>,
+    resampler_quality: Option<String>,
+    resampler_linear: Option<String>,
+    resampler_medium: Option<String>,
+    resampler_high: Option<String>,
+    preserve_pitch: Option<String>,
+    open_stream: Option<String>,
+    stream_url: Option<String>,
+    equalizer: Option<String>,
+    eq_edit: Option<String>,
+    eq_enabled: Option<String>,
+    eq_preset: Option<String>,
+    eq_delete: Option<String>,
+    eq_save: Option<String>,
+    eq_preset_name: Option<String>,
+    eq_import: Option<String>,
+    eq_import_path: Option<String>,
+    eq_preamp: Option<String>,
+    eq_graphic: Option<String>,
+    eq_parametric: Option<String>,
+    eq_add_band: Option<String>,
+    eq_band: Option<String>,
+    eq_peaking: Option<String>,
+    eq_low_shelf: Option<String>,
+    eq_high_shelf: Option<String>,
+    eq_low_pass: Option<String>,
+    eq_high_pass: Option<String>,
+    eq_frequency: Option<String>,
+    eq_gain: Option<String>,
+    eq_q: Option<String>,
+    visualizer: Option<String>,
+    visualizer_off: Option<String>,
+    visualizer_spectrum: Option<String>,
+    visualizer_oscilloscope: Option<String>,
 }
 
 pub fn localize(denominator: Option<String>, localization: Localization) {
@@ -81,7 +125,51 @@ pub fn localize(denominator: Option<String>, localization: Localization) {
         update_text,
         check_update,
         update,
-        rescan
+        rescan,
+        scan_loudness,
+        crossfade,
+        crossfade_linear,
+        crossfade_equal_power,
+        replay_gain,
+        replay_gain_off,
+        replay_gain_track,
+        replay_gain_album,
+        replay_gain_auto,
+        output_device,
+        default_device,
+        resampler_quality,
+        resampler_linear,
+        resampler_medium,
+        resampler_high,
+        preserve_pitch,
+        open_stream,
+        stream_url,
+        equalizer,
+        eq_edit,
+        eq_enabled,
+        eq_preset,
+        eq_delete,
+        eq_save,
+        eq_preset_name,
+        eq_import,
+        eq_import_path,
+        eq_preamp,
+        eq_graphic,
+        eq_parametric,
+        eq_add_band,
+        eq_band,
+        eq_peaking,
+        eq_low_shelf,
+        eq_high_shelf,
+        eq_low_pass,
+        eq_high_pass,
+        eq_frequency,
+        eq_gain,
+        eq_q,
+        visualizer,
+        visualizer_off,
+        visualizer_spectrum,
+        visualizer_oscilloscope
     );
 }
 
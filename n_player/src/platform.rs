@@ -169,9 +169,9 @@ impl Platform for LinuxPlatform {
 
                         meta.set_title(metadata.title);
                         meta.set_artist(metadata.artists);
-                        meta.set_length(Some(mpris_server::Time::from_secs(
-                            metadata.length as i64,
-                        )));
+                        meta.set_length(metadata.length.map(|length| {
+                            mpris_server::Time::from_millis((length * 1000.0).floor() as i64)
+                        }));
                         meta.set_art_url(metadata.image_path);
                         meta.set_trackid(Some(
                             mpris_server::zbus::zvariant::ObjectPath::from_string_unchecked(
@@ -182,6 +182,8 @@ impl Platform for LinuxPlatform {
                         mpris_server::Property::Metadata(meta)
                     }
                     Property::Volume(volume) => mpris_server::Property::Volume(volume),
+                    Property::Rate(rate) => mpris_server::Property::Rate(rate),
+                    Property::CanSeek(can_seek) => mpris_server::Property::CanSeek(can_seek),
                     Property::LoopStatus(loop_status) => {
                         let loop_status = match loop_status {
                             n_audio::queue::LoopStatus::Playlist => {
@@ -357,7 +359,7 @@ impl Platform for AndroidPlatform {
                             (&title).into(),
                             (&artist).into(),
                             (&cover_path).into(),
-                            metadata.length.into(),
+                            metadata.length.unwrap_or_default().into(),
                         ],
                     )
                     .unwrap();
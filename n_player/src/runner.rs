@@ -1,17 +1,26 @@
 use flume::Receiver;
+use n_audio::equalizer::{EqSettings, Equalizer, EQUALIZER};
+use n_audio::event::PlayerEvent;
 use n_audio::queue::{LoopStatus, QueuePlayer};
-use n_audio::TrackTime;
+use n_audio::replay_gain::ReplayGainMode;
+use n_audio::resampler::ResamplerQuality;
+use n_audio::time_stretch::TimeStretchMode;
+use n_audio::visualization::VisualizationTap;
+use n_audio::{Crossfade, TrackTime};
 use std::path::PathBuf;
 use std::sync::Arc;
-use std::time::Duration;
 use tokio::sync::RwLock;
 
 pub async fn run(runner: Arc<RwLock<Runner>>, rx: Receiver<RunnerMessage>) {
-    let mut interval = tokio::time::interval(Duration::from_millis(500));
+    let events = runner.read().await.subscribe();
     loop {
         tokio::select! {
-            _ = interval.tick() => {
-                runner.write().await.update().await;
+            event = events.recv_async() => {
+                if let Ok(event) = event {
+                    let mut runner = runner.write().await;
+                    runner.handle_event(event);
+                    runner.update().await;
+                }
             }
             message = rx.recv_async() => {
                 if let Ok(message) = message {
@@ -34,6 +43,18 @@ pub enum RunnerMessage {
     PlayTrack(usize),
     Seek(RunnerSeek),
     LoopStatus(LoopStatus),
+    SetCrossfade(Crossfade),
+    SetReplayGainMode(ReplayGainMode),
+    SetDevice(Option<String>),
+    SetResamplerQuality(ResamplerQuality),
+    SetPlaybackSpeed(f64),
+    SetTimeStretchMode(TimeStretchMode),
+    /// Replaces the settings of the equalizer, `None` disables it
+    SetEqualizer(Option<EqSettings>),
+    /// Changes a single parameter of the equalizer while it's playing, see `Equalizer`
+    SetEqParameter(String, f32),
+    /// Plays a file path, a `file://` URI or an HTTP(S) stream, adding it to the queue if needed
+    OpenUri(String),
 }
 
 #[derive(Debug)]
@@ -45,6 +66,9 @@ pub enum RunnerSeek {
 pub struct Runner {
     player: QueuePlayer,
     current_time: TrackTime,
+    stream_title: Option<String>,
+    /// Entry of the last track played to its end, along with its exact length
+    measured_length: Option<(Arc<str>, f64)>,
 }
 
 impl Runner {
@@ -52,6 +76,21 @@ impl Runner {
         Self {
             player,
             current_time: TrackTime::default(),
+            stream_title: None,
+            measured_length: None,
+        }
+    }
+
+    fn handle_event(&mut self, event: PlayerEvent) {
+        match event {
+            PlayerEvent::StreamTitle(title) => self.stream_title = Some(title),
+            PlayerEvent::TrackStarted => self.stream_title = None,
+            PlayerEvent::LengthMeasured(id, length) => {
+                if let Some(track) = self.player.track_by_id(id) {
+                    self.measured_length = Some((track, length));
+                }
+            }
+            _ => {}
         }
     }
 
@@ -60,11 +99,19 @@ impl Runner {
             self.current_time = time;
         }
 
-        if self.player.has_ended() {
+        if !self.player.check_advanced() && self.player.has_ended() {
             if let Err(err) = self.player.play_next(false).await {
                 eprintln!("error happened: {err}");
             }
         }
+
+        if let Err(err) = self.player.preload_next().await {
+            eprintln!("error happened while preloading: {err}");
+        }
+
+        for err in self.player.get_errors() {
+            eprintln!("error happened during playback: {err}");
+        }
     }
 
     async fn parse_command(&mut self, message: RunnerMessage) {
@@ -118,6 +165,8 @@ impl Runner {
                     eprintln!("error happened: {err}");
                 }
             }
+            // Tracks of unknown length, like live streams, can't be seeked
+            RunnerMessage::Seek(_) if !self.current_time.is_seekable() => {}
             RunnerMessage::Seek(seek) => {
                 let seek = match seek {
                     RunnerSeek::Absolute(value) => value,
@@ -128,11 +177,75 @@ impl Runner {
                 }
             }
             RunnerMessage::LoopStatus(loop_status) => {
-                self.player.set_loop_status(loop_status);
+                self.player.set_loop_status(loop_status).await;
+            }
+            RunnerMessage::SetCrossfade(crossfade) => {
+                self.player.set_crossfade(crossfade).await.unwrap();
+            }
+            RunnerMessage::SetReplayGainMode(replay_gain_mode) => {
+                self.player
+                    .set_replay_gain_mode(replay_gain_mode)
+                    .await
+                    .unwrap();
+            }
+            RunnerMessage::SetDevice(device) => {
+                self.player.set_device(device).await.unwrap();
+            }
+            RunnerMessage::SetResamplerQuality(quality) => {
+                self.player.set_resampler_quality(quality).await.unwrap();
+            }
+            RunnerMessage::SetPlaybackSpeed(speed) => {
+                self.player.set_playback_speed(speed as f32).await.unwrap();
+            }
+            RunnerMessage::SetTimeStretchMode(mode) => {
+                self.player.set_time_stretch_mode(mode).await.unwrap();
+            }
+            RunnerMessage::SetEqualizer(Some(settings)) => {
+                // It goes first, so that the processors added later get the equalized audio
+                self.player
+                    .insert_dsp(0, Box::new(Equalizer::new(settings)))
+                    .await
+                    .unwrap();
+            }
+            RunnerMessage::SetEqualizer(None) => {
+                self.player.remove_dsp(EQUALIZER).await.unwrap();
+            }
+            RunnerMessage::SetEqParameter(name, value) => {
+                self.player
+                    .set_dsp_parameter(EQUALIZER, &name, value)
+                    .await
+                    .unwrap();
+            }
+            RunnerMessage::OpenUri(uri) => {
+                let path = match uri.strip_prefix("file://") {
+                    Some(path) => percent_decode(path),
+                    None => uri,
+                };
+                let index = match self.player.queue().iter().position(|t| t.as_ref() == path) {
+                    Some(index) => index,
+                    None => {
+                        self.player.add(path).await;
+                        self.player.len() - 1
+                    }
+                };
+                self.player.end_current().await.unwrap();
+                if let Err(err) = self.player.play_index(index).await {
+                    eprintln!("error happened: {err}");
+                }
             }
         }
     }
 
+    /// Returns the tap of what is being heard, see `Player::visualization`
+    pub fn visualization(&self) -> VisualizationTap {
+        self.player.visualization()
+    }
+
+    /// Returns a new receiver of the events sent by the player
+    pub fn subscribe(&self) -> Receiver<PlayerEvent> {
+        self.player.subscribe()
+    }
+
     pub fn playback(&self) -> bool {
         !self.player.is_paused() && self.player.is_playing()
     }
@@ -141,10 +254,24 @@ impl Runner {
         self.player.get_volume() as f64
     }
 
+    pub fn playback_speed(&self) -> f64 {
+        self.player.get_playback_speed() as f64
+    }
+
     pub fn time(&self) -> TrackTime {
         self.current_time
     }
 
+    /// Returns the entry of the queue and the exact length of the last track played to its end
+    pub fn measured_length(&self) -> Option<(Arc<str>, f64)> {
+        self.measured_length.clone()
+    }
+
+    /// Returns the last title announced by the stream being played
+    pub fn stream_title(&self) -> Option<String> {
+        self.stream_title.clone()
+    }
+
     pub fn path(&self) -> String {
         self.player.path()
     }
@@ -193,7 +320,31 @@ impl Runner {
         self.player.shrink_to_fit()
     }
 
-    pub fn shuffle(&mut self) {
-        self.player.shuffle()
+    pub async fn shuffle(&mut self) {
+        self.player.shuffle().await
+    }
+}
+
+/// Decodes the `%XX` escapes of a `file://` URI
+fn percent_decode(path: &str) -> String {
+    let mut bytes = vec![];
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = (byte == b'%')
+            .then(|| tail.get(..2))
+            .flatten()
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            None => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
     }
+    String::from_utf8_lossy(&bytes).into_owned()
 }
@@ -1,6 +1,11 @@
 use crate::platform::Platform;
-use crate::{FileTrack, Theme, WindowSize};
+use crate::{
+    CrossfadeCurve, EqPreset, FileTrack, ReplayGainMode, ResamplerQuality, Theme, Visualizer,
+    WindowSize,
+};
 use bitcode::{Decode, Encode};
+use n_audio::equalizer::EqSettings;
+use n_audio::time_stretch::TimeStretchMode;
 use std::fs::File;
 use std::hash::{DefaultHasher, Hash, Hasher};
 use std::io::{BufReader, BufWriter, Cursor};
@@ -16,6 +21,44 @@ pub struct Settings {
     pub save_window_size: bool,
     pub locale: Option<String>,
     pub timestamp: Option<u64>,
+    pub crossfade: f64,
+    pub crossfade_curve: CrossfadeCurve,
+    pub replay_gain: ReplayGainMode,
+    pub output_device: Option<String>,
+    pub resampler_quality: ResamplerQuality,
+    pub preserve_pitch: bool,
+    pub equalizer: bool,
+    /// What the equalizer is applying now, saved even if it's disabled
+    pub eq_preset: EqPreset,
+    pub eq_presets: Vec<EqPreset>,
+    pub visualizer: Visualizer,
+}
+
+/// Layout of `Settings` saved by the versions without the audio settings, read so that upgrading keeps them
+#[derive(Decode)]
+struct LegacySettings {
+    path: String,
+    volume: f64,
+    theme: Theme,
+    window_size: WindowSize,
+    save_window_size: bool,
+    locale: Option<String>,
+    timestamp: Option<u64>,
+}
+
+impl From<LegacySettings> for Settings {
+    fn from(legacy: LegacySettings) -> Self {
+        Self {
+            path: legacy.path,
+            volume: legacy.volume,
+            theme: legacy.theme,
+            window_size: legacy.window_size,
+            save_window_size: legacy.save_window_size,
+            locale: legacy.locale,
+            timestamp: legacy.timestamp,
+            ..Self::default()
+        }
+    }
 }
 
 impl Settings {
@@ -28,6 +71,8 @@ impl Settings {
             ) {
                 if let Ok(storage) = bitcode::decode(&data) {
                     storage
+                } else if let Ok(legacy) = bitcode::decode::<LegacySettings>(&data) {
+                    legacy.into()
                 } else {
                     eprintln!("not encoded");
                     Self::default()
@@ -70,6 +115,26 @@ impl Settings {
         PathBuf::new()
     }
 
+    pub fn time_stretch_mode(&self) -> TimeStretchMode {
+        if self.preserve_pitch {
+            TimeStretchMode::PreservePitch
+        } else {
+            TimeStretchMode::Tape
+        }
+    }
+
+    /// Returns what the equalizer should apply, `None` if it's disabled
+    pub fn equalizer(&self) -> Option<EqSettings> {
+        self.equalizer.then(|| EqSettings::from(&self.eq_preset))
+    }
+
+    pub fn crossfade(&self) -> n_audio::Crossfade {
+        n_audio::Crossfade {
+            duration: self.crossfade as f32,
+            curve: self.crossfade_curve.into(),
+        }
+    }
+
     pub async fn check_timestamp(&self) -> bool {
         if let Some(saved_timestamp) = &self.timestamp {
             if let Ok(timestamp) = self.timestamp().await {
@@ -149,6 +214,27 @@ impl Settings {
         .unwrap()
     }
 
+    /// Replaces the estimated length of the cached track at `path` with the one measured while playing it
+    ///
+    /// Returns the updated track, `None` if its length wasn't estimated
+    pub async fn update_track_length<P: Deref<Target = impl Platform>>(
+        &self,
+        platform: P,
+        path: String,
+        length: f64,
+    ) -> Option<FileTrack> {
+        let mut tracks = self.read_tracks(&*platform).await;
+        let track = tracks
+            .iter_mut()
+            .find(|track| track.path == path && track.length_estimated)?;
+        track.length = Some(length);
+        track.length_estimated = false;
+        let track = track.clone();
+
+        self.add_tracks(&*platform, tracks).await;
+        Some(track)
+    }
+
     pub async fn save<P: Deref<Target = impl Platform>>(&self, platform: P) {
         self.save_and_compress(platform.internal_dir().await).await
     }
@@ -182,6 +268,16 @@ impl Default for Settings {
             save_window_size: false,
             locale: None,
             timestamp: None,
+            crossfade: 0.0,
+            crossfade_curve: CrossfadeCurve::default(),
+            replay_gain: ReplayGainMode::default(),
+            output_device: None,
+            resampler_quality: ResamplerQuality::default(),
+            preserve_pitch: true,
+            equalizer: false,
+            eq_preset: EqPreset::default(),
+            eq_presets: vec![],
+            visualizer: Visualizer::default(),
         }
     }
 }